@@ -1,297 +1,1583 @@
 //! The Front-End
 
-use crate::rsa::{AlgoRSA, RSA, KeyType};
-use crate::simpledb::SimpleDB;
-use crate::primes::KeySize;
-use crate::err::ErrorKind;
+use rsa::{AlgoRSA, RSA, UserKeys, Trust, Message, Usage, KeyType, KeySize, KeyStore, FileStore, SimpleDB, Codec, Passphrase, ErrorKind, RSAError};
+use rsa::{HashAlgorithm, SignatureScheme, UserInfo, E};
+use rsa::{jwt, cms, der};
+use rsa::sshsig::{self, AllowedSigners, PublicKey, Signature};
 use num_bigint::BigUint;
-use std::path::PathBuf;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Read, Write};
 use structopt::StructOpt;
 use structopt::clap::AppSettings;
 use regex::Regex;
-use failure::{ResultExt, Error};
+use failure::{Fail, ResultExt};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+// `println!` for status lines, which shrugs off a stdout that was closed, as by `rsa rename | head -0`
+#[cfg(not(test))]
+macro_rules! say {
+    ($($arg:tt)*) => {{
+        let _ = writeln!(std::io::stdout(), $($arg)*);
+    }};
+}
+
+// the test harness captures `println!`, but not what is written to `stdout()` directly
+#[cfg(test)]
+macro_rules! say {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+    }};
+}
 
 // TODO: Make strings that are supposed to be files, files
 
 #[derive(Debug, StructOpt)]
 #[structopt(raw(global_setting = "AppSettings::AllowLeadingHyphen"))]
 pub struct CLI {
-    #[structopt(long = "db")]
+    #[structopt(long = "db", env = "RSA_DB")]
     /// Specify the database that the private/public keys will be stored. Required
     database: String,
 
-    #[structopt(long = "user", short = "u")]
-    /// Specify the user for user-specific actions like encrypting, decrypting, and exporting
+    #[structopt(long = "passphrase-fd")]
+    /// Read the passphrase of an encrypted database from this file descriptor instead of `RSA_PASSPHRASE` or a prompt
+    passphrase_fd: Option<i32>,
+
+    #[structopt(long = "db-format", raw(possible_values = r#"&["json", "bincode", "msgpack"]"#))]
+    /// Convert the database to json, bincode or msgpack. New databases are msgpack
+    db_format: Option<Codec>,
+
+    #[structopt(long = "migrate")]
+    /// Rewrite a database from an older version of this program in the current layout
+    migrate: bool,
+
+    #[structopt(long = "user", short = "u", raw(global = "true"))]
+    /// Specify the user for user-specific actions like encrypting, decrypting, and exporting. A key id or
+    /// fingerprint, or a prefix of at least 8 hex digits of one, selects the user with that key
     user: Option<String>,
 
-    #[structopt(long = "file", short = "f")]
-    /// Specify output file. Output file must not exist
+    #[structopt(long = "file", short = "f", raw(global = "true"))]
+    /// Specify output file, or `-` for stdout. An existing file is only replaced with `--force`
     output_file: Option<String>,
 
-    #[structopt(long = "encrypt", short = "e")]
-    /// Encrypt. Requires a String argument that is the data to encrypt
+    #[structopt(long = "force", raw(global = "true"))]
+    /// Replace the output file if it exists
+    force: bool,
+
+    #[structopt(long = "output", default_value = "text", raw(possible_values = r#"&["text", "json"]"#, global = "true"))]
+    /// Print the results of list, inspect, keygen, sign and verify, and errors, as text or as JSON objects
+    output: OutputFormat,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    // The flags below came before the subcommands. They still work, with a warning, but only one at a time
+
+    #[structopt(long = "encrypt", short = "e", raw(hidden = "true"))]
+    /// Deprecated: `encrypt --message`
     encrypt: Option<String>,
 
-    #[structopt(long = "encrypt-file")]
-    // Specify a file to encrypt. File may include anything
+    #[structopt(long = "encrypt-file", raw(hidden = "true"))]
+    /// Deprecated: `encrypt <file>`
     encrypt_file: Option<String>,
 
-    #[structopt(long = "decrypt", short = "d")]
-    /// Decrypt data
+    #[structopt(long = "decrypt", short = "d", raw(hidden = "true"))]
+    /// Deprecated: `decrypt --message`
     decrypt: Option<String>,
 
-    #[structopt(long = "decrypt-file")]
-    /// Decrypt file
+    #[structopt(long = "decrypt-file", raw(hidden = "true"))]
+    /// Deprecated: `decrypt <file>`
     decrypt_file: Option<String>,
 
-    #[structopt(long = "generate", short = "g")]
-    /// Generate a new key
+    #[structopt(long = "generate", short = "g", raw(hidden = "true"))]
+    /// Deprecated: `keygen`
     generate: bool,
 
-    #[structopt(long = "import", short = "i")]
-    /// Import a key
-    import: Option<String>, // file
+    #[structopt(long = "import", short = "i", raw(hidden = "true"))]
+    /// Deprecated: `import <file>`
+    import: Option<String>,
 
-    #[structopt(long = "export-public")]
-    /// Export a public key
-    export_public: bool, // user
+    #[structopt(long = "export-public", raw(hidden = "true"))]
+    /// Deprecated: `export`
+    export_public: bool,
 
-    #[structopt(long = "export-private")]
-    /// Export a private key
-    export_private: bool, // user
+    #[structopt(long = "export-private", raw(hidden = "true"))]
+    /// Deprecated: `export --private`
+    export_private: bool,
 
-    #[structopt(long = "import-public")]
-    /// import a public key
-    import_public: Option<String>, // data
+    #[structopt(long = "import-public", raw(hidden = "true"))]
+    /// Deprecated: `import --text`
+    import_public: Option<String>,
 
-    #[structopt(long = "import-private")]
-    /// import a private key (must have imported a public key first)
+    #[structopt(long = "import-private", raw(hidden = "true"))]
+    /// Deprecated: `import --private --text`
     import_private: Option<String>,
 
-    #[structopt(long = "list-all", short = "l")]
-    /// List all key-pairs present in the database
-    list: bool,
+    #[structopt(long = "set-primary", raw(hidden = "true"))]
+    /// Deprecated: `edit --primary`
+    set_primary: Option<String>,
+
+    #[structopt(long = "set-expiry", raw(hidden = "true"))]
+    /// Deprecated: `edit --expiry`
+    set_expiry: Option<String>,
+
+    #[structopt(long = "set-comment", raw(hidden = "true"))]
+    /// Deprecated: `edit --comment`
+    set_comment: Option<String>,
+
+    #[structopt(long = "set-usage", raw(hidden = "true"))]
+    /// Deprecated: `edit --usage`
+    set_usage: Option<String>,
+
+    #[structopt(long = "set-trust", raw(hidden = "true"))]
+    /// Deprecated: `edit --trust`
+    set_trust: Option<String>,
+
+    #[structopt(long = "revoke", raw(hidden = "true"))]
+    /// Deprecated: `revoke`
+    revoke: Option<String>,
+
+    #[structopt(long = "key-id", raw(hidden = "true"))]
+    /// Deprecated: `revoke --key-id` and `export --revocation --key-id`
+    key_id: Option<String>,
+
+    #[structopt(long = "export-revocation", raw(hidden = "true"))]
+    /// Deprecated: `export --revocation`
+    export_revocation: bool,
+
+    #[structopt(long = "import-revocation", raw(hidden = "true"))]
+    /// Deprecated: `import --revocation --text`
+    import_revocation: Option<String>,
+
+    #[structopt(long = "list-all", short = "l", raw(hidden = "true"))]
+    /// Deprecated: `list`
+    list_all: bool,
+}
+
+impl CLI {
+    /// The command asked for by the deprecated flags, if any. Each of them prints a warning
+    fn legacy_command(&mut self) -> Result<Option<Command>, RSAError> {
+        let mut commands = Vec::new();
+        let mut deprecated = |flag: &str, instead: &str, command: Command| {
+            eprintln!("Warning: `{}` is deprecated, use `rsa {}` instead", flag, instead);
+            commands.push(command);
+        };
+        if self.generate {
+            deprecated("--generate", "keygen", Command::Keygen { bits: None, exponent: E, comment: None });
+        }
+        if let Some(message) = self.encrypt.take() {
+            deprecated("--encrypt", "encrypt --message", Command::Encrypt { recipient: None, message: Some(message), file: None });
+        }
+        if let Some(file) = self.encrypt_file.take() {
+            deprecated("--encrypt-file", "encrypt <file>", Command::Encrypt { recipient: None, message: None, file: Some(file) });
+        }
+        if let Some(message) = self.decrypt.take() {
+            deprecated("--decrypt", "decrypt --message", Command::Decrypt { message: Some(message), file: None });
+        }
+        if let Some(file) = self.decrypt_file.take() {
+            deprecated("--decrypt-file", "decrypt <file>", Command::Decrypt { message: None, file: Some(file) });
+        }
+        if self.export_public {
+            deprecated("--export-public", "export", Command::Export { private: false, revocation: false, key_id: None });
+        }
+        if self.export_private {
+            deprecated("--export-private", "export --private", Command::Export { private: true, revocation: false, key_id: None });
+        }
+        if let Some(file) = self.import.take() {
+            deprecated("--import", "import <file>", Command::Import { text: None, file: Some(file), private: false, revocation: false });
+        }
+        if let Some(text) = self.import_public.take() {
+            deprecated("--import-public", "import --text", Command::Import { text: Some(text), file: None, private: false, revocation: false });
+        }
+        if let Some(text) = self.import_private.take() {
+            deprecated("--import-private", "import --private --text", Command::Import { text: Some(text), file: None, private: true, revocation: false });
+        }
+        let edited = [("--set-primary", &self.set_primary), ("--set-expiry", &self.set_expiry), ("--set-comment", &self.set_comment),
+                      ("--set-usage", &self.set_usage), ("--set-trust", &self.set_trust)]
+            .iter().find(|(_, value)| value.is_some()).map(|(flag, _)| *flag);
+        if let Some(flag) = edited {
+            deprecated(flag, "edit", Command::Edit {
+                primary: self.set_primary.take(),
+                expiry: self.set_expiry.take(),
+                comment: self.set_comment.take(),
+                usage: self.set_usage.take(),
+                trust: self.set_trust.take(),
+            });
+        }
+        if let Some(reason) = self.revoke.take() {
+            deprecated("--revoke", "revoke", Command::Revoke { reason, key_id: self.key_id.clone() });
+        }
+        if self.export_revocation {
+            deprecated("--export-revocation", "export --revocation", Command::Export { private: false, revocation: true, key_id: self.key_id.clone() });
+        }
+        if let Some(text) = self.import_revocation.take() {
+            deprecated("--import-revocation", "import --revocation --text", Command::Import { text: Some(text), file: None, private: false, revocation: true });
+        }
+        if self.list_all {
+            deprecated("--list-all", "list", Command::List);
+        }
+        match commands.len() {
+            0 => Ok(None),
+            1 if self.command.is_none() => Ok(commands.pop()),
+            _ => Err(ErrorKind::ConflictingCommands)?,
+        }
+    }
+}
+
+/// How results and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = RSAError;
+
+    fn from_str(s: &str) -> Result<Self, RSAError> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(ErrorKind::Encoding)?,
+        }
+    }
+}
+
+/// An error as a JSON object, for `--output json`
+pub fn error_json(error: &RSAError) -> String {
+    let fail: &dyn Fail = error;
+    let causes: Vec<String> = fail.iter_causes().map(|cause| cause.to_string()).collect();
+    serde_json::json!({
+        "error": {
+            "kind": format!("{:?}", error.kind()),
+            "message": error.to_string(),
+            "causes": causes,
+            "exit_code": error.kind().exit_code(),
+        }
+    }).to_string()
+}
+
+/// Which signature scheme `sign` and `verify` use
+#[derive(Debug, StructOpt)]
+struct SchemeArgs {
+    #[structopt(long = "scheme", default_value = "pss", raw(possible_values = r#"&["pss", "pkcs1"]"#))]
+    /// RSASSA-PSS, or RSASSA-PKCS1-v1_5
+    scheme: String,
+
+    #[structopt(long = "hash", default_value = "sha256", raw(possible_values = r#"&["sha256", "sha384", "sha512"]"#))]
+    /// The hash function to sign with
+    hash: String,
+}
+
+impl SchemeArgs {
+    fn scheme(&self) -> SignatureScheme {
+        let hash = match self.hash.as_str() {
+            "sha384" => HashAlgorithm::Sha384,
+            "sha512" => HashAlgorithm::Sha512,
+            _ => HashAlgorithm::Sha256,
+        };
+        match self.scheme.as_str() {
+            "pkcs1" => SignatureScheme::Pkcs1v15(hash),
+            _ => SignatureScheme::Pss(hash),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(name = "keygen")]
+    /// Generate a new key pair for `--user`. A user who has keys gets a new primary key. Missing values are asked
+    /// for when stdin is a terminal
+    Keygen {
+        #[structopt(long = "bits", short = "b")]
+        /// Key size: 512, 1024, 2048, 4096 or 8192
+        bits: Option<usize>,
+
+        #[structopt(long = "exponent", default_value = "65537")]
+        /// Public exponent. Only 65537 is supported
+        exponent: usize,
+
+        #[structopt(long = "comment")]
+        /// Attach a comment to the new key
+        comment: Option<String>,
+    },
+
+    #[structopt(name = "encrypt")]
+    /// Encrypt a file or a message to `--recipient`
+    Encrypt {
+        #[structopt(long = "recipient", short = "r")]
+        /// The user to encrypt to, by name or key id. Defaults to `--user`
+        recipient: Option<String>,
+
+        #[structopt(long = "message", short = "m", raw(conflicts_with = r#""file""#))]
+        /// Encrypt this text instead of a file
+        message: Option<String>,
+
+        /// The file to encrypt. It may hold anything. Reads stdin if it is `-` or left out
+        file: Option<String>,
+    },
+
+    #[structopt(name = "decrypt")]
+    /// Decrypt a `BEGIN RSA MESSAGE` file or message with the keys of `--user`
+    Decrypt {
+        #[structopt(long = "message", short = "m", raw(conflicts_with = r#""file""#))]
+        /// Decrypt this message instead of a file
+        message: Option<String>,
+
+        /// The file to decrypt. Reads stdin if it is `-` or left out
+        file: Option<String>,
+    },
+
+    #[structopt(name = "sign")]
+    /// Sign a file with the primary key of `--user`, printing a `BEGIN RSA SIGNATURE` block
+    Sign {
+        /// The file to sign. Reads stdin if it is `-` or left out
+        file: Option<String>,
+
+        #[structopt(flatten)]
+        scheme: SchemeArgs,
+    },
+
+    #[structopt(name = "verify")]
+    /// Verify the signature of a file, made by `--user` or, without it, by anyone in the database
+    Verify {
+        /// The signed file, or `-` for stdin
+        file: String,
+
+        #[structopt(long = "signature", short = "s")]
+        /// The file holding the signature, as printed by `sign`, or `-` for stdin
+        signature: String,
+
+        #[structopt(flatten)]
+        scheme: SchemeArgs,
+    },
+
+    #[structopt(name = "export")]
+    /// Print the public key of `--user`, with its certifications
+    Export {
+        #[structopt(long = "private", raw(conflicts_with = r#""revocation""#))]
+        /// Print the private key instead
+        private: bool,
+
+        #[structopt(long = "revocation")]
+        /// Print the revocation certificate of a revoked key instead
+        revocation: bool,
+
+        #[structopt(long = "key-id", raw(requires = r#""revocation""#))]
+        /// The revoked key, instead of the primary key
+        key_id: Option<String>,
+    },
+
+    #[structopt(name = "import")]
+    /// Import a public key for `--user`, as printed by `export`, with the certifications that follow it
+    Import {
+        #[structopt(long = "text", raw(conflicts_with = r#""file""#))]
+        /// The exported text itself, instead of a file
+        text: Option<String>,
+
+        /// The file to import. Reads stdin if it is `-` or left out
+        file: Option<String>,
+
+        #[structopt(long = "private", raw(conflicts_with = r#""revocation""#))]
+        /// Import the private key of a public key imported before
+        private: bool,
+
+        #[structopt(long = "revocation")]
+        /// Import a revocation certificate, checking that the revoked key signed it. `--user` is not needed
+        revocation: bool,
+    },
+
+    #[structopt(name = "list")]
+    /// List all users and their keys
+    List,
+
+    #[structopt(name = "inspect")]
+    /// Show the keys of `--user` in detail, and how far they are trusted
+    Inspect,
+
+    #[structopt(name = "edit")]
+    /// Change the primary key of `--user`, its metadata, or how far the user is trusted
+    Edit {
+        #[structopt(long = "primary", raw(required_unless_one = r#"&["expiry", "comment", "usage", "trust"]"#))]
+        /// Encrypt and sign with the key that has this id, as shown by `list`
+        primary: Option<String>,
+
+        #[structopt(long = "expiry")]
+        /// Stop the primary key from encrypting and signing after this date (YYYY-MM-DD), or `never`
+        expiry: Option<String>,
+
+        #[structopt(long = "comment")]
+        /// Attach a comment to the primary key. An empty comment removes it
+        comment: Option<String>,
 
-    // #[structopt(flatten)]
-    //verbosity: Verbosity,
+        #[structopt(long = "usage")]
+        /// Restrict the primary key to a comma-separated list of encrypt, sign and certify
+        usage: Option<String>,
+
+        #[structopt(long = "trust")]
+        /// How far the contact is trusted to certify other keys: unknown, marginal or full
+        trust: Option<String>,
+    },
+
+    #[structopt(name = "revoke")]
+    /// Revoke a key of `--user` and print the revocation certificate
+    Revoke {
+        /// Why: compromised, superseded, retired or unspecified
+        reason: String,
+
+        #[structopt(long = "key-id")]
+        /// The key to revoke, instead of the primary key
+        key_id: Option<String>,
+    },
+
+    #[structopt(name = "jwt")]
+    /// Create and verify JSON Web Tokens signed with keys from the database
+    Jwt(JwtCommand),
+
+    #[structopt(name = "cms")]
+    /// Create and read CMS/PKCS#7 (S/MIME) messages, compatible with `openssl cms`
+    Cms(CmsCommand),
+
+    #[structopt(name = "ssh")]
+    /// SSH keys and signatures. For git, set `gpg.ssh.program` to this binary and `RSA_DB` to the database
+    Ssh(SshCommand),
+
+    #[structopt(name = "rotate")]
+    /// Give `--user` a new primary key of the same size and revoke the old one as superseded
+    Rotate {
+        #[structopt(long = "archive")]
+        /// Re-encrypt every `BEGIN RSA MESSAGE` file below this directory from the old keys of `--user` to the new one.
+        /// The original of each file is kept next to it as `.orig`
+        archive: Option<String>,
+
+        #[structopt(long = "include-legacy", raw(requires = r#""archive""#))]
+        /// Also re-encrypt messages that do not record their key. They are assumed to be encrypted to the oldest key
+        /// of `--user`; without this flag they are skipped, as they may belong to someone else
+        include_legacy: bool,
+    },
+
+    #[structopt(name = "delete")]
+    /// Remove `--user` and all of their keys. Asks first if private keys would be lost
+    Delete {
+        #[structopt(long = "yes", short = "y")]
+        /// Do not ask for confirmation
+        yes: bool,
+
+        #[structopt(long = "erase")]
+        /// Also overwrite the backup of the previous database version, which still holds the deleted keys
+        erase: bool,
+    },
+
+    #[structopt(name = "rename")]
+    /// Move all keys of `--user` to a new name
+    Rename {
+        to: String,
+    },
+
+    #[structopt(name = "copy")]
+    /// Add the public keys of `--user` under another name, as an alias
+    Copy {
+        to: String,
+    },
+
+    #[structopt(name = "verify-contact")]
+    /// Record that the fingerprint of the primary key of `--user` was checked with them, in person or by phone
+    VerifyContact {
+        #[structopt(long = "fingerprint")]
+        /// The fingerprint they gave, in hex or as printed by `list`. Without it, asks for confirmation
+        fingerprint: Option<String>,
+    },
+
+    #[structopt(name = "certify")]
+    /// Certify that the primary key of `--user` belongs to them, signing it with a key of your own
+    Certify {
+        #[structopt(long = "by")]
+        /// The user whose primary key signs the certification
+        by: String,
+    },
+
+    #[structopt(name = "trust-path")]
+    /// Show through whose certifications the key of `--user` is valid
+    TrustPath,
+
+    #[structopt(name = "passphrase")]
+    /// Encrypt the database with a new passphrase, read from `RSA_NEW_PASSPHRASE` or prompted for
+    Passphrase {
+        #[structopt(long = "remove")]
+        /// Store the database unencrypted instead
+        remove: bool,
+    },
+}
+
+impl Command {
+    // whether `--user` names someone already in the database, rather than a user to create or to import keys as
+    fn looks_up_user(&self) -> bool {
+        match self {
+            Command::Keygen { .. } | Command::Cms(CmsCommand::ImportCertificate { .. }) => false,
+            Command::Import { private, .. } => *private,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum SshCommand {
+    #[structopt(name = "public-key")]
+    /// Print the public key of `--user` in OpenSSH format, for `user.signingkey` and allowed_signers files
+    PublicKey,
+}
+
+/// The subset of `ssh-keygen -Y` that git uses for `gpg.format = ssh`
+#[derive(Debug, StructOpt)]
+#[structopt(name = "rsa -Y")]
+struct SshKeygen {
+    #[structopt(short = "Y")]
+    /// One of sign, verify, find-principals, check-novalidate
+    operation: String,
+
+    #[structopt(long = "db", env = "RSA_DB")]
+    /// The database holding the signing key. Only needed to sign
+    database: Option<String>,
+
+    #[structopt(long = "passphrase-fd")]
+    /// Read the passphrase of an encrypted database from this file descriptor
+    passphrase_fd: Option<i32>,
+
+    #[structopt(short = "n")]
+    /// Signature namespace, such as `git` or `file`
+    namespace: Option<String>,
+
+    #[structopt(short = "f")]
+    /// The public key to sign with, or the allowed_signers file to verify against
+    file: Option<String>,
+
+    #[structopt(short = "I")]
+    /// The principal that must have made the signature
+    principal: Option<String>,
+
+    #[structopt(short = "s")]
+    /// The signature file to verify
+    signature: Option<String>,
+
+    #[structopt(short = "U")]
+    /// Accepted for compatibility; keys always come from the database
+    _agent: bool,
+
+    #[structopt(short = "O", raw(number_of_values = "1"))]
+    /// Options. Only `verify-time=YYYYMMDD[HHMM[SS]]` is understood
+    options: Vec<String>,
+
+    /// Files to sign. `-` signs stdin
+    files: Vec<String>,
+}
+
+impl SshKeygen {
+    fn run() -> Result<(), RSAError> {
+        let args = SshKeygen::from_args();
+        let namespace = || args.namespace.as_ref().ok_or(ErrorKind::MalformedSignature);
+        match args.operation.as_str() {
+            "sign" => {
+                let database = args.database.as_ref().ok_or(ErrorKind::Database)?;
+                let rsa = AlgoRSA::new(open_keyring(database, args.passphrase_fd)?);
+                let key = PublicKey::parse(&String::from_utf8(Opts::read_file(args.file.as_ref().ok_or(ErrorKind::NoUserSpecified)?)?)?)?;
+                let user = rsa.find_public(&key.n)?;
+                // the signature is made with, and names, the primary key of the user
+                if rsa.public_key(&user)? != key.n {
+                    Err(ErrorKind::NotPrimaryKey)?
+                }
+                for file in &args.files {
+                    if file == "-" {
+                        write_stdout(sshsig::sign(&rsa, &user, namespace()?, &Self::read_stdin()?)?.as_bytes())?;
+                    } else {
+                        let signature = sshsig::sign(&rsa, &user, namespace()?, &Opts::read_file(file)?)?;
+                        write_atomically(Path::new(&format!("{}.sig", file)), signature.as_bytes())?;
+                    }
+                }
+            },
+            "find-principals" => {
+                let signature = Signature::parse(&String::from_utf8(Opts::read_file(args.signature.as_ref().ok_or(ErrorKind::MalformedSignature)?)?)?)?;
+                let principals = self::allowed_signers(&args)?.find_principals(&signature, &signature.namespace, &args.verify_time()?);
+                if principals.is_empty() {
+                    Err(ErrorKind::PrincipalNotAllowed)?
+                }
+                write_stdout(principals.iter().map(|p| format!("{}\n", p)).collect::<String>().as_bytes())?;
+            },
+            "verify" | "check-novalidate" => {
+                let signature = Signature::parse(&String::from_utf8(Opts::read_file(args.signature.as_ref().ok_or(ErrorKind::MalformedSignature)?)?)?)?;
+                signature.verify(namespace()?, &Self::read_stdin()?)?;
+                if args.operation == "verify" {
+                    let principal = args.principal.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
+                    if !self::allowed_signers(&args)?.is_allowed(principal, &signature, namespace()?, &args.verify_time()?) {
+                        Err(ErrorKind::PrincipalNotAllowed)?
+                    }
+                    say!("Good \"{}\" signature for {} with RSA key {}", namespace()?, principal, signature.public_key.fingerprint());
+                } else {
+                    say!("Good \"{}\" signature with RSA key {}", namespace()?, signature.public_key.fingerprint());
+                }
+            },
+            _ => Err(ErrorKind::UnsupportedAlgorithm)?
+        }
+        Ok(())
+    }
+
+    fn verify_time(&self) -> Result<chrono::DateTime<chrono::Utc>, RSAError> {
+        match self.options.iter().find_map(|o| o.strip_prefix("verify-time=")) {
+            Some(time) => sshsig::parse_time(time),
+            None => Ok(chrono::Utc::now()),
+        }
+    }
+
+    fn read_stdin() -> Result<Vec<u8>, RSAError> {
+        let mut buffer = Vec::new();
+        stdin()?.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
 }
 
+fn allowed_signers(args: &SshKeygen) -> Result<AllowedSigners, RSAError> {
+    let file = args.file.as_ref().ok_or(ErrorKind::KeyNotFound)?;
+    AllowedSigners::parse(&String::from_utf8(Opts::read_file(file)?)?)
+}
+
+#[derive(Debug, StructOpt)]
+enum CmsCommand {
+    #[structopt(name = "certificate")]
+    /// Print the certificate of `--user`, creating a self-signed one if the key has none
+    Certificate,
+
+    #[structopt(name = "import-certificate")]
+    /// Import a PEM or DER certificate for `--user`. Adds the public key if the user does not exist
+    ImportCertificate {
+        /// The certificate, or `-` for stdin
+        file: String,
+    },
+
+    #[structopt(name = "encrypt")]
+    /// Encrypt a file to one or more recipients as EnvelopedData
+    Encrypt {
+        /// The file to encrypt, or `-` for stdin
+        file: String,
+
+        #[structopt(long = "to", short = "r", required = true)]
+        /// Recipients, by name or key id. Each must have a certificate in the database
+        recipients: Vec<String>,
+
+        #[structopt(long = "der")]
+        /// Output DER instead of PEM
+        der: bool,
+    },
+
+    #[structopt(name = "decrypt")]
+    /// Decrypt EnvelopedData addressed to any key in the database
+    Decrypt {
+        /// The EnvelopedData, or `-` for stdin
+        file: String,
+    },
+
+    #[structopt(name = "sign")]
+    /// Sign a file as `--user`, producing SignedData
+    Sign {
+        /// The file to sign, or `-` for stdin
+        file: String,
+
+        #[structopt(long = "detached")]
+        /// Do not include the signed file within the signature. The file is signed as-is, so verify with `openssl cms -verify -binary`
+        detached: bool,
+
+        #[structopt(long = "der")]
+        /// Output DER instead of PEM
+        der: bool,
+    },
+
+    #[structopt(name = "verify")]
+    /// Verify SignedData against the keys in the database, printing the content if it is attached
+    Verify {
+        /// The SignedData, or `-` for stdin
+        file: String,
+
+        #[structopt(long = "content")]
+        /// The signed file, for detached signatures only, or `-` for stdin
+        content: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum JwtCommand {
+    #[structopt(name = "sign")]
+    /// Sign a set of claims with the key of `--user`
+    Sign {
+        #[structopt(long = "alg", default_value = "RS256")]
+        /// One of RS256, RS384, RS512, PS256
+        alg: String,
+
+        #[structopt(long = "claims", default_value = "{}")]
+        /// Claims to sign, as a JSON object, or `-` to read them from stdin
+        claims: String,
+
+        #[structopt(long = "expires-in")]
+        /// Seconds from now until the token expires. Sets the `exp` claim
+        expires_in: Option<u64>,
+    },
 
-fn prompt_number() -> Result<usize, Error> {
+    #[structopt(name = "verify")]
+    /// Verify a token against the keyring, printing its claims. Uses `--user` if given, otherwise the `kid` header
+    Verify {
+        /// The compact token to verify, or `-` to read it from stdin
+        token: String,
+
+        #[structopt(long = "leeway", default_value = "0")]
+        /// Seconds of clock skew to allow when checking `exp` and `nbf`
+        leeway: u64,
+    },
+}
+
+fn prompt_number() -> Result<usize, RSAError> {
     let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    stdin()?.read_line(&mut input)?;
     Ok(input.trim().parse::<usize>().context(ErrorKind::WrongNumber)?)
 }
 
-fn prompt_string() -> Result<String, Error> {
+fn prompt_string() -> Result<String, RSAError> {
     let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    stdin()?.read_line(&mut input)?;
     Ok(input.trim().into())
 }
 
+/// Open the database, asking for its passphrase once if it is encrypted
+fn open_keyring(database: &str, passphrase_fd: Option<i32>) -> Result<FileStore, RSAError> {
+    let db = SimpleDB::new(PathBuf::from(database))?;
+    if db.is_encrypted()? {
+        db.unlock(read_passphrase(passphrase_fd)?);
+    }
+    FileStore::new(db)
+}
+
+// from the file descriptor if given, then `RSA_PASSPHRASE`, then the terminal
+fn read_passphrase(fd: Option<i32>) -> Result<Passphrase, RSAError> {
+    if let Some(fd) = fd {
+        return read_passphrase_fd(fd);
+    }
+    if let Ok(passphrase) = std::env::var("RSA_PASSPHRASE") {
+        return Ok(Passphrase::new(passphrase));
+    }
+    let passphrase = rpassword::prompt_password("Passphrase for the key database: ").map_err(|_| ErrorKind::PassphraseRequired)?;
+    Ok(Passphrase::new(passphrase))
+}
+
+// set once the passphrase was read from stdin, which then cannot be an input as well
+static STDIN_PASSPHRASE: AtomicBool = AtomicBool::new(false);
+
+/// Stdin, unless the passphrase was read from it
+fn stdin() -> Result<std::io::Stdin, RSAError> {
+    if STDIN_PASSPHRASE.load(Ordering::SeqCst) {
+        Err(ErrorKind::StdinTwice)?
+    }
+    Ok(std::io::stdin())
+}
+
+#[cfg(unix)]
+#[allow(clippy::unbuffered_bytes)]
+fn read_passphrase_fd(fd: i32) -> Result<Passphrase, RSAError> {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
+    // std aborts the process when it closes a descriptor that is not open
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        Err(ErrorKind::BadFileDescriptor)?
+    }
+    if fd == 0 {
+        STDIN_PASSPHRASE.store(true, Ordering::SeqCst);
+    }
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    // a byte at a time, so that nothing after the passphrase is taken from the descriptor
+    let mut line = Vec::new();
+    for byte in (&*file).bytes() {
+        match byte? {
+            b'\n' => break,
+            byte => line.push(byte),
+        }
+    }
+    // the standard streams stay open; any other descriptor was handed to us for the passphrase alone
+    if fd > 2 {
+        unsafe { ManuallyDrop::drop(&mut file) };
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Passphrase::new(String::from_utf8(line)?))
+}
+
+#[cfg(not(unix))]
+fn read_passphrase_fd(_fd: i32) -> Result<Passphrase, RSAError> {
+    Err(ErrorKind::PassphraseRequired)?
+}
+
+fn new_passphrase() -> Result<Passphrase, RSAError> {
+    if let Ok(passphrase) = std::env::var("RSA_NEW_PASSPHRASE") {
+        return Ok(Passphrase::new(passphrase));
+    }
+    let passphrase = rpassword::prompt_password("New passphrase: ").map_err(|_| ErrorKind::PassphraseRequired)?;
+    if rpassword::prompt_password("Repeat the new passphrase: ").map_err(|_| ErrorKind::PassphraseRequired)? != passphrase {
+        Err(ErrorKind::PassphraseMismatch)?
+    }
+    Ok(Passphrase::new(passphrase))
+}
+
+// `never`, or the end of the given day in UTC
+fn parse_expiry(input: &str) -> Result<Option<u64>, RSAError> {
+    if input == "never" {
+        return Ok(None);
+    }
+    let date = chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d").context(ErrorKind::InvalidDate)?;
+    let end_of_day = date.and_hms_opt(23, 59, 59).ok_or(ErrorKind::InvalidDate)?.and_utc();
+    Ok(Some(end_of_day.timestamp().max(0) as u64))
+}
+
 pub struct Opts {
     args: CLI,
-    rsa: AlgoRSA
+    rsa: AlgoRSA,
+    /// Whether someone is at a terminal to ask for what the command line left out
+    interactive: bool,
+}
+
+/// Write `data` to stdout. A reader that stopped reading, like `head`, has all it wanted, so that is no error
+fn write_stdout(data: &[u8]) -> Result<(), RSAError> {
+    let out = std::io::stdout();
+    let mut handle = out.lock();
+    match handle.write_all(data).and_then(|_| handle.flush()) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+// The file format of an encrypted message: wrapped base64 between BEGIN and END lines
+fn armor_message(message: &Message) -> Result<Vec<u8>, RSAError> {
+    let mut encrypted = base64::encode(&message.to_bytes()?).into_bytes();
+    let length = encrypted.len();
+    let added_lines = encrypted.len() / 75;
+    encrypted.resize(encrypted.len() + added_lines, 0);
+    line_wrap::line_wrap(encrypted.as_mut_slice(), length, 75, &line_wrap::lf());
+
+    let mut armored = b"--------------------- BEGIN RSA MESSAGE  ---------------------\n".to_vec();
+    armored.extend(encrypted);
+    armored.extend_from_slice(b"\n--------------------- END RSA MESSAGE  ---------------------");
+    Ok(armored)
+}
+
+// The file format of a revocation certificate
+fn armor_revocation(certificate: &[u8]) -> String {
+    format!("------------------- BEGIN RSA REVOCATION CERTIFICATE --------------------
+                             \n {}
+                             \n------------------- END RSA REVOCATION CERTIFICATE ----------------------\n",
+                             textwrap::fill(&base64::encode(certificate), 70))
+}
+
+// `path` with `.suffix` appended to the file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replace `path` with `data` in one step. The data goes to a temporary file next to it, which is renamed over
+/// `path` once it is complete, so a failure never leaves a half-written file behind
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), RSAError> {
+    let temp = with_suffix(path, &format!("{}.tmp", std::process::id()));
+    let written = (|| -> Result<(), RSAError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // only we can read what may be a private key, unless the file it replaces was readable by others
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp)?;
+        if let Ok(meta) = std::fs::metadata(path) {
+            std::fs::set_permissions(&temp, meta.permissions())?;
+        }
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+// `inspect` for people: the user, then each key with everything that is known about it
+fn describe_user(info: &UserInfo) -> String {
+    let date = |time: u64| chrono::DateTime::from_timestamp(time as i64, 0).map_or_else(|| time.to_string(), |t| t.format("%Y-%m-%d").to_string());
+    let mut text = format!("{} ({}, trust {}{}, {})\n", info.user, info.ownership, info.trust,
+        if info.verified && info.ownership == "contact" { ", verified" } else { "" }, if info.valid { "valid" } else { "not valid" });
+    for key in &info.keys {
+        text.push_str(&format!("\n  key {}{}\n", key.id, if key.primary { " (primary)" } else { "" }));
+        text.push_str(&format!("    fingerprint  {}\n                 {}\n", key.fingerprint_base64, key.fingerprint));
+        text.push_str(&format!("    size         {} bits, {}\n", key.bits, if key.private { "with private key" } else { "public key only" }));
+        text.push_str(&format!("    usage        {}\n", key.usages.join(", ")));
+        if let Some(created) = key.created {
+            text.push_str(&format!("    created      {}\n", date(created)));
+        }
+        if let Some(expires) = key.expires {
+            text.push_str(&format!("    {}      {}\n", if key.expired { "expired" } else { "expires" }, date(expires)));
+        }
+        if let (Some(revoked_at), Some(reason)) = (key.revoked_at, key.revocation_reason) {
+            text.push_str(&format!("    revoked      {}, {}\n", date(revoked_at), reason));
+        }
+        if let Some(comment) = &key.comment {
+            text.push_str(&format!("    comment      {}\n", comment));
+        }
+    }
+    text
+}
+
+// Every file below `dir` that holds an encrypted message, in a stable order
+fn message_files(dir: &Path) -> Result<Vec<PathBuf>, RSAError> {
+    let mut files = Vec::new();
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            files.extend(message_files(&path)?);
+        } else if path.extension().is_some_and(|e| e == "orig") {
+            // originals kept by an earlier rotation
+        } else if std::fs::read(&path).is_ok_and(|data| data.windows(17).any(|w| w == b"BEGIN RSA MESSAGE")) {
+            files.push(path);
+        }
+    }
+    Ok(files)
 }
 
 // Take from format --- BEGIN ---- {content} ---- END --- to just content
-fn parse_rsa_format(input: &str) -> Result<String, Error> {
-    //  do some basic input sanitization first, in case the user/OS entered some newlines in the file
+fn parse_rsa_format(input: &str) -> Result<String, RSAError> {
+    //  do some basic input sanitization first, in case the user/OS entered some whitespace in the file
     //  actually modifying the base64 in any way would lead to total failure, however
-    let re_replace = Regex::new(r"[\t\n]*")?;
+    let re_replace = Regex::new(r"\s*")?;
     let input = re_replace.replace_all(input, "");
     let re_base64 = Regex::new(r"-+[ A-Z]+-+([A-Za-z0-9+/=?]+)")?;
     let base64_cap = re_base64.captures(&input).ok_or(ErrorKind::RegexParse)?;
     Ok(base64_cap.get(1).ok_or(ErrorKind::RegexParse)?.as_str().into())
 }
 
-impl Opts {
-    pub fn parse() -> Result<Self, Error> {
-        let args = CLI::from_args();
-        let database_file = PathBuf::from(args.database.clone());
-        let database: SimpleDB<HashMap<String, RSA>> = SimpleDB::new(database_file)?;
-        Ok(Opts {
-            args: args,
-            rsa: AlgoRSA::new(database)?
-        })
+/// `name`, or the user with a key of that id or fingerprint if nobody is called `name`
+fn resolve_user(rsa: &AlgoRSA, name: String) -> Result<String, RSAError> {
+    if rsa.user_exists(&name) {
+        return Ok(name);
     }
+    rsa.find_key_id(&name).or_else(|e| match e.kind() {
+        ErrorKind::KeyNotFound => Ok(name),
+        _ => Err(e),
+    })
+}
 
-    //TODO: Remove panic
-    pub fn generate_dialog(&self) -> Result<(), Error> {
-        if self.args.generate {
-            println!("Who Are You?");
-            let user = prompt_string()?;
-
-            println!("Hello {}. Choose a KeySize (One of 512, 1024, 2048, 4096, 8192)", user);
-            let key_size = KeySize::from_input(&prompt_number()?)?;
+impl Opts {
+    pub fn new(mut args: CLI) -> Result<Self, RSAError> {
+        if let Some(command) = args.legacy_command()? {
+            args.command = Some(command);
+        }
+        let rsa = AlgoRSA::new(open_keyring(&args.database, args.passphrase_fd)?);
+        // `--user`, `--recipient` and `cms encrypt --to` can name a key instead, by key id or fingerprint, where they name someone we have
+        if args.command.as_ref().is_some_and(Command::looks_up_user) {
+            args.user = args.user.take().map(|user| resolve_user(&rsa, user)).transpose()?;
+        }
+        match &mut args.command {
+            Some(Command::Encrypt { recipient, .. }) => {
+                *recipient = recipient.take().map(|recipient| resolve_user(&rsa, recipient)).transpose()?;
+            },
+            Some(Command::Cms(CmsCommand::Encrypt { recipients, .. })) => {
+                *recipients = recipients.drain(..).map(|recipient| resolve_user(&rsa, recipient)).collect::<Result<_, _>>()?;
+            },
+            _ => {},
+        }
+        Ok(Opts { args, rsa, interactive: std::io::stdin().is_terminal() })
+    }
 
-            println!("Hold On, Generating Key of size {} and committing to the Database", key_size.as_num());
-            // TODO: Start in separate thread
-            self.rsa.create(&user, &key_size)?;
+    fn user(&self) -> Result<&str, RSAError> {
+        Ok(self.args.user.as_deref().ok_or(ErrorKind::NoUserSpecified)?)
+    }
 
-            println!("User {} with public/private keys added to database!", user);
+    /// Run the command given on the command line. Changes to the database are saved by `finish`
+    pub fn run(&self) -> Result<(), RSAError> {
+        // before anything is changed, such as a key revoked whose certificate is to be written
+        self.check_output()?;
+        let command = match &self.args.command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        match command {
+            Command::Keygen { bits, exponent, comment } => self.keygen(*bits, *exponent, comment.as_deref()),
+            Command::Encrypt { recipient, message, file } => {
+                let recipient = match recipient {
+                    Some(recipient) => recipient,
+                    None => self.user()?,
+                };
+                self.encrypt(recipient, &Self::read_input(message, file)?)
+            },
+            Command::Decrypt { message, file } => self.decrypt(&String::from_utf8(Self::read_input(message, file)?)?),
+            Command::Sign { file, scheme } => self.sign(&Self::read_input(&None, file)?, scheme),
+            Command::Verify { file, signature, scheme } => self.verify(file, signature, scheme),
+            Command::Export { private, revocation, key_id } => self.export(*private, *revocation, key_id.as_deref()),
+            Command::Import { text, file, private, revocation } => self.import(&String::from_utf8(Self::read_input(text, file)?)?, *private, *revocation),
+            Command::List => match self.args.output {
+                OutputFormat::Json => self.write_json(&serde_json::json!({ "users": self.rsa.inspect_all()? })),
+                OutputFormat::Text => self.write_output(format!("{}\n", self.rsa.list()?).as_bytes()),
+            },
+            Command::Inspect => {
+                let info = self.rsa.inspect(self.user()?)?;
+                match self.args.output {
+                    OutputFormat::Json => self.write_json(&info),
+                    OutputFormat::Text => self.write_output(describe_user(&info).as_bytes()),
+                }
+            },
+            Command::Edit { primary, expiry, comment, usage, trust } => {
+                self.edit(primary.as_deref(), expiry.as_deref(), comment.as_deref(), usage.as_deref(), trust.as_deref())
+            },
+            Command::Revoke { reason, key_id } => {
+                let certificate = self.rsa.revoke(self.user()?, key_id.as_deref(), reason.parse()?)?;
+                self.write_output(armor_revocation(&certificate).as_bytes())
+            },
+            Command::Jwt(command) => self.jwt(command),
+            Command::Cms(command) => self.cms(command),
+            Command::Ssh(SshCommand::PublicKey) => {
+                let user = self.user()?;
+                self.write_output(format!("{}\n", PublicKey::new(self.rsa.public_key(user)?).to_line(user)).as_bytes())
+            },
+            Command::Rotate { archive, include_legacy } => self.rotate(archive.as_deref(), *include_legacy),
+            Command::Delete { yes, .. } => self.delete(*yes),
+            Command::Rename { to } => {
+                let user = self.user()?;
+                self.rsa.rename(user, to)?;
+                say!("Renamed {} to {}", user, to);
+                Ok(())
+            },
+            Command::Copy { to } => {
+                let user = self.user()?;
+                self.rsa.copy(user, to)?;
+                say!("Copied the public keys of {} to {}", user, to);
+                Ok(())
+            },
+            Command::VerifyContact { fingerprint } => self.verify_contact(fingerprint.as_deref()),
+            Command::Certify { by } => {
+                let user = self.user()?;
+                self.rsa.certify(by, user)?;
+                say!("{} certified the key of {}", by, user);
+                Ok(())
+            },
+            Command::TrustPath => {
+                let user = self.user()?;
+                let text = match self.rsa.trust_path(user)? {
+                    Some(path) => path.join(" -> "),
+                    None => format!("The key of {} is not certified by anyone you trust", user),
+                };
+                self.write_output(format!("{}\n", text).as_bytes())
+            },
+            // re-encrypts the whole database, which `finish` does
+            Command::Passphrase { .. } => Ok(()),
         }
-
-        Ok(())
     }
 
-    pub fn decrypt_dialog(&self) -> Result<(), Error> {
-        if let Some(message) = &self.args.decrypt {
-            self.decrypt(message)?;
+    fn keygen(&self, bits: Option<usize>, exponent: usize, comment: Option<&str>) -> Result<(), RSAError> {
+        if exponent != E {
+            Err(ErrorKind::UnsupportedKey)?
         }
+        // only ask for what is missing, and only if someone is there to answer
+        let interactive = self.interactive;
+        let user = match &self.args.user {
+            Some(user) => user.clone(),
+            None if interactive => {
+                say!("Who Are You?");
+                prompt_string()?
+            },
+            None => Err(ErrorKind::NoUserSpecified)?,
+        };
+        let bits = match bits {
+            Some(bits) => bits,
+            None if interactive => {
+                say!("Hello {}. Choose a KeySize (One of 512, 1024, 2048, 4096, 8192)", user);
+                prompt_number()?
+            },
+            None => Err(ErrorKind::MissingArgument)?,
+        };
+        let key_size = KeySize::from_input(&bits)?;
 
-        if let Some(file) = &self.args.decrypt_file {
-            let mut file = File::open(file)?;
-            let mut message = String::new();
-            file.read_to_string(&mut message)?;
-            self.decrypt(&message)?;
+        let json = self.args.output == OutputFormat::Json;
+        if !json {
+            say!("Hold On, Generating Key of size {} and committing to the Database", key_size.as_num());
+        }
+        // TODO: Start in separate thread
+        let had_keys = self.rsa.user_exists(&user);
+        let key_id = self.rsa.create(&user, &key_size)?;
+        if let Some(comment) = comment.filter(|c| !c.is_empty()) {
+            self.rsa.edit_key(&user, |key| key.set_comment(Some(comment.to_string())))?;
+        }
+
+        if json {
+            let key = self.rsa.inspect(&user)?.keys.into_iter().find(|key| key.id == key_id).ok_or(ErrorKind::KeyNotFound)?;
+            self.write_json(&serde_json::json!({ "user": user, "key": key }))?;
+        } else if had_keys {
+            say!("Key {} is now the primary key of {}. Older keys are kept to decrypt old messages", key_id, user);
+        } else {
+            say!("User {} with public/private keys added to database!", user);
         }
         Ok(())
     }
 
-    fn decrypt(&self, message: &String) -> Result<(), Error> {
-        let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
+    fn decrypt(&self, message: &str) -> Result<(), RSAError> {
+        let user = self.user()?;
         let message = parse_rsa_format(message)?;
         let message = base64::decode(&message)?;
-        let message: Vec<BigUint> = bincode::deserialize(&message)?;
-        let decrypted = self.rsa.decrypt(&user, message)?;
+        let message = Message::from_bytes(&message)?;
+        let decrypted = self.rsa.decrypt(user, message)?;
+        self.write_output(&decrypted)
+    }
 
-        if let Some(file) = &self.args.output_file {
-            let mut file = Self::handle_paths(file)?;
-            file.write_all(&decrypted)?;
-            file.flush()?;
-        } else {
-            std::io::stdout().write(decrypted.as_slice())?;
-            std::io::stdout().flush()?;
+    fn encrypt(&self, user: &str, buffer: &[u8]) -> Result<(), RSAError> {
+        self.warn_unverified(user)?;
+        let encrypted = armor_message(&self.rsa.encrypt(user, buffer)?)?;
+        self.write_output(&encrypted)
+    }
+
+    fn sign(&self, data: &[u8], scheme: &SchemeArgs) -> Result<(), RSAError> {
+        let user = self.user()?;
+        let signature = self.rsa.sign(user, scheme.scheme(), data)?;
+        if self.args.output == OutputFormat::Json {
+            return self.write_json(&serde_json::json!({
+                "user": user,
+                "key_id": self.rsa.key_id(user)?,
+                "scheme": scheme.scheme,
+                "hash": scheme.hash,
+                "signature": base64::encode(&signature),
+            }));
+        }
+        let export = format!("------------------------ BEGIN RSA SIGNATURE -------------------------\n{}\n------------------------ END RSA SIGNATURE ---------------------------\n",
+                             textwrap::fill(&base64::encode(&signature), 70));
+        self.write_output(export.as_bytes())
+    }
+
+    fn verify(&self, file: &str, signature: &str, scheme: &SchemeArgs) -> Result<(), RSAError> {
+        let scheme = scheme.scheme();
+        if file == "-" && signature == "-" {
+            Err(ErrorKind::StdinTwice)?
+        }
+        let data = Self::read_file(file)?;
+        let signature = base64::decode(&parse_rsa_format(&String::from_utf8(Self::read_file(signature)?)?)?)?;
+        let signer = match &self.args.user {
+            Some(user) => {
+                self.rsa.verify(user, scheme, &data, &signature)?;
+                user.clone()
+            },
+            None => {
+                // the same key can be known under several names; prefer one it is valid for
+                let (mut signers, mut refused) = (Vec::new(), None);
+                for user in self.rsa.users()? {
+                    match self.rsa.verify(&user, scheme, &data, &signature) {
+                        Ok(()) => signers.push(user),
+                        // the signature checks out, but the key that made it may no longer be relied on
+                        Err(e) if matches!(e.kind(), ErrorKind::KeyRevoked | ErrorKind::KeyExpired) => {
+                            refused.get_or_insert(e);
+                        },
+                        Err(_) => {},
+                    }
+                }
+                let valid = signers.iter().position(|user| self.rsa.is_valid(user).unwrap_or(false));
+                match signers.into_iter().nth(valid.unwrap_or(0)) {
+                    Some(signer) => signer,
+                    None => Err(refused.unwrap_or_else(|| ErrorKind::InvalidSignature.into()))?,
+                }
+            },
+        };
+        // only hard revocations fail a signature without a time, so a soft one is all that can be left
+        let revocation = self.rsa.signature_revocation(&signer, scheme, &data, &signature)?;
+        if self.args.output == OutputFormat::Json {
+            let revoked = revocation.map(|r| r.reason.as_str());
+            return self.write_json(&serde_json::json!({ "valid": true, "signer": signer, "trusted": self.rsa.is_valid(&signer)?, "revoked": revoked }));
         }
+        self.warn_unverified(&signer)?;
+        if let Some(revocation) = revocation {
+            let date = chrono::DateTime::from_timestamp(revocation.revoked_at as i64, 0)
+                .map_or_else(|| revocation.revoked_at.to_string(), |t| t.format("%Y-%m-%d").to_string());
+            eprintln!("Warning: the key that made this signature was revoked as {} on {}. The signature does not say when it was made, so it may have been made since", revocation.reason.as_str(), date);
+        }
+        say!("Good signature from {}", signer);
         Ok(())
     }
 
-    pub fn encrypt_dialog(&self) -> Result<(), Error> {
-        if let Some(message) = &self.args.encrypt {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            self.encrypt(user, &message.as_bytes())?;
+    fn warn_unverified(&self, user: &str) -> Result<(), RSAError> {
+        if !self.rsa.is_valid(user)? {
+            eprintln!("Warning: the key of {} has not been verified or certified by someone you trust. Compare its fingerprint with them and run `verify-contact`", user);
         }
+        Ok(())
+    }
 
-        if let Some(data_file) = &self.args.encrypt_file {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            let mut f = Self::handle_paths(data_file)?;
-            let mut buffer: Vec<u8> = Vec::new();
-            f.read_to_end(&mut buffer)?;
-            self.encrypt(&user, &buffer.as_slice())?;
+    fn edit(&self, primary: Option<&str>, expiry: Option<&str>, comment: Option<&str>, usage: Option<&str>, trust: Option<&str>) -> Result<(), RSAError> {
+        let user = self.user()?;
+        // parse everything before changing anything
+        let expires = expiry.map(parse_expiry).transpose()?;
+        let usages = usage
+            .map(|usages| usages.split(',').map(|u| u.trim().parse()).collect::<Result<Vec<Usage>, _>>())
+            .transpose()?;
+        let trust: Option<Trust> = trust.map(str::parse).transpose()?;
+        if let Some(trust) = trust {
+            self.rsa.set_trust(user, trust)?;
+        }
+        if let Some(key_id) = primary {
+            self.rsa.set_primary(user, key_id)?;
+        }
+        if expires.is_none() && comment.is_none() && usages.is_none() {
+            return Ok(());
         }
+        self.rsa.edit_key(user, |key| {
+            if let Some(expires) = expires {
+                key.set_expires(expires);
+            }
+            if let Some(comment) = comment {
+                key.set_comment(Some(comment.to_string()).filter(|c| !c.is_empty()));
+            }
+            if let Some(usages) = usages {
+                key.set_usages(usages);
+            }
+        })
+    }
 
+    fn verify_contact(&self, fingerprint: Option<&str>) -> Result<(), RSAError> {
+        let user = self.user()?;
+        let fingerprint = match fingerprint {
+            Some(fingerprint) => fingerprint.to_string(),
+            None => {
+                let key = self.rsa.keys(user)?.primary()?.clone();
+                say!("The primary key of {} has the fingerprint\n\n    {}\n    {}\n", user, key.fingerprint_base64(), key.fingerprint_hex());
+                print!("Did {} confirm this fingerprint, in person or over a channel you trust? [y/N] ", user);
+                std::io::stdout().flush()?;
+                if !prompt_string()?.eq_ignore_ascii_case("y") {
+                    Err(ErrorKind::Cancelled)?
+                }
+                key.fingerprint_hex()
+            },
+        };
+        self.rsa.verify_contact(user, &fingerprint)?;
+        say!("The key of {} is verified", user);
         Ok(())
     }
 
-    fn encrypt(&self, user: &String, buffer: &[u8]) -> Result<(), Error> {
-        let encrypted = self.rsa.encrypt(user, buffer)?;
-        let encrypted = bincode::serialize(&encrypted)?; //serializing Vec<Vec<u8>>
-        let mut encrypted = base64::encode(&encrypted).into_bytes();
-        let length = encrypted.len();
-        let added_lines = encrypted.len() / 75;
-        encrypted.resize(encrypted.len() + added_lines, 0);
-        line_wrap::line_wrap(encrypted.as_mut_slice(), length, 75, &line_wrap::lf());
-
-        if let Some(file) = &self.args.output_file {
-            let mut file = Self::handle_paths(file)?;
-            file.write(b"--------------------- BEGIN RSA MESSAGE  ---------------------\n")?;
-            file.write_all(&encrypted.as_slice())?;
-            file.write_all(b"\n--------------------- END RSA MESSAGE  ---------------------")?;
-            file.flush()?;
-        } else {
-            let out = std::io::stdout();
-            let mut handle = out.lock();
-            handle.write(b"--------------------- BEGIN RSA MESSAGE  ---------------------\n")?;
-            handle.write(encrypted.as_slice())?;
-            handle.write(b"\n--------------------- END RSA MESSAGE  -----------------------")?;
+    fn rotate(&self, archive: Option<&str>, include_legacy: bool) -> Result<(), RSAError> {
+        let user = self.user()?;
+        let key_id = self.rsa.rotate(user)?;
+        // the new key has to be in the database before anything is encrypted to it
+        self.rsa.flush()?;
+        say!("Key {} is now the primary key of {}. The old key was revoked as superseded", key_id, user);
+
+        if let Some(archive) = archive {
+            let (mut done, mut failed) = (0, 0);
+            let own_keys = self.rsa.keys(user)?;
+            for path in message_files(&PathBuf::from(archive))? {
+                match self.reencrypt_file(user, &own_keys, &key_id, &path, include_legacy) {
+                    Ok(true) => {
+                        done += 1;
+                        say!("re-encrypted {}", path.display());
+                    },
+                    Ok(false) => say!("skipped {}", path.display()),
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("failed {}: {}", path.display(), e);
+                    },
+                }
+            }
+            say!("Re-encrypted {} files, {} failed", done, failed);
+            if failed > 0 {
+                Err(ErrorKind::ArchiveIncomplete)?
+            }
         }
         Ok(())
     }
 
-    fn handle_paths(path: &String) -> Result<File, Error> {
-        let path = PathBuf::from(path);
-        if !path.exists() {
-            Ok(File::create(path)?)
-        } else {
-            Ok(File::open(path)?)
+    // re-encrypt the message in `path` to the key `key_id`, unless it already is or belongs to someone else.
+    // Textbook RSA cannot tell a wrong key from the right one, so a message without a key id is only touched if asked
+    fn reencrypt_file(&self, user: &str, own_keys: &UserKeys, key_id: &str, path: &Path, include_legacy: bool) -> Result<bool, RSAError> {
+        let message = Message::from_bytes(&base64::decode(&parse_rsa_format(&std::fs::read_to_string(path)?)?)?)?;
+        match &message.key_id {
+            Some(id) if own_keys.get(id).is_none_or(|key| key.id() == key_id) => return Ok(false),
+            None if !include_legacy => return Ok(false),
+            _ => (),
+        }
+        let armored = armor_message(&self.rsa.reencrypt(user, message)?)?;
+        let orig = with_suffix(path, "orig");
+        if orig.exists() {
+            Err(ErrorKind::OutputExists)?
         }
+        std::fs::copy(path, &orig)?;
+        write_atomically(path, &armored)?;
+        Ok(true)
     }
 
-    pub fn export_dialog(&self) -> Result<(), Error> {
-        if self.args.export_public {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            let key = self.rsa.export(&user, KeyType::Public)?;
-            let export = format!("----------------------- BEGIN RSA PUBLIC KEY ------------------------
+    fn export(&self, private: bool, revocation: bool, key_id: Option<&str>) -> Result<(), RSAError> {
+        let user = self.user()?;
+        let export = if revocation {
+            armor_revocation(&self.rsa.export_revocation(user, key_id)?)
+        } else if private {
+            let key = self.rsa.export(user, KeyType::Private)?;
+            format!("----------------------- BEGIN RSA PRIVATE KEY ------------------------
                                  \n {}
-                                 \n----------------------- END RSA PUBLIC KEY --------------------------",
-                                 textwrap::fill(&key, 70));
-            println!("{}", export);
-        }
-
-        if self.args.export_private {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            let key = self.rsa.export(&user, KeyType::Private)?;
-            let export = format!("----------------------- BEGIN RSA PRIVATE KEY ------------------------
+                                 \n----------------------- END RSA PRIVATE KEY --------------------------\n",
+                                 textwrap::fill(&key, 70))
+        } else {
+            let key = self.rsa.export(user, KeyType::Public)?;
+            let mut export = format!("----------------------- BEGIN RSA PUBLIC KEY ------------------------
                                  \n {}
-                                 \n----------------------- END RSA PRIVATE KEY --------------------------",
+                                 \n----------------------- END RSA PUBLIC KEY --------------------------\n",
                                  textwrap::fill(&key, 70));
-            println!("{}", export);
+            if !self.rsa.keys(user)?.certifications().is_empty() {
+                let certifications = base64::encode(&self.rsa.export_certifications(user)?);
+                export.push_str(&format!("--------------------- BEGIN RSA CERTIFICATIONS ----------------------
+                                 \n {}
+                                 \n--------------------- END RSA CERTIFICATIONS ------------------------\n",
+                                 textwrap::fill(&certifications, 70)));
+            }
+            export
+        };
+        self.write_output(export.as_bytes())
+    }
+
+    fn import(&self, text: &str, private: bool, revocation: bool) -> Result<(), RSAError> {
+        if revocation {
+            let certificate = base64::decode(&parse_rsa_format(text)?)?;
+            let user = self.rsa.import_revocation(&certificate)?;
+            say!("Revoked a key of {}", user);
+            return Ok(());
         }
+        let user = self.user()?;
+        if private {
+            let privkey = base64::decode(&parse_rsa_format(text)?)?;
+            return self.rsa.import_private(user, &BigUint::from_bytes_be(&privkey));
+        }
+        let pubkey = base64::decode(&parse_rsa_format(text)?)?;
+        let size = KeySize::from_input(&(pubkey.len() * 8))?;
+        let pubkey = BigUint::from_bytes_be(&pubkey);
+        let rsa = RSA::new(pubkey, None, size);
+        self.rsa.import(user, rsa)?;
+        // certifications exported along with the key follow it
+        if let Some(header) = text.find("BEGIN RSA CERTIFICATIONS") {
+            let start = text[..header].trim_end_matches([' ', '-']).len();
+            let certifications = base64::decode(&parse_rsa_format(&text[start..])?)?;
+            let added = self.rsa.import_certifications(user, &certifications)?;
+            say!("Imported {} certifications of {}", added, user);
+        }
+        Ok(())
+    }
 
+    fn delete(&self, yes: bool) -> Result<(), RSAError> {
+        let user = self.user()?;
+        let has_private = self.rsa.keys(user)?.iter().any(RSA::private_exists);
+        if has_private && !yes {
+            print!("Delete {} with their private keys? Anything encrypted to them can no longer be decrypted [y/N] ", user);
+            std::io::stdout().flush()?;
+            if !prompt_string()?.eq_ignore_ascii_case("y") {
+                Err(ErrorKind::Cancelled)?
+            }
+        }
+        self.rsa.delete(user)?;
+        say!("Deleted {}", user);
         Ok(())
     }
 
-    pub fn import_dialog(&self) -> Result<(), Error> {
-        if self.args.import_public.is_some() {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            let pubkey = base64::decode(&parse_rsa_format(&self.args.import_public.as_ref().expect("Within checked scope; Q.E.D"))?)?;
-            let size = KeySize::from_input(&(pubkey.len() * 8))?;
-            let pubkey = BigUint::from_bytes_be(&pubkey);
-            let rsa = RSA::new(pubkey, None, size);
-            self.rsa.import(user, rsa);
+    fn jwt(&self, command: &JwtCommand) -> Result<(), RSAError> {
+        match command {
+            JwtCommand::Sign { alg, claims, expires_in } => {
+                let claims = match claims.as_str() {
+                    "-" => String::from_utf8(Self::read_file(claims)?)?,
+                    claims => claims.to_string(),
+                };
+                let claims = serde_json::from_str(&claims).context(ErrorKind::MalformedToken)?;
+                let claims = jwt::with_times(claims, *expires_in);
+                let token = jwt::sign(&self.rsa, self.user()?, jwt::Algorithm::from_name(alg)?, &claims)?;
+                self.write_output(format!("{}\n", token).as_bytes())?;
+            },
+            JwtCommand::Verify { token, leeway } => {
+                let token = match token.as_str() {
+                    "-" => String::from_utf8(Self::read_file(token)?)?,
+                    token => token.to_string(),
+                };
+                let verified = jwt::verify(&self.rsa, token.trim(), self.args.user.as_deref(), *leeway)?;
+                eprintln!("Valid {} token signed by {}", verified.header.alg, verified.user);
+                self.write_json(&verified.claims)?;
+            }
         }
+        Ok(())
+    }
 
-        if self.args.import_private.is_some() {
-            let user = self.args.user.as_ref().ok_or(ErrorKind::NoUserSpecified)?;
-            let privkey = base64::decode(&parse_rsa_format(&self.args.import_private.as_ref().expect("Within checked scope; Q.E.D"))?)?;
-            self.rsa.import_private(user, &BigUint::from_bytes_be(&privkey))?;
+    fn cms(&self, command: &CmsCommand) -> Result<(), RSAError> {
+        match command {
+            CmsCommand::Certificate => {
+                self.write_output(der::to_pem("CERTIFICATE", &cms::certificate(&self.rsa, self.user()?)?).as_bytes())?;
+            },
+            CmsCommand::ImportCertificate { file } => {
+                cms::import_certificate(&self.rsa, self.user()?, &Self::read_file(file)?)?;
+            },
+            CmsCommand::Encrypt { file, recipients, der } => {
+                for recipient in recipients {
+                    self.warn_unverified(recipient)?;
+                }
+                let encrypted = cms::encrypt(&self.rsa, recipients, &Self::read_file(file)?)?;
+                self.write_cms(&encrypted, *der)?;
+            },
+            CmsCommand::Decrypt { file } => {
+                let (recipient, decrypted) = cms::decrypt(&self.rsa, &Self::read_file(file)?)?;
+                eprintln!("Decrypted for {}", recipient);
+                self.write_output(&decrypted)?;
+            },
+            CmsCommand::Sign { file, detached, der } => {
+                let signed = cms::sign(&self.rsa, self.user()?, &Self::read_file(file)?, *detached)?;
+                self.write_cms(&signed, *der)?;
+            },
+            CmsCommand::Verify { file, content } => {
+                if file == "-" && content.as_deref() == Some("-") {
+                    Err(ErrorKind::StdinTwice)?
+                }
+                let content = content.as_ref().map(|c| Self::read_file(c)).transpose()?;
+                let verified = cms::verify(&self.rsa, &Self::read_file(file)?, content.as_deref())?;
+                eprintln!("Verification successful. Signed by {}", verified.signers.join(", "));
+                if content.is_none() {
+                    self.write_output(&verified.content)?;
+                }
+            },
         }
         Ok(())
     }
 
-    pub fn list_dialog(&self) -> Result<(), Error> {
-        if self.args.list {
-            println!("{}", self.rsa.list()?);
+    fn write_cms(&self, data: &[u8], as_der: bool) -> Result<(), RSAError> {
+        if as_der {
+            self.write_output(data)
+        } else {
+            self.write_output(der::to_pem("CMS", data).as_bytes())
         }
+    }
 
-        Ok(())
+    // `--file`, unless it is stdout
+    fn output_file(&self) -> Option<&Path> {
+        self.args.output_file.as_deref().filter(|file| *file != "-").map(Path::new)
     }
 
-    pub fn finish(self) -> Result<(), Error> {
-        self.rsa.save_keys()?;
+    // refuse `--file` for a command with nothing to write, and to replace an existing file without `--force`
+    fn check_output(&self) -> Result<(), RSAError> {
+        match self.output_file() {
+            Some(_) if !self.writes_output() => Err(ErrorKind::OutputNotSupported)?,
+            Some(file) if file.exists() && !self.args.force => Err(ErrorKind::OutputExists)?,
+            _ => Ok(()),
+        }
+    }
+
+    // whether the command writes anything that `--file` could take
+    fn writes_output(&self) -> bool {
+        match &self.args.command {
+            Some(Command::Verify { .. }) => self.args.output == OutputFormat::Json,
+            Some(Command::Cms(CmsCommand::ImportCertificate { .. })) => false,
+            Some(Command::Cms(CmsCommand::Verify { content, .. })) => content.is_none(),
+            Some(Command::Encrypt { .. } | Command::Decrypt { .. } | Command::Sign { .. } | Command::Export { .. }
+                | Command::List | Command::Inspect | Command::Revoke { .. } | Command::TrustPath
+                | Command::Jwt(_) | Command::Cms(_) | Command::Ssh(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Write to `--file` if one was given, otherwise to stdout. The file only appears once all of `data` is written
+    fn write_output(&self, data: &[u8]) -> Result<(), RSAError> {
+        if let Some(file) = self.output_file() {
+            self.check_output()?;
+            write_atomically(file, data)?;
+        } else {
+            write_stdout(data)?;
+        }
         Ok(())
     }
+
+    fn write_json<T: serde::Serialize>(&self, value: &T) -> Result<(), RSAError> {
+        let mut json = serde_json::to_vec_pretty(value)?;
+        json.push(b'\n');
+        self.write_output(&json)
+    }
+
+    /// The contents of the file at `path`, or of stdin if it is `-`
+    fn read_file(path: &str) -> Result<Vec<u8>, RSAError> {
+        let mut buffer = Vec::new();
+        if path == "-" {
+            stdin()?.read_to_end(&mut buffer)?;
+        } else {
+            File::open(path)?.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    // the text given on the command line, or else the contents of the file, or else stdin
+    fn read_input(text: &Option<String>, file: &Option<String>) -> Result<Vec<u8>, RSAError> {
+        match (text, file) {
+            (Some(text), _) => Ok(text.as_bytes().to_vec()),
+            (None, file) => Self::read_file(file.as_deref().unwrap_or("-")),
+        }
+    }
+
+    /// Whether the command line asked for anything at all
+    fn has_work(&self) -> bool {
+        self.args.command.is_some() || self.args.migrate || self.args.db_format.is_some()
+    }
+
+    pub fn finish(self) -> Result<(), RSAError> {
+        let mut store = self.rsa.into_store();
+        if self.args.migrate {
+            if store.migrate()? {
+                say!("Migrated {} to the current layout", store.location());
+            } else {
+                say!("{} is already in the current layout", store.location());
+            }
+        }
+        if let Some(codec) = self.args.db_format {
+            store.set_codec(codec)?;
+        }
+        if let Some(Command::Delete { erase: true, .. }) = self.args.command {
+            store.erase_backup()?;
+        }
+        if let Some(Command::Passphrase { remove }) = self.args.command {
+            let passphrase = if remove { None } else { Some(new_passphrase()?) };
+            return store.set_passphrase(passphrase);
+        }
+        store.flush()
+    }
 }
 
-pub struct App;
+pub enum App {
+    /// git invokes `gpg.ssh.program` with the arguments it would give to `ssh-keygen`
+    SshKeygen,
+    Cli(Box<CLI>),
+}
 
 impl App {
+    pub fn from_args() -> Self {
+        if std::env::args().nth(1).as_deref() == Some("-Y") {
+            App::SshKeygen
+        } else {
+            App::Cli(Box::new(CLI::from_args()))
+        }
+    }
 
+    /// How to print the error, should `run` fail
+    pub fn output(&self) -> OutputFormat {
+        match self {
+            App::Cli(args) => args.output,
+            App::SshKeygen => OutputFormat::Text,
+        }
+    }
 
-    pub fn run() -> Result<(), Error> {
-        let opts = Opts::parse()?;
-
-        opts.generate_dialog()?;
-        opts.encrypt_dialog()?;
-        opts.decrypt_dialog()?;
-        opts.export_dialog()?;
-        opts.list_dialog()?;
-        opts.import_dialog()?;
+    pub fn run(self) -> Result<(), RSAError> {
+        let args = match self {
+            App::SshKeygen => return SshKeygen::run(),
+            App::Cli(args) => args,
+        };
+        let opts = Opts::new(*args)?;
+        if !opts.has_work() {
+            CLI::clap().print_help().context(ErrorKind::Io)?;
+            say!();
+            return Ok(());
+        }
+        opts.run()?;
         opts.finish()?;
         Ok(())
     }
@@ -315,7 +1601,205 @@ mod tests {
 \n
 
 ------------------ END RSA PUBLIC KEY ---------------------";
-        parse_rsa_format(&test_str).unwrap();
+        parse_rsa_format(test_str).unwrap();
 
     }
+
+    #[test]
+    fn should_replace_files_atomically() {
+        let path = PathBuf::from("/tmp/RSA_CLI_OUTPUT_TEST");
+        let _ = std::fs::remove_file(&path);
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(), 0o600);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            write_atomically(&path, b"third").unwrap();
+            assert_eq!(mode(), 0o644);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // a directory cannot be replaced by a file; the temporary file must not be left behind
+        let dir = PathBuf::from("/tmp/RSA_CLI_OUTPUT_DIR");
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(write_atomically(&dir, b"data").is_err());
+        let leftovers = std::fs::read_dir("/tmp").unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("RSA_CLI_OUTPUT_DIR."))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    // the database at `db` holds alice and bob, with 512 bit keys
+    fn keyring(db: &str) -> AlgoRSA {
+        let _ = std::fs::remove_file(db);
+        let rsa = AlgoRSA::new(FileStore::open(PathBuf::from(db)).unwrap());
+        rsa.create("alice", &KeySize::FiveTwelve).unwrap();
+        rsa.create("bob", &KeySize::FiveTwelve).unwrap();
+        rsa.flush().unwrap();
+        rsa
+    }
+
+    fn run(args: &[&str]) -> Result<(), RSAError> {
+        let opts = Opts::new(CLI::from_iter(args))?;
+        opts.run()?;
+        opts.finish()
+    }
+
+    #[test]
+    fn should_leave_messages_of_others_alone_when_rotating() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("keys.db");
+        let db = db.to_str().unwrap();
+        let archive = dir.path().join("archive");
+        std::fs::create_dir_all(&archive).unwrap();
+        let rsa = keyring(db);
+        let mut legacy = rsa.encrypt("bob", b"for bob").unwrap();
+        legacy.key_id = None;
+        legacy.length = None;
+        let legacy = armor_message(&legacy).unwrap();
+        std::fs::write(archive.join("bob.rsa"), &legacy).unwrap();
+        std::fs::write(archive.join("alice.rsa"), armor_message(&rsa.encrypt("alice", b"for alice").unwrap()).unwrap()).unwrap();
+        drop(rsa);
+
+        run(&["rsa", "--db", db, "-u", "alice", "rotate", "--archive", archive.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read(archive.join("bob.rsa")).unwrap(), legacy);
+        assert!(!archive.join("bob.rsa.orig").exists());
+        assert!(archive.join("alice.rsa.orig").exists());
+
+        let rsa = AlgoRSA::new(FileStore::open(PathBuf::from(db)).unwrap());
+        let read = |name: &str| Message::from_bytes(&base64::decode(&parse_rsa_format(&std::fs::read_to_string(archive.join(name)).unwrap()).unwrap()).unwrap()).unwrap();
+        assert_eq!(rsa.decrypt("bob", read("bob.rsa")).unwrap(), b"for bob");
+        let message = read("alice.rsa");
+        assert_eq!(message.key_id, Some(rsa.key_id("alice").unwrap()));
+        assert_eq!(rsa.decrypt("alice", message).unwrap(), b"for alice");
+    }
+
+    #[test]
+    fn should_report_revoked_signers_without_a_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let db = path("keys.db");
+        drop(keyring(&db));
+        std::fs::write(path("data"), b"release").unwrap();
+        // 512 bit keys are too small for PSS
+        let verify = || run(&["rsa", "--db", &db, "verify", &path("data"), "-s", &path("data.sig"), "--scheme", "pkcs1"]);
+        run(&["rsa", "--db", &db, "-u", "alice", "sign", &path("data"), "-f", &path("data.sig"), "--scheme", "pkcs1"]).unwrap();
+        verify().unwrap();
+
+        let rsa = AlgoRSA::new(FileStore::open(PathBuf::from(&db)).unwrap());
+        rsa.revoke("alice", None, rsa::revocation::Reason::Compromised).unwrap();
+        rsa.flush().unwrap();
+        assert_eq!(verify().unwrap_err().kind(), ErrorKind::KeyRevoked);
+        std::fs::write(path("data"), b"forged").unwrap();
+        assert_eq!(verify().unwrap_err().kind(), ErrorKind::InvalidSignature);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_read_only_the_passphrase_from_its_descriptor() {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        assert_eq!(read_passphrase_fd(1 << 20).unwrap_err().kind(), ErrorKind::BadFileDescriptor);
+        assert_eq!(read_passphrase_fd(-1).unwrap_err().kind(), ErrorKind::BadFileDescriptor);
+
+        let path = "/tmp/RSA_CLI_PASSPHRASE_FD_TEST";
+        std::fs::write(path, "secret\nmessage").unwrap();
+        let fd = File::open(path).unwrap().into_raw_fd();
+        // shares the offset with `fd`, which is closed once the passphrase is read
+        let rest = unsafe { File::from_raw_fd(libc::dup(fd)) };
+        read_passphrase_fd(fd).unwrap();
+        assert_eq!(std::io::read_to_string(rest).unwrap(), "message");
+    }
+
+    #[test]
+    fn should_generate_keys_from_the_command_line_alone() {
+        let db = "/tmp/RSA_CLI_KEYGEN_TEST.db";
+        let _ = std::fs::remove_file(db);
+        let opts = |args: &[&str]| {
+            let mut opts = Opts::new(CLI::from_iter(["rsa", "--db", db].iter().chain(args))).unwrap();
+            opts.interactive = false;
+            opts
+        };
+        let keygen = opts(&["-u", "erin", "keygen", "--bits", "512", "--comment", "laptop"]);
+        keygen.run().unwrap();
+        keygen.finish().unwrap();
+        let key = AlgoRSA::new(FileStore::open(PathBuf::from(db)).unwrap()).inspect("erin").unwrap().keys.remove(0);
+        assert_eq!((key.bits, key.comment.as_deref(), key.private), (512, Some("laptop"), true));
+
+        // nobody is there to ask for what is missing
+        assert_eq!(opts(&["-u", "frank", "keygen"]).run().unwrap_err().kind(), ErrorKind::MissingArgument);
+        assert_eq!(opts(&["keygen", "--bits", "512"]).run().unwrap_err().kind(), ErrorKind::NoUserSpecified);
+        let exponent = opts(&["-u", "frank", "keygen", "--bits", "512", "--exponent", "3"]);
+        assert_eq!(exponent.run().unwrap_err().kind(), ErrorKind::UnsupportedKey);
+        exponent.finish().unwrap();
+        assert!(!AlgoRSA::new(FileStore::open(PathBuf::from(db)).unwrap()).user_exists("frank"));
+    }
+
+    #[test]
+    fn should_resolve_key_ids_only_for_existing_users() {
+        let db = "/tmp/RSA_CLI_RESOLVE_TEST.db";
+        let id = keyring(db).key_id("alice").unwrap();
+        let user = |command: &[&str]| {
+            let args = ["rsa", "--db", db, "-u", &id].iter().chain(command).copied().collect::<Vec<_>>();
+            Opts::new(CLI::from_iter(&args)).unwrap().args.user.unwrap()
+        };
+        assert_eq!(user(&["inspect"]), "alice");
+        assert_eq!(user(&["import", "--private"]), "alice");
+        assert_eq!(user(&["import", "bob.pub"]), id);
+        assert_eq!(user(&["keygen"]), id);
+        assert_eq!(user(&["cms", "import-certificate", "bob.pem"]), id);
+        match Opts::new(CLI::from_iter(&["rsa", "--db", db, "cms", "encrypt", "-", "-r", &id, "bob"])).unwrap().args.command {
+            Some(Command::Cms(CmsCommand::Encrypt { recipients, .. })) => assert_eq!(recipients, ["alice", "bob"]),
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[test]
+    fn should_write_output_to_the_file_or_refuse_it() {
+        let db = "/tmp/RSA_CLI_FILE_TEST.db";
+        let output = "/tmp/RSA_CLI_FILE_TEST.out";
+        let _ = std::fs::remove_file(output);
+        drop(keyring(db));
+        run(&["rsa", "--db", db, "list", "-f", output]).unwrap();
+        assert!(std::fs::read_to_string(output).unwrap().contains("alice"));
+        run(&["rsa", "--db", db, "-u", "bob", "jwt", "sign", "-f", output, "--force"]).unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap().matches('.').count(), 2);
+
+        let refused = run(&["rsa", "--db", db, "-u", "bob", "rename", "carol", "-f", output, "--force"]).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::OutputNotSupported);
+        assert!(AlgoRSA::new(FileStore::open(PathBuf::from(db)).unwrap()).user_exists("bob"));
+        run(&["rsa", "--db", db, "-u", "bob", "rename", "carol", "-f", "-"]).unwrap();
+    }
+
+    #[test]
+    fn should_print_errors_as_json() {
+        let error: RSAError = "twelve".parse::<u32>().map_err(RSAError::from).unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&error_json(&error)).unwrap();
+        assert_eq!(json["error"]["kind"], "WrongNumber");
+        assert_eq!(json["error"]["exit_code"], 2);
+        assert_eq!(json["error"]["causes"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn should_map_deprecated_flags_to_commands() {
+        let mut args = CLI::from_iter(&["rsa", "--db", "/tmp/rsa_cli_test.db", "-u", "alice", "--set-expiry", "never", "--set-comment", "work"]);
+        match args.legacy_command().unwrap() {
+            Some(Command::Edit { expiry, comment, primary: None, usage: None, trust: None }) => {
+                assert_eq!((expiry.unwrap(), comment.unwrap()), ("never".to_string(), "work".to_string()));
+            },
+            command => panic!("unexpected {:?}", command),
+        }
+
+        let mut args = CLI::from_iter(&["rsa", "--db", "/tmp/rsa_cli_test.db", "encrypt", "-u", "alice", "-m", "hi"]);
+        assert!(args.legacy_command().unwrap().is_none());
+        assert_eq!(args.user.as_deref(), Some("alice"));
+
+        for conflicting in &[&["-g", "-l"][..], &["-e", "hi", "list"][..], &["--export-public", "--export-private"][..]] {
+            let mut args = CLI::from_iter(["rsa", "--db", "/tmp/rsa_cli_test.db"].iter().chain(conflicting.iter()));
+            assert_eq!(args.legacy_command().unwrap_err().kind(), ErrorKind::ConflictingCommands);
+        }
+    }
 }
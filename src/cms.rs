@@ -0,0 +1,355 @@
+//! CMS (RFC 5652) EnvelopedData and SignedData, compatible with `openssl cms` and S/MIME
+//! Recipients and signers are identified by the IssuerAndSerialNumber of the certificate stored with their key
+use crate::rsa::{AlgoRSA, EncryptionScheme, HashAlgorithm, SignatureScheme};
+use crate::keystore::KeyStore;
+use crate::x509::{self, oid as x509_oid, Certificate};
+use crate::der::{self, Der};
+use crate::err::{ErrorKind, RSAError};
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+use chrono::Utc;
+use rand::Rng;
+
+/// Validity of certificates generated for keys that do not have one yet
+pub const CERTIFICATE_DAYS: i64 = 365 * 5;
+
+pub mod oid {
+    pub const DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+    pub const SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+    pub const ENVELOPED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 3];
+    pub const CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+    pub const MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+    pub const SIGNING_TIME: &[u64] = &[1, 2, 840, 113549, 1, 9, 5];
+    pub const AES128_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 2];
+    pub const AES192_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 22];
+    pub const AES256_CBC: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 42];
+}
+
+/// The certificate of `user`, creating and storing a self-signed one if the key does not have one yet
+pub fn certificate(rsa: &AlgoRSA<impl KeyStore>, user: &str) -> Result<Vec<u8>, RSAError> {
+    match rsa.certificate(user) {
+        Ok(cert) => Ok(cert),
+        Err(ref e) if e.kind() == ErrorKind::CertificateNotFound && rsa.private_exists(user) => {
+            let cert = Certificate::self_signed(rsa, user, CERTIFICATE_DAYS)?;
+            rsa.set_certificate(user, cert.clone())?;
+            Ok(cert)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Store a certificate for `user`, importing its public key if the user is not in the database yet
+pub fn import_certificate(rsa: &AlgoRSA<impl KeyStore>, user: &str, input: &[u8]) -> Result<(), RSAError> {
+    let der = der::from_pem("CERTIFICATE", input)?;
+    let cert = Certificate::parse(&der)?;
+    if cert.e != crate::rsa::E.into() {
+        Err(ErrorKind::UnsupportedKey)?
+    }
+    if rsa.user_exists(user) {
+        if rsa.public_key(user)? != cert.n {
+            Err(ErrorKind::CertificateMismatch)?
+        }
+    } else {
+        let size = crate::primes::KeySize::from_input(&(cert.n.to_bytes_be().len() * 8))?;
+        rsa.import(user, crate::rsa::RSA::new(cert.n.clone(), None, size))?;
+    }
+    rsa.set_certificate(user, der)
+}
+
+fn content_info(content_type: &[u64], content: Vec<u8>) -> Vec<u8> {
+    der::sequence(&[der::oid(content_type), der::context(0, true, &content)])
+}
+
+/// Parse a ContentInfo of the expected type, returning its content
+fn parse_content_info<'a>(input: &'a [u8], content_type: &[u64]) -> Result<Der<'a>, RSAError> {
+    let info = Der::parse(input)?.expect(der::SEQUENCE)?.children()?;
+    if info.len() != 2 || info[0].as_oid()? != content_type {
+        Err(ErrorKind::MalformedDer)?
+    }
+    let content = info[1].expect(0xa0)?.children()?;
+    Ok(*content.first().ok_or(ErrorKind::MalformedDer)?)
+}
+
+/// Content of an OCTET STRING, which BER allows to be split into a constructed sequence of chunks
+fn octets(octets: &Der) -> Result<Vec<u8>, RSAError> {
+    if octets.tag & 0x20 == 0 {
+        Ok(octets.content.to_vec())
+    } else {
+        Ok(octets.children()?.iter().map(|c| c.content).collect::<Vec<_>>().concat())
+    }
+}
+
+/// Encrypt `data` to each of `recipients` with AES-256-CBC, wrapping the key with RSAES-OAEP (SHA-256)
+pub fn encrypt(rsa: &AlgoRSA<impl KeyStore>, recipients: &[String], data: &[u8]) -> Result<Vec<u8>, RSAError> {
+    let mut rng = rand::thread_rng();
+    let mut key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    rng.fill(&mut key);
+    rng.fill(&mut iv);
+
+    let sha256 = x509::algorithm(x509_oid::SHA256, None);
+    let oaep_params = der::sequence(&[
+        der::context(0, true, &sha256),
+        der::context(1, true, &x509::algorithm(x509_oid::MGF1, Some(sha256.clone()))),
+    ]);
+    let mut recipient_infos = Vec::new();
+    for user in recipients {
+        let cert = Certificate::parse(&rsa.certificate(user)?)?;
+        let encrypted_key = rsa.encrypt_block(user, EncryptionScheme::Oaep(HashAlgorithm::Sha256), &key)?;
+        recipient_infos.push(der::sequence(&[
+            der::small_integer(0),
+            cert.issuer_and_serial(),
+            x509::algorithm(x509_oid::RSAES_OAEP, Some(oaep_params.clone())),
+            der::octet_string(&encrypted_key),
+        ]));
+    }
+
+    let encrypted = cbc::Encryptor::<aes::Aes256>::new(&key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data);
+    let enveloped = der::sequence(&[
+        der::small_integer(0),
+        der::set(&recipient_infos),
+        der::sequence(&[
+            der::oid(oid::DATA),
+            x509::algorithm(oid::AES256_CBC, Some(der::octet_string(&iv))),
+            der::context(0, false, &encrypted),
+        ]),
+    ]);
+    Ok(content_info(oid::ENVELOPED_DATA, enveloped))
+}
+
+/// Decrypt EnvelopedData addressed to any user in the database that has a private key and a certificate
+/// Returns the recipient that decrypted the message and the plaintext
+pub fn decrypt(rsa: &AlgoRSA<impl KeyStore>, input: &[u8]) -> Result<(String, Vec<u8>), RSAError> {
+    let input = der::from_pem("CMS", input)?;
+    let enveloped = parse_content_info(&input, oid::ENVELOPED_DATA)?.expect(der::SEQUENCE)?.children()?;
+    // version, optional [0] originatorInfo, recipientInfos, encryptedContentInfo
+    let rest = if enveloped.get(1).map(|d| d.tag) == Some(0xa0) { &enveloped[2..] } else { &enveloped[1..] };
+    if rest.len() < 2 {
+        Err(ErrorKind::MalformedDer)?
+    }
+
+    let ours: Vec<(String, Certificate)> = rsa.users()?.into_iter()
+        .filter(|user| rsa.private_exists(user))
+        .filter_map(|user| rsa.certificate(&user).ok().and_then(|c| Certificate::parse(&c).ok()).map(|c| (user, c)))
+        .collect();
+
+    let mut key = None;
+    for recipient in rest[0].expect(der::SET)?.children()? {
+        // only KeyTransRecipientInfo is supported; other kinds are tagged
+        if recipient.tag != der::SEQUENCE {
+            continue;
+        }
+        let fields = recipient.children()?;
+        if fields.len() != 4 {
+            Err(ErrorKind::MalformedDer)?
+        }
+        if let Some((user, _)) = ours.iter().find(|(_, cert)| cert.is_identified_by(&fields[1])) {
+            let scheme = key_transport_scheme(&fields[2])?;
+            key = Some((user.clone(), rsa.decrypt_block(user, scheme, fields[3].expect(der::OCTET_STRING)?.content)?));
+            break;
+        }
+    }
+    let (user, key) = key.ok_or(ErrorKind::RecipientNotFound)?;
+
+    let content_info = rest[1].expect(der::SEQUENCE)?.children()?;
+    if content_info.len() != 3 || content_info[2].tag & 0x1f != 0 {
+        Err(ErrorKind::MalformedDer)?
+    }
+    let algorithm = content_info[1].children()?;
+    let cipher = algorithm.first().ok_or(ErrorKind::MalformedDer)?.as_oid()?;
+    let iv = algorithm.get(1).ok_or(ErrorKind::MalformedDer)?.expect(der::OCTET_STRING)?.content;
+    let encrypted = octets(&content_info[2])?;
+    let decrypted = match (cipher.as_slice(), key.len()) {
+        (c, 16) if c == oid::AES128_CBC => cbc::Decryptor::<aes::Aes128>::new_from_slices(&key, iv)
+            .map(|d| d.decrypt_padded_vec_mut::<Pkcs7>(&encrypted)),
+        (c, 24) if c == oid::AES192_CBC => cbc::Decryptor::<aes::Aes192>::new_from_slices(&key, iv)
+            .map(|d| d.decrypt_padded_vec_mut::<Pkcs7>(&encrypted)),
+        (c, 32) if c == oid::AES256_CBC => cbc::Decryptor::<aes::Aes256>::new_from_slices(&key, iv)
+            .map(|d| d.decrypt_padded_vec_mut::<Pkcs7>(&encrypted)),
+        _ => Err(ErrorKind::UnsupportedAlgorithm)?
+    };
+    let decrypted = decrypted.map_err(|_| ErrorKind::MalformedDer)?.map_err(|_| ErrorKind::Decryption)?;
+    Ok((user, decrypted))
+}
+
+fn key_transport_scheme(algorithm: &Der) -> Result<EncryptionScheme, RSAError> {
+    let algorithm = algorithm.expect(der::SEQUENCE)?.children()?;
+    let arcs = algorithm.first().ok_or(ErrorKind::MalformedDer)?.as_oid()?;
+    if arcs == x509_oid::RSA_ENCRYPTION {
+        return Ok(EncryptionScheme::Pkcs1v15);
+    }
+    if arcs != x509_oid::RSAES_OAEP {
+        Err(ErrorKind::UnsupportedAlgorithm)?
+    }
+    // the hash defaults to SHA-1, which is not supported
+    let params = algorithm.get(1).ok_or(ErrorKind::UnsupportedAlgorithm)?.children()?;
+    let hash = params.iter().find(|p| p.tag == 0xa0).ok_or(ErrorKind::UnsupportedAlgorithm)?.children()?;
+    let hash = hash.first().ok_or(ErrorKind::MalformedDer)?.children()?;
+    let hash = x509::hash_from_oid(&hash.first().ok_or(ErrorKind::MalformedDer)?.as_oid()?)?;
+    Ok(EncryptionScheme::Oaep(hash))
+}
+
+fn attribute(arcs: &[u64], value: Vec<u8>) -> Vec<u8> {
+    der::sequence(&[der::oid(arcs), der::set(&[value])])
+}
+
+/// Sign `data` as `user` with SHA-256, embedding the signer certificate
+/// A detached signature does not include `data`, which must then be supplied to `verify`
+pub fn sign(rsa: &AlgoRSA<impl KeyStore>, user: &str, data: &[u8], detached: bool) -> Result<Vec<u8>, RSAError> {
+    let cert_der = certificate(rsa, user)?;
+    let cert = Certificate::parse(&cert_der)?;
+    let sha256 = x509::algorithm(x509_oid::SHA256, None);
+
+    let signed_attrs = der::set(&[
+        attribute(oid::CONTENT_TYPE, der::oid(oid::DATA)),
+        attribute(oid::SIGNING_TIME, der::time(&Utc::now())),
+        attribute(oid::MESSAGE_DIGEST, der::octet_string(&HashAlgorithm::Sha256.digest(data))),
+    ]);
+    let signature = rsa.sign(user, SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256), &signed_attrs)?;
+    let signer_info = der::sequence(&[
+        der::small_integer(1),
+        cert.issuer_and_serial(),
+        sha256.clone(),
+        // signedAttrs are [0] IMPLICIT, so the SET tag is replaced
+        der::context(0, true, Der::parse(&signed_attrs)?.content),
+        x509::algorithm(x509_oid::RSA_ENCRYPTION, Some(der::null())),
+        der::octet_string(&signature),
+    ]);
+
+    let mut encap_content_info = vec![der::oid(oid::DATA)];
+    if !detached {
+        encap_content_info.push(der::context(0, true, &der::octet_string(data)));
+    }
+    let signed = der::sequence(&[
+        der::small_integer(1),
+        der::set(&[sha256]),
+        der::sequence(&encap_content_info),
+        der::context(0, true, &cert_der),
+        der::set(&[signer_info]),
+    ]);
+    Ok(content_info(oid::SIGNED_DATA, signed))
+}
+
+/// A SignedData whose signatures all verified against keys in the database
+#[derive(Debug)]
+pub struct Verified {
+    pub signers: Vec<String>,
+    pub content: Vec<u8>,
+}
+
+/// Verify every signer of a SignedData. Each signer's key must be in the database
+/// `detached_content` is required if the content is not encapsulated within the signature, and refused if it is
+pub fn verify(rsa: &AlgoRSA<impl KeyStore>, input: &[u8], detached_content: Option<&[u8]>) -> Result<Verified, RSAError> {
+    let input = der::from_pem("CMS", input)?;
+    let signed = parse_content_info(&input, oid::SIGNED_DATA)?.expect(der::SEQUENCE)?.children()?;
+    if signed.len() < 4 {
+        Err(ErrorKind::MalformedDer)?
+    }
+
+    let encap = signed[2].expect(der::SEQUENCE)?.children()?;
+    if encap.first().ok_or(ErrorKind::MalformedDer)?.as_oid()? != oid::DATA {
+        Err(ErrorKind::UnsupportedAlgorithm)?
+    }
+    let content = match (encap.get(1), detached_content) {
+        // which of the two was signed would be up to us
+        (Some(_), Some(_)) => Err(ErrorKind::ContentTwice)?,
+        (Some(explicit), None) => octets(explicit.expect(0xa0)?.children()?.first().ok_or(ErrorKind::MalformedDer)?)?,
+        (None, Some(content)) => content.to_vec(),
+        (None, None) => Err(ErrorKind::InvalidSignature)?,
+    };
+
+    let certificates: Vec<Certificate> = signed.iter()
+        .find(|d| d.tag == 0xa0)
+        .map(|certs| certs.children())
+        .transpose()?
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|cert| Certificate::parse(cert.raw).ok())
+        .collect();
+    let stored: Vec<Certificate> = rsa.users()?.iter()
+        .filter_map(|user| rsa.certificate(user).ok().and_then(|c| Certificate::parse(&c).ok()))
+        .collect();
+
+    let signer_infos = signed.last().ok_or(ErrorKind::MalformedDer)?.expect(der::SET)?.children()?;
+    if signer_infos.is_empty() {
+        Err(ErrorKind::InvalidSignature)?
+    }
+    let mut signers = Vec::new();
+    for signer_info in signer_infos {
+        let fields = signer_info.expect(der::SEQUENCE)?.children()?;
+        if fields.len() < 5 {
+            Err(ErrorKind::MalformedDer)?
+        }
+        let cert = certificates.iter().chain(stored.iter())
+            .find(|cert| cert.is_identified_by(&fields[1]))
+            .ok_or(ErrorKind::KeyNotFound)?;
+        let user = rsa.find_public(&cert.n)?;
+        let hash = x509::hash_from_oid(&fields[2].children()?.first().ok_or(ErrorKind::MalformedDer)?.as_oid()?)?;
+
+        let (signed_bytes, rest) = if fields[3].tag == 0xa0 {
+            check_signed_attributes(&fields[3], hash, &content)?;
+            let mut attrs = fields[3].raw.to_vec();
+            attrs[0] = der::SET;
+            (attrs, &fields[4..])
+        } else {
+            (content.clone(), &fields[3..])
+        };
+        let signature = rest.get(1).ok_or(ErrorKind::MalformedDer)?.expect(der::OCTET_STRING)?.content;
+        rsa.verify(&user, SignatureScheme::Pkcs1v15(hash), &signed_bytes, signature)?;
+        signers.push(user);
+    }
+    Ok(Verified { signers, content })
+}
+
+fn check_signed_attributes(attrs: &Der, hash: HashAlgorithm, content: &[u8]) -> Result<(), RSAError> {
+    let mut digest_ok = false;
+    let mut content_type_ok = false;
+    for attr in attrs.children()? {
+        let attr = attr.expect(der::SEQUENCE)?.children()?;
+        if attr.len() != 2 {
+            Err(ErrorKind::MalformedDer)?
+        }
+        let values = attr[1].expect(der::SET)?.children()?;
+        let arcs = attr[0].as_oid()?;
+        if arcs == oid::MESSAGE_DIGEST {
+            digest_ok = values.len() == 1 && values[0].expect(der::OCTET_STRING)?.content == hash.digest(content).as_slice();
+        } else if arcs == oid::CONTENT_TYPE {
+            content_type_ok = values.len() == 1 && values[0].as_oid()? == oid::DATA;
+        }
+    }
+    if !(digest_ok && content_type_ok) {
+        Err(ErrorKind::InvalidSignature)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::MemoryStore;
+    use crate::primes::KeySize;
+
+    #[test]
+    fn should_envelope_and_sign() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("dave", &KeySize::TenTwentyFour).unwrap();
+        rsa.create("erin", &KeySize::TenTwentyFour).unwrap();
+        certificate(&rsa, "dave").unwrap();
+        certificate(&rsa, "erin").unwrap();
+
+        let enveloped = encrypt(&rsa, &["erin".to_string()], b"for erin only").unwrap();
+        let (user, plaintext) = decrypt(&rsa, &enveloped).unwrap();
+        assert_eq!(user, "erin");
+        assert_eq!(plaintext, b"for erin only");
+
+        let attached = sign(&rsa, "dave", b"signed by dave", false).unwrap();
+        let verified = verify(&rsa, &attached, None).unwrap();
+        assert_eq!(verified.signers, vec!["dave".to_string()]);
+        assert_eq!(verified.content, b"signed by dave");
+
+        let detached = sign(&rsa, "dave", b"signed by dave", true).unwrap();
+        assert!(verify(&rsa, &detached, None).is_err());
+        assert!(verify(&rsa, &detached, Some(b"signed by dave")).is_ok());
+        assert!(verify(&rsa, &detached, Some(b"signed by mallory")).is_err());
+        assert_eq!(verify(&rsa, &attached, Some(b"signed by dave")).unwrap_err().kind(), ErrorKind::ContentTwice);
+    }
+}
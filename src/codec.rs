@@ -0,0 +1,127 @@
+//! How `SimpleDB` turns values into bytes
+//! Every encoding is wrapped in an envelope naming the codec and the layout version of the value, so that
+//! older layouts can be migrated. Databases written before there was a choice of codec are headerless JSON,
+//! which can never start with the envelope, so they still load
+use crate::err::{ErrorKind, RSAError};
+use serde::{Serialize, de::DeserializeOwned};
+use failure::ResultExt;
+use std::str::FromStr;
+
+/// The envelope: magic, codec tag, layout version as a big-endian u32
+const MAGIC: &[u8] = b"RSAV";
+/// Written before layouts were versioned: magic and codec tag only, always layout 0
+const UNVERSIONED_MAGIC: &[u8] = b"RSAD";
+
+/// A serialization format for the database
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Readable, but slow and large for big numbers, which become arrays of digits
+    Json,
+    /// Compact and the fastest to load, but tied to the exact layout of the stored types
+    Bincode,
+    /// Compact, and keeps field names so that fields can be added later
+    #[default]
+    MessagePack,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::Json => b'J',
+            Codec::Bincode => b'B',
+            Codec::MessagePack => b'M',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, RSAError> {
+        [Codec::Json, Codec::Bincode, Codec::MessagePack].iter()
+            .find(|c| c.tag() == tag)
+            .copied()
+            .ok_or_else(|| ErrorKind::Database.into())
+    }
+
+    /// `value`, in layout `version`, encoded with this codec inside the envelope
+    pub fn encode<D: Serialize>(self, value: &D, version: u32) -> Result<Vec<u8>, RSAError> {
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(self.tag());
+        encoded.extend_from_slice(&version.to_be_bytes());
+        match self {
+            Codec::Json => serde_json::to_writer(&mut encoded, value).context(ErrorKind::Database)?,
+            Codec::Bincode => bincode::serialize_into(&mut encoded, value).context(ErrorKind::Database)?,
+            Codec::MessagePack => rmp_serde::encode::write_named(&mut encoded, value).context(ErrorKind::Database)?,
+        }
+        Ok(encoded)
+    }
+
+    /// Open the envelope of `data`: the codec, the layout version and the encoded value
+    pub fn unwrap(data: &[u8]) -> Result<(Codec, u32, &[u8]), RSAError> {
+        if let Some(rest) = data.strip_prefix(MAGIC) {
+            if rest.len() < 5 {
+                Err(ErrorKind::Database)?
+            }
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&rest[1..5]);
+            Ok((Codec::from_tag(rest[0])?, u32::from_be_bytes(version), &rest[5..]))
+        } else if let Some(rest) = data.strip_prefix(UNVERSIONED_MAGIC) {
+            let (tag, payload) = rest.split_first().ok_or(ErrorKind::Database)?;
+            Ok((Codec::from_tag(*tag)?, 0, payload))
+        } else {
+            Ok((Codec::Json, 0, data))
+        }
+    }
+
+    /// Decode a value taken out of the envelope by `unwrap`
+    pub fn decode<D: DeserializeOwned>(self, payload: &[u8]) -> Result<D, RSAError> {
+        let value = match self {
+            Codec::Json => serde_json::from_slice(payload).context(ErrorKind::Database)?,
+            Codec::Bincode => bincode::deserialize(payload).context(ErrorKind::Database)?,
+            Codec::MessagePack => rmp_serde::from_slice(payload).context(ErrorKind::Database)?,
+        };
+        Ok(value)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = RSAError;
+
+    fn from_str(s: &str) -> Result<Self, RSAError> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+            _ => Err(ErrorKind::Encoding)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rsa::RSA;
+    use crate::primes::KeySize;
+    use num_bigint::BigUint;
+    use std::collections::HashMap;
+
+    #[test]
+    fn should_roundtrip_every_codec() {
+        let mut keys = HashMap::new();
+        keys.insert("alice".to_string(), RSA::new(BigUint::from(3233u32), Some(BigUint::from(413u32)), KeySize::FiveTwelve));
+        for codec in &[Codec::Json, Codec::Bincode, Codec::MessagePack] {
+            let encoded = codec.encode(&keys, 7).unwrap();
+            let (found, version, payload) = Codec::unwrap(&encoded).unwrap();
+            assert_eq!((found, version), (*codec, 7));
+            assert_eq!(found.decode::<HashMap<String, RSA>>(payload).unwrap(), keys);
+        }
+    }
+
+    #[test]
+    fn should_unwrap_older_envelopes() {
+        let (codec, version, payload) = Codec::unwrap(br#"{"a":1}"#).unwrap();
+        assert_eq!((codec, version), (Codec::Json, 0));
+        assert_eq!(codec.decode::<HashMap<String, u32>>(payload).unwrap()["a"], 1);
+        let (codec, version, _) = Codec::unwrap(b"RSADM\x81").unwrap();
+        assert_eq!((codec, version), (Codec::MessagePack, 0));
+        assert!(Codec::unwrap(b"RSADX{}").is_err());
+        assert!(Codec::unwrap(b"RSAVJ\0").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Just enough DER (ASN.1 Distinguished Encoding Rules) to read and write the structures of
+//! X.509 and CMS. Encoders return owned byte vectors that can be nested freely; the decoder
+//! borrows from its input so that signed portions can be hashed exactly as they were received
+use crate::err::{ErrorKind, RSAError};
+use num_bigint::BigUint;
+use chrono::{DateTime, Datelike, Utc};
+
+pub const INTEGER: u8 = 0x02;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OID: u8 = 0x06;
+pub const UTF8_STRING: u8 = 0x0c;
+pub const UTC_TIME: u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+
+/// Encode a complete Tag-Length-Value
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().cloned().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &items.concat())
+}
+
+/// SET OF; DER requires the elements to be sorted by their encoding
+pub fn set(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut items = items.to_vec();
+    items.sort();
+    tlv(SET, &items.concat())
+}
+
+/// Context-specific tag `[n]`. Constructed for EXPLICIT tagging or IMPLICIT tagging of a constructed type
+pub fn context(n: u8, constructed: bool, content: &[u8]) -> Vec<u8> {
+    let tag = if constructed { 0xa0 | n } else { 0x80 | n };
+    tlv(tag, content)
+}
+
+pub fn integer(num: &BigUint) -> Vec<u8> {
+    let mut bytes = num.to_bytes_be();
+    if bytes[0] & 0x80 != 0 {
+        bytes.insert(0, 0);
+    }
+    tlv(INTEGER, &bytes)
+}
+
+pub fn small_integer(num: u64) -> Vec<u8> {
+    integer(&BigUint::from(num))
+}
+
+pub fn null() -> Vec<u8> {
+    tlv(NULL, &[])
+}
+
+pub fn octet_string(data: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, data)
+}
+
+/// BIT STRING with no unused bits
+pub fn bit_string(data: &[u8]) -> Vec<u8> {
+    let mut content = vec![0];
+    content.extend_from_slice(data);
+    tlv(BIT_STRING, &content)
+}
+
+pub fn utf8_string(s: &str) -> Vec<u8> {
+    tlv(UTF8_STRING, s.as_bytes())
+}
+
+pub fn oid(arcs: &[u64]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut encode_arc = |mut arc: u64| {
+        let mut base128 = vec![(arc & 0x7f) as u8];
+        arc >>= 7;
+        while arc > 0 {
+            base128.push(0x80 | (arc & 0x7f) as u8);
+            arc >>= 7;
+        }
+        base128.reverse();
+        content.extend(base128);
+    };
+    encode_arc(arcs[0] * 40 + arcs[1]);
+    arcs[2..].iter().for_each(|arc| encode_arc(*arc));
+    tlv(OID, &content)
+}
+
+/// UTCTime for years before 2050, GeneralizedTime after, as RFC 5280 requires
+pub fn time(time: &DateTime<Utc>) -> Vec<u8> {
+    if time.year() < 2050 {
+        tlv(UTC_TIME, time.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    } else {
+        tlv(GENERALIZED_TIME, time.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+}
+
+/// A decoded element, borrowing from the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Der<'a> {
+    pub tag: u8,
+    /// the value, without tag and length
+    pub content: &'a [u8],
+    /// the complete encoding, including tag and length
+    pub raw: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    /// Parse a single element which must span all of `input`
+    pub fn parse(input: &'a [u8]) -> Result<Self, RSAError> {
+        let (der, rest) = Self::parse_partial(input)?;
+        if !rest.is_empty() {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok(der)
+    }
+
+    /// Parse the first element in `input`, returning the remainder
+    pub fn parse_partial(input: &'a [u8]) -> Result<(Self, &'a [u8]), RSAError> {
+        if input.len() < 2 || input[0] & 0x1f == 0x1f {
+            Err(ErrorKind::MalformedDer)?
+        }
+        let (len, header) = match input[1] {
+            len @ 0..=0x7f => (len as usize, 2),
+            0x80 => Err(ErrorKind::MalformedDer)?, // indefinite lengths are BER, not DER
+            len_len => {
+                let len_len = (len_len & 0x7f) as usize;
+                if len_len > std::mem::size_of::<usize>() || input.len() < 2 + len_len {
+                    Err(ErrorKind::MalformedDer)?
+                }
+                let len = input[2..2 + len_len].iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+                (len, 2 + len_len)
+            }
+        };
+        let end = header.checked_add(len).ok_or(ErrorKind::MalformedDer)?;
+        if input.len() < end {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok((Der { tag: input[0], content: &input[header..end], raw: &input[..end] }, &input[end..]))
+    }
+
+    /// Fail unless this element has the tag `tag`
+    pub fn expect(self, tag: u8) -> Result<Self, RSAError> {
+        if self.tag != tag {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok(self)
+    }
+
+    /// The elements inside a constructed type (SEQUENCE, SET, explicit tags)
+    pub fn children(&self) -> Result<Vec<Der<'a>>, RSAError> {
+        let mut children = Vec::new();
+        let mut rest = self.content;
+        while !rest.is_empty() {
+            let (child, next) = Self::parse_partial(rest)?;
+            children.push(child);
+            rest = next;
+        }
+        Ok(children)
+    }
+
+    pub fn as_biguint(&self) -> Result<BigUint, RSAError> {
+        let der = self.expect(INTEGER)?;
+        if der.content.is_empty() || der.content[0] & 0x80 != 0 {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok(BigUint::from_bytes_be(der.content))
+    }
+
+    pub fn as_oid(&self) -> Result<Vec<u64>, RSAError> {
+        let der = self.expect(OID)?;
+        let mut arcs = Vec::new();
+        let mut arc: u64 = 0;
+        for byte in der.content {
+            if arc > (u64::MAX >> 7) {
+                Err(ErrorKind::MalformedDer)?
+            }
+            arc = (arc << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                if arcs.is_empty() {
+                    let first = std::cmp::min(arc / 40, 2);
+                    arcs.push(first);
+                    arcs.push(arc - first * 40);
+                } else {
+                    arcs.push(arc);
+                }
+                arc = 0;
+            }
+        }
+        if arcs.is_empty() {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok(arcs)
+    }
+
+    /// Content of a BIT STRING with no unused bits
+    pub fn as_bit_string(&self) -> Result<&'a [u8], RSAError> {
+        let der = self.expect(BIT_STRING)?;
+        if der.content.first() != Some(&0) {
+            Err(ErrorKind::MalformedDer)?
+        }
+        Ok(&der.content[1..])
+    }
+}
+
+/// Wrap DER in PEM armor with the given label
+pub fn to_pem(label: &str, der: &[u8]) -> String {
+    let body = base64::encode(der);
+    let lines: Vec<&str> = body.as_bytes().chunks(64).map(|c| std::str::from_utf8(c).unwrap_or_default()).collect();
+    format!("-----BEGIN {}-----\n{}\n-----END {}-----\n", label, lines.join("\n"), label)
+}
+
+/// Extract the DER from PEM armor with the given label. Input that is not PEM is returned as-is
+pub fn from_pem(label: &str, input: &[u8]) -> Result<Vec<u8>, RSAError> {
+    let text = match std::str::from_utf8(input) {
+        Ok(text) if text.contains("-----BEGIN ") => text,
+        _ => return Ok(input.to_vec()),
+    };
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin).ok_or(ErrorKind::MalformedDer)? + begin.len();
+    let stop = text[start..].find(&end).ok_or(ErrorKind::MalformedDer)? + start;
+    let body: String = text[start..stop].chars().filter(|c| !c.is_whitespace()).collect();
+    Ok(base64::decode(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_oids() {
+        let sha256 = oid(&[2, 16, 840, 1, 101, 3, 4, 2, 1]);
+        assert_eq!(sha256, vec![0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]);
+        assert_eq!(Der::parse(&sha256).unwrap().as_oid().unwrap(), vec![2, 16, 840, 1, 101, 3, 4, 2, 1]);
+    }
+
+    #[test]
+    fn should_roundtrip_long_lengths() {
+        let content = vec![7u8; 300];
+        let encoded = sequence(&[octet_string(&content), integer(&BigUint::from(0x80usize))]);
+        assert_eq!(&encoded[..4], &[0x30, 0x82, 0x01, 0x34]);
+        let children = Der::parse(&encoded).unwrap().children().unwrap();
+        assert_eq!(children[0].content, content.as_slice());
+        assert_eq!(children[1].content, &[0x00, 0x80]);
+        assert_eq!(children[1].as_biguint().unwrap(), BigUint::from(0x80usize));
+    }
+
+    #[test]
+    fn should_reject_truncated_input() {
+        let encoded = octet_string(&[1, 2, 3]);
+        assert!(Der::parse(&encoded[..4]).is_err());
+        assert!(Der::parse(&[0x30, 0x80, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn should_roundtrip_pem() {
+        let der = sequence(&[null()]);
+        assert_eq!(from_pem("CMS", to_pem("CMS", &der).as_bytes()).unwrap(), der);
+        assert_eq!(from_pem("CMS", &der).unwrap(), der);
+    }
+}
@@ -1,8 +1,12 @@
+//! Errors returned by the library and the CLI
+#![allow(non_local_definitions)] // emitted by the `Fail` derive
 use failure::{Context, Fail, Backtrace};
 use std::fmt::Display;
 
+/// The error returned by every fallible function in this crate
+/// Match on `kind()`; the underlying error, if any, is available through `Fail::cause`
 #[derive(Debug)]
-struct RSAError {
+pub struct RSAError {
     inner: Context<ErrorKind>
 }
 
@@ -27,12 +31,132 @@ pub enum ErrorKind {
     #[fail(display = "Must specify a user")]
     NoUserSpecified,
     #[fail(display = "Failed to parse a file exported by this program.")]
-    RegexParse
+    RegexParse,
+    #[fail(display = "The key is too small for the chosen signature scheme")]
+    KeyTooSmall,
+    #[fail(display = "Signature verification failed")]
+    InvalidSignature,
+    #[fail(display = "No key in the database matches the requested key id")]
+    KeyNotFound,
+    #[fail(display = "Malformed token")]
+    MalformedToken,
+    #[fail(display = "Unsupported signature algorithm")]
+    UnsupportedAlgorithm,
+    #[fail(display = "Token has expired")]
+    TokenExpired,
+    #[fail(display = "Token is not valid yet")]
+    TokenNotYetValid,
+    #[fail(display = "Decryption failed")]
+    Decryption,
+    #[fail(display = "Malformed DER/ASN.1 data")]
+    MalformedDer,
+    #[fail(display = "No certificate for this user. Generate or import one first")]
+    CertificateNotFound,
+    #[fail(display = "The certificate does not match the key in the database")]
+    CertificateMismatch,
+    #[fail(display = "Only RSA keys with public exponent 65537 are supported")]
+    UnsupportedKey,
+    #[fail(display = "None of the recipients have a private key in the database")]
+    RecipientNotFound,
+    #[fail(display = "Malformed signature")]
+    MalformedSignature,
+    #[fail(display = "The message is too long for the key and padding scheme")]
+    MessageTooLong,
+    #[fail(display = "Malformed input. Expected base64, UTF-8 or JSON")]
+    Encoding,
+    #[fail(display = "Could not read or write a file")]
+    Io,
+    #[fail(display = "The number has no modular inverse")]
+    NoModularInverse,
+    #[fail(display = "The database was changed by another process in a way that conflicts with ours. Nothing was saved")]
+    DatabaseChanged,
+    #[fail(display = "The database is encrypted. Set RSA_PASSPHRASE, pass --passphrase-fd or run from a terminal")]
+    PassphraseRequired,
+    #[fail(display = "Wrong passphrase, or the encrypted database is corrupt")]
+    WrongPassphrase,
+    #[fail(display = "The passphrases do not match")]
+    PassphraseMismatch,
+    #[fail(display = "The database was written by a newer version of this program")]
+    DatabaseTooNew,
+    #[fail(display = "The key has expired")]
+    KeyExpired,
+    #[fail(display = "The key is not allowed to be used for this. Its usages are shown by `rsa list`")]
+    KeyUsageNotAllowed,
+    #[fail(display = "Unknown key usage. Use encrypt, sign or certify")]
+    UnknownUsage,
+    #[fail(display = "Dates must be written as YYYY-MM-DD")]
+    InvalidDate,
+    #[fail(display = "The key has been revoked")]
+    KeyRevoked,
+    #[fail(display = "The key has not been revoked")]
+    RevocationNotFound,
+    #[fail(display = "Unknown revocation reason. Use compromised, superseded, retired or unspecified")]
+    UnknownReason,
+    #[fail(display = "Some files could not be re-encrypted")]
+    ArchiveIncomplete,
+    #[fail(display = "A user with that name already exists")]
+    UserExists,
+    #[fail(display = "Cancelled, nothing was changed")]
+    Cancelled,
+    #[fail(display = "Keys of several users match that key id. Give more of the fingerprint")]
+    AmbiguousKeyId,
+    #[fail(display = "Unknown trust level. Use unknown, marginal or full, or verify-contact to verify a key")]
+    UnknownTrust,
+    #[fail(display = "The fingerprint does not match the primary key")]
+    FingerprintMismatch,
+    #[fail(display = "allowed_signers does not allow the key that made the signature for this principal and namespace")]
+    PrincipalNotAllowed,
+    #[fail(display = "Give one command at a time. Deprecated flags cannot be combined with each other or with a subcommand")]
+    ConflictingCommands,
+    #[fail(display = "A required value is missing, and stdin is not a terminal to ask for it. Pass it on the command line")]
+    MissingArgument,
+    #[fail(display = "Only one input can be read from stdin")]
+    StdinTwice,
+    #[fail(display = "The output file exists. Pass --force to replace it")]
+    OutputExists,
+    #[fail(display = "The passphrase file descriptor is not open")]
+    BadFileDescriptor,
+    #[fail(display = "This command writes nothing that --file could take")]
+    OutputNotSupported,
+    #[fail(display = "Only the primary key of a user signs. Make the key primary with `edit --primary` first")]
+    NotPrimaryKey,
+    #[fail(display = "The signature already holds the signed content. Leave out --content")]
+    ContentTwice,
+}
+
+impl ErrorKind {
+    /// The exit code of the CLI for this kind of error. 1 is left for errors that are not an `ErrorKind`
+    ///
+    /// | code | meaning |
+    /// |------|---------|
+    /// | 2 | invalid arguments or user input |
+    /// | 3 | a user, key or certificate is not in the database |
+    /// | 4 | a signature, token or certificate did not verify |
+    /// | 5 | decryption failed |
+    /// | 6 | malformed or unsupported input |
+    /// | 7 | the database or another file could not be read or written |
+    /// | 8 | key generation failed |
+    pub fn exit_code(self) -> i32 {
+        use ErrorKind::*;
+        match self {
+            InvalidKeyLength | WrongNumber | NoUserSpecified | ImportOrder | KeyTooSmall | MessageTooLong | PassphraseRequired | PassphraseMismatch
+                | KeyExpired | KeyUsageNotAllowed | UnknownUsage | InvalidDate | UnknownReason
+                | UserExists | Cancelled | AmbiguousKeyId | UnknownTrust | ConflictingCommands | MissingArgument | StdinTwice | OutputExists
+                | BadFileDescriptor | OutputNotSupported | NotPrimaryKey | ContentTwice => 2,
+            UserNotFound | PrivateKeyNotFound | KeyNotFound | CertificateNotFound | RecipientNotFound | RevocationNotFound => 3,
+            InvalidSignature | TokenExpired | TokenNotYetValid | CertificateMismatch | KeyRevoked | FingerprintMismatch
+                | PrincipalNotAllowed => 4,
+            Decryption | WrongPassphrase | ArchiveIncomplete => 5,
+            RegexParse | MalformedToken | MalformedDer | MalformedSignature | UnsupportedAlgorithm | UnsupportedKey | Encoding => 6,
+            Database | Io | DatabaseChanged | DatabaseTooNew => 7,
+            PrimeNotFound | BigNumConversion | NoModularInverse => 8,
+        }
+    }
 }
 
 
 impl Fail for RSAError {
-    fn cause(&self) -> Option<&Fail> {
+    fn cause(&self) -> Option<&dyn Fail> {
         self.inner.cause()
     }
     fn backtrace(&self) -> Option<&Backtrace> {
@@ -47,6 +171,7 @@ impl Display for RSAError {
 }
 
 impl RSAError {
+    /// What went wrong
     pub fn kind(&self) -> ErrorKind {
         *self.inner.get_context()
     }
@@ -60,6 +185,53 @@ impl From<ErrorKind> for RSAError {
 
 impl From<Context<ErrorKind>> for RSAError {
     fn from(inner: Context<ErrorKind>) -> RSAError {
-        RSAError { inner: inner }
+        RSAError { inner }
+    }
+}
+
+/// Errors from other crates keep their cause, under the closest `ErrorKind`
+macro_rules! from_error {
+    ($($error:ty => $kind:ident),* $(,)?) => {$(
+        impl From<$error> for RSAError {
+            fn from(e: $error) -> RSAError {
+                RSAError { inner: e.context(ErrorKind::$kind) }
+            }
+        }
+    )*}
+}
+
+from_error! {
+    std::io::Error => Io,
+    std::string::FromUtf8Error => Encoding,
+    std::str::Utf8Error => Encoding,
+    base64::DecodeError => Encoding,
+    serde_json::Error => Encoding,
+    bincode::Error => Encoding,
+    std::num::ParseIntError => WrongNumber,
+    regex::Error => RegexParse,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<u32, RSAError> {
+        Ok(input.parse()?)
+    }
+
+    #[test]
+    fn should_keep_kind_and_cause() {
+        let e = parse("twelve").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WrongNumber);
+        assert!(e.cause().is_some());
+        assert_eq!(RSAError::from(ErrorKind::UserNotFound).kind(), ErrorKind::UserNotFound);
+    }
+
+    #[test]
+    fn should_map_kinds_to_exit_codes() {
+        assert_eq!(ErrorKind::UserNotFound.exit_code(), ErrorKind::PrivateKeyNotFound.exit_code());
+        assert_ne!(ErrorKind::UserNotFound.exit_code(), ErrorKind::Database.exit_code());
+        assert_ne!(ErrorKind::InvalidSignature.exit_code(), ErrorKind::Decryption.exit_code());
+        assert_eq!(ErrorKind::PrincipalNotAllowed.exit_code(), ErrorKind::InvalidSignature.exit_code());
     }
 }
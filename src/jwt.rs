@@ -0,0 +1,214 @@
+//! Compact JWS/JWT (RFC 7515, RFC 7519) signed with keys from the database
+//! The `kid` header carries the key identifier shown by `rsa list`, so a token can be verified
+//! against whichever key in the keyring signed it
+use crate::rsa::{AlgoRSA, HashAlgorithm, SignatureScheme};
+use crate::keystore::KeyStore;
+use crate::err::{ErrorKind, RSAError};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use chrono::Utc;
+use failure::ResultExt;
+
+/// JWA algorithms (RFC 7518 Section 3.1) supported for signing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    RS256,
+    RS384,
+    RS512,
+    PS256,
+}
+
+impl Algorithm {
+    pub fn scheme(self) -> SignatureScheme {
+        match self {
+            Algorithm::RS256 => SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256),
+            Algorithm::RS384 => SignatureScheme::Pkcs1v15(HashAlgorithm::Sha384),
+            Algorithm::RS512 => SignatureScheme::Pkcs1v15(HashAlgorithm::Sha512),
+            Algorithm::PS256 => SignatureScheme::Pss(HashAlgorithm::Sha256),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::RS256 => "RS256",
+            Algorithm::RS384 => "RS384",
+            Algorithm::RS512 => "RS512",
+            Algorithm::PS256 => "PS256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, RSAError> {
+        Ok(match name {
+            "RS256" => Algorithm::RS256,
+            "RS384" => Algorithm::RS384,
+            "RS512" => Algorithm::RS512,
+            "PS256" => Algorithm::PS256,
+            _ => Err(ErrorKind::UnsupportedAlgorithm)?
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Header {
+    pub alg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+/// A token that passed signature and time validation
+#[derive(Debug)]
+pub struct Verified {
+    /// the user in the database whose key signed the token
+    pub user: String,
+    pub header: Header,
+    pub claims: Map<String, Value>,
+}
+
+fn encode_segment(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, RSAError> {
+    Ok(base64::decode_config(segment, base64::URL_SAFE_NO_PAD).context(ErrorKind::MalformedToken)?)
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp().max(0) as u64
+}
+
+/// Sign `claims` with the key of `user`, producing a compact JWS `header.payload.signature`
+pub fn sign(rsa: &AlgoRSA<impl KeyStore>, user: &str, alg: Algorithm, claims: &Map<String, Value>) -> Result<String, RSAError> {
+    let header = Header {
+        alg: alg.as_str().into(),
+        typ: Some("JWT".into()),
+        kid: Some(rsa.key_id(user)?),
+    };
+    let signing_input = format!("{}.{}",
+                                encode_segment(&serde_json::to_vec(&header)?),
+                                encode_segment(&serde_json::to_vec(claims)?));
+    let signature = rsa.sign(user, alg.scheme(), signing_input.as_bytes())?;
+    Ok(format!("{}.{}", signing_input, encode_segment(&signature)))
+}
+
+/// Verify a compact JWS and validate its `exp` and `nbf` claims, allowing `leeway` seconds of clock skew
+/// If `user` is given, the token must have been signed by that user's key; otherwise the key is looked up by `kid`.
+/// Either way, a token with a `kid` must have been signed by exactly the key it names
+pub fn verify(rsa: &AlgoRSA<impl KeyStore>, token: &str, user: Option<&str>, leeway: u64) -> Result<Verified, RSAError> {
+    let token = token.trim();
+    let mut segments = token.rsplitn(2, '.');
+    let signature = decode_segment(segments.next().ok_or(ErrorKind::MalformedToken)?)?;
+    let signing_input = segments.next().ok_or(ErrorKind::MalformedToken)?;
+    let mut parts = signing_input.splitn(2, '.');
+    let header = decode_segment(parts.next().ok_or(ErrorKind::MalformedToken)?)?;
+    let payload = decode_segment(parts.next().ok_or(ErrorKind::MalformedToken)?)?;
+
+    let header: Header = serde_json::from_slice(&header).context(ErrorKind::MalformedToken)?;
+    let alg = Algorithm::from_name(&header.alg)?;
+
+    let signer = match (user, header.kid.as_ref()) {
+        (Some(user), Some(kid)) => {
+            rsa.keys(user)?.get(kid).ok_or(ErrorKind::KeyNotFound)?;
+            user.to_string()
+        },
+        (Some(user), None) => user.to_string(),
+        (None, Some(kid)) => rsa.find_key_id(kid)?,
+        (None, None) => Err(ErrorKind::KeyNotFound)?,
+    };
+    let claims: Map<String, Value> = serde_json::from_slice(&payload).context(ErrorKind::MalformedToken)?;
+    let issued_at = claims.get("iat").and_then(Value::as_u64);
+    // the key that `kid` names, not just any key of the signer
+    rsa.verify_key_at(&signer, header.kid.as_deref(), alg.scheme(), signing_input.as_bytes(), &signature, issued_at)?;
+    validate_time(&claims, now(), leeway)?;
+
+    Ok(Verified { user: signer, header, claims })
+}
+
+fn validate_time(claims: &Map<String, Value>, now: u64, leeway: u64) -> Result<(), RSAError> {
+    if let Some(exp) = claims.get("exp") {
+        let exp = exp.as_u64().ok_or(ErrorKind::MalformedToken)?;
+        if now >= exp.saturating_add(leeway) {
+            Err(ErrorKind::TokenExpired)?
+        }
+    }
+    if let Some(nbf) = claims.get("nbf") {
+        let nbf = nbf.as_u64().ok_or(ErrorKind::MalformedToken)?;
+        if now.saturating_add(leeway) < nbf {
+            Err(ErrorKind::TokenNotYetValid)?
+        }
+    }
+    Ok(())
+}
+
+/// Adds `iat`, and `exp` if `expires_in` is given, to a set of claims
+pub fn with_times(mut claims: Map<String, Value>, expires_in: Option<u64>) -> Map<String, Value> {
+    let now = now();
+    claims.entry("iat").or_insert_with(|| now.into());
+    if let Some(expires_in) = expires_in {
+        claims.insert("exp".into(), now.saturating_add(expires_in).into());
+    }
+    claims
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::MemoryStore;
+    use crate::primes::KeySize;
+
+    fn claims(json: &str) -> Map<String, Value> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn should_validate_time_claims() {
+        assert!(validate_time(&claims(r#"{"exp": 100}"#), 99, 0).is_ok());
+        assert!(validate_time(&claims(r#"{"exp": 100}"#), 100, 0).is_err());
+        assert!(validate_time(&claims(r#"{"exp": 100}"#), 100, 5).is_ok());
+        assert!(validate_time(&claims(r#"{"nbf": 100}"#), 99, 0).is_err());
+        assert!(validate_time(&claims(r#"{"nbf": 100}"#), 99, 1).is_ok());
+        assert!(validate_time(&claims(r#"{"exp": "soon"}"#), 99, 0).is_err());
+    }
+
+    #[test]
+    fn should_sign_and_verify_tokens() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("alice", &KeySize::TenTwentyFour).unwrap();
+        rsa.create("bob", &KeySize::TenTwentyFour).unwrap();
+
+        for alg in &[Algorithm::RS256, Algorithm::RS384, Algorithm::RS512, Algorithm::PS256] {
+            let token = sign(&rsa, "alice", *alg, &claims(r#"{"sub": "service"}"#)).unwrap();
+            let verified = verify(&rsa, &token, None, 0).unwrap();
+            assert_eq!(verified.user, "alice");
+            assert_eq!(verified.claims["sub"], "service");
+            assert!(verify(&rsa, &token, Some("bob"), 0).is_err());
+        }
+
+        let token = sign(&rsa, "alice", Algorithm::RS256, &claims(r#"{"exp": 1}"#)).unwrap();
+        assert!(verify(&rsa, &token, None, 0).is_err());
+
+        let token = sign(&rsa, "alice", Algorithm::RS256, &claims(r#"{"admin": false}"#)).unwrap();
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = encode_segment(br#"{"admin":true}"#);
+        parts[1] = &forged;
+        assert!(verify(&rsa, &parts.join("."), None, 0).is_err());
+    }
+
+    #[test]
+    fn should_verify_only_with_the_key_that_kid_names() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("alice", &KeySize::FiveTwelve).unwrap();
+        let old = rsa.key_id("alice").unwrap();
+        rsa.rotate("alice").unwrap();
+        assert!(verify(&rsa, &sign(&rsa, "alice", Algorithm::RS256, &claims("{}")).unwrap(), Some("alice"), 0).is_ok());
+
+        // signed by the new key of alice, but naming the old one
+        let header = Header { alg: "RS256".into(), typ: Some("JWT".into()), kid: Some(old) };
+        let signing_input = format!("{}.{}", encode_segment(&serde_json::to_vec(&header).unwrap()), encode_segment(b"{}"));
+        let signature = rsa.sign("alice", Algorithm::RS256.scheme(), signing_input.as_bytes()).unwrap();
+        let token = format!("{}.{}", signing_input, encode_segment(&signature));
+        assert_eq!(verify(&rsa, &token, Some("alice"), 0).unwrap_err().kind(), ErrorKind::InvalidSignature);
+        assert_eq!(verify(&rsa, &token, None, 0).unwrap_err().kind(), ErrorKind::InvalidSignature);
+    }
+}
@@ -0,0 +1,610 @@
+//! Where the keyring keeps its keys
+//! `AlgoRSA` only talks to a `KeyStore`, so keys can live in memory, in a single database file, or in a
+//! directory with one file per key
+use crate::rsa::{UserKeys, KeyV1, UserKeysV2, UserKeysV3, UserKeysV4, UserKeysV5, UserKeysV6};
+use crate::simpledb::{SimpleDB, Schema};
+use crate::seal::{Passphrase, SealingKey};
+use crate::codec::Codec;
+use crate::err::{ErrorKind, RSAError};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use failure::ResultExt;
+
+/// Layout 1 put the keyring in a versioned envelope; the keys themselves are laid out as in layout 0.
+/// Layout 2 gave every user a list of keys, layout 3 gave keys metadata, layout 4 revocations and layout 5
+/// ownership and trust. Layout 6 added certifications, and layout 7 took verification out of the trust levels
+impl Schema for HashMap<String, UserKeys> {
+    const VERSION: u32 = 7;
+
+    fn migrate(version: u32, codec: Codec, payload: &[u8]) -> Result<Self, RSAError> {
+        match version {
+            0 | 1 => {
+                let keys: HashMap<String, KeyV1> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, key)| (user, UserKeys::new(key.into()))).collect())
+            },
+            2 => {
+                let keys: HashMap<String, UserKeysV2> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, keys)| (user, keys.into())).collect())
+            },
+            3 => {
+                let keys: HashMap<String, UserKeysV3> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, keys)| (user, keys.into())).collect())
+            },
+            4 => {
+                let keys: HashMap<String, UserKeysV4> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, keys)| (user, keys.into())).collect())
+            },
+            5 => {
+                let keys: HashMap<String, UserKeysV5> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, keys)| (user, keys.into())).collect())
+            },
+            6 => {
+                let keys: HashMap<String, UserKeysV6> = codec.decode(payload)?;
+                Ok(keys.into_iter().map(|(user, keys)| (user, keys.into())).collect())
+            },
+            _ => Err(ErrorKind::Database)?,
+        }
+    }
+}
+
+/// The file of a single user in a `DirectoryStore`, versioned like the keyring
+impl Schema for Option<UserKeys> {
+    const VERSION: u32 = 7;
+
+    fn migrate(version: u32, codec: Codec, payload: &[u8]) -> Result<Self, RSAError> {
+        match version {
+            0 | 1 => Ok(codec.decode::<Option<KeyV1>>(payload)?.map(|key| UserKeys::new(key.into()))),
+            2 => Ok(codec.decode::<Option<UserKeysV2>>(payload)?.map(UserKeys::from)),
+            3 => Ok(codec.decode::<Option<UserKeysV3>>(payload)?.map(UserKeys::from)),
+            4 => Ok(codec.decode::<Option<UserKeysV4>>(payload)?.map(UserKeys::from)),
+            5 => Ok(codec.decode::<Option<UserKeysV5>>(payload)?.map(UserKeys::from)),
+            6 => Ok(codec.decode::<Option<UserKeysV6>>(payload)?.map(UserKeys::from)),
+            _ => Err(ErrorKind::Database)?,
+        }
+    }
+}
+
+/// Storage for the keys of each user
+pub trait KeyStore {
+    /// The keys of `user`, if there are any
+    fn get(&self, user: &str) -> Result<Option<UserKeys>, RSAError>;
+
+    /// Add or replace the keys of `user`
+    fn put(&mut self, user: &str, keys: UserKeys) -> Result<(), RSAError>;
+
+    /// Remove the keys of `user`, returning them if they existed
+    fn delete(&mut self, user: &str) -> Result<Option<UserKeys>, RSAError>;
+
+    /// All users with a key, in no particular order
+    fn list(&self) -> Result<Vec<String>, RSAError>;
+
+    /// Where the keys are kept, for display
+    fn location(&self) -> String;
+
+    /// Write out changes that are buffered in memory. Stores that write through on every `put` need not override this
+    fn flush(&mut self) -> Result<(), RSAError> {
+        Ok(())
+    }
+
+    /// Every user and their keys, sorted by user
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (String, UserKeys)>>, RSAError> {
+        let mut users = self.list()?;
+        users.sort();
+        let mut keys = Vec::with_capacity(users.len());
+        for user in users {
+            if let Some(key) = self.get(&user)? {
+                keys.push((user, key));
+            }
+        }
+        Ok(Box::new(keys.into_iter()))
+    }
+}
+
+/// Keys that are never written anywhere. Meant for tests and throwaway keyrings
+#[derive(Debug, Default)]
+pub struct MemoryStore(HashMap<String, UserKeys>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn get(&self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        Ok(self.0.get(user).cloned())
+    }
+
+    fn put(&mut self, user: &str, keys: UserKeys) -> Result<(), RSAError> {
+        self.0.insert(user.to_string(), keys);
+        Ok(())
+    }
+
+    fn delete(&mut self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        Ok(self.0.remove(user))
+    }
+
+    fn list(&self) -> Result<Vec<String>, RSAError> {
+        Ok(self.0.keys().cloned().collect())
+    }
+
+    fn location(&self) -> String {
+        "(memory)".to_string()
+    }
+}
+
+/// All keys in one deflated database file. Keys are loaded when the store is opened and written back on `flush`
+/// If another process saved the database in the meantime, its changes are kept as long as they touch different users
+#[derive(Debug)]
+pub struct FileStore {
+    db: SimpleDB<HashMap<String, UserKeys>>,
+    keys: HashMap<String, UserKeys>,
+    /// the keys as they were when loaded, to tell our changes from those of other processes
+    loaded: HashMap<String, UserKeys>,
+    changed: HashSet<String>,
+}
+
+impl FileStore {
+    /// Open the database at `path`, creating it if it does not exist
+    pub fn open(path: PathBuf) -> Result<Self, RSAError> {
+        Self::new(SimpleDB::new(path)?)
+    }
+
+    /// Load the keys from an open database
+    pub fn new(db: SimpleDB<HashMap<String, UserKeys>>) -> Result<Self, RSAError> {
+        let keys = db.get()?;
+        Ok(FileStore { db, loaded: keys.clone(), keys, changed: HashSet::new() })
+    }
+
+    /// Save any changes, then rewrite the database encrypted with `passphrase`, or unencrypted with `None`
+    pub fn set_passphrase(&mut self, passphrase: Option<Passphrase>) -> Result<(), RSAError> {
+        self.flush()?;
+        self.db.set_passphrase(passphrase);
+        self.db.save(self.keys.clone())
+    }
+
+    /// Rewrite the database in the current layout if it was written in an older one. Returns whether it was
+    pub fn migrate(&mut self) -> Result<bool, RSAError> {
+        let outdated = self.db.is_outdated();
+        self.flush()?;
+        if self.db.is_outdated() {
+            self.db.save(self.keys.clone())?;
+        }
+        Ok(outdated)
+    }
+
+    /// Save any changes, then overwrite and remove the backup of the previous version, which may still hold
+    /// deleted keys
+    pub fn erase_backup(&mut self) -> Result<(), RSAError> {
+        self.flush()?;
+        self.db.erase_backup()
+    }
+
+    /// Save any changes, then rewrite the database with `codec` unless it is already saved with it
+    pub fn set_codec(&mut self, codec: Codec) -> Result<(), RSAError> {
+        self.flush()?;
+        if self.db.codec() != codec {
+            self.db.set_codec(codec);
+            self.db.save(self.keys.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyStore for FileStore {
+    fn get(&self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        Ok(self.keys.get(user).cloned())
+    }
+
+    fn put(&mut self, user: &str, keys: UserKeys) -> Result<(), RSAError> {
+        self.changed.insert(user.to_string());
+        self.keys.insert(user.to_string(), keys);
+        Ok(())
+    }
+
+    fn delete(&mut self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        self.changed.insert(user.to_string());
+        Ok(self.keys.remove(user))
+    }
+
+    fn list(&self) -> Result<Vec<String>, RSAError> {
+        Ok(self.keys.keys().cloned().collect())
+    }
+
+    fn location(&self) -> String {
+        let path = self.db.file_path();
+        path.canonicalize().unwrap_or_else(|_| path.clone()).display().to_string()
+    }
+
+    fn flush(&mut self) -> Result<(), RSAError> {
+        if self.changed.is_empty() {
+            return Ok(());
+        }
+        let (keys, loaded, changed) = (&self.keys, &self.loaded, &self.changed);
+        let saved = self.db.save_with(keys.clone(), |mut theirs, _| {
+            for user in changed {
+                // both processes changed the same user; neither version can win
+                if theirs.get(user) != loaded.get(user) {
+                    Err(ErrorKind::DatabaseChanged)?
+                }
+                match keys.get(user) {
+                    Some(key) => theirs.insert(user.clone(), key.clone()),
+                    None => theirs.remove(user),
+                };
+            }
+            Ok(theirs)
+        })?;
+        self.keys = saved.clone();
+        self.loaded = saved;
+        self.changed.clear();
+        Ok(())
+    }
+}
+
+const KEY_EXTENSION: &str = "key";
+
+/// One file per user in a directory, named after the user. Every change is written immediately
+/// With a passphrase, every file is sealed like an encrypted `FileStore`; see `unlock` and `set_passphrase`
+#[derive(Debug)]
+pub struct DirectoryStore {
+    dir: PathBuf,
+    passphrase: Option<Passphrase>,
+    /// the key the files are sealed with, shared between them so that Argon2 runs once rather than once per file
+    sealing_key: Rc<RefCell<Option<SealingKey>>>,
+    /// the keys of each user as we last read or wrote them, to notice changes made by others before writing
+    loaded: RefCell<HashMap<String, Option<UserKeys>>>,
+}
+
+impl DirectoryStore {
+    /// Use the directory `dir`, creating it if it does not exist
+    pub fn open(dir: PathBuf) -> Result<Self, RSAError> {
+        fs::create_dir_all(&dir).context(ErrorKind::Database)?;
+        Ok(DirectoryStore { dir, passphrase: None, sealing_key: Rc::new(RefCell::new(None)), loaded: RefCell::new(HashMap::new()) })
+    }
+
+    /// The passphrase to open sealed key files with. Every file written from now on is sealed with it
+    pub fn unlock(&mut self, passphrase: Passphrase) {
+        self.passphrase = Some(passphrase);
+    }
+
+    /// Rewrite every key file sealed with `passphrase`, or unsealed with `None`, and erase the backups that are still
+    /// sealed with the old one
+    pub fn set_passphrase(&mut self, passphrase: Option<Passphrase>) -> Result<(), RSAError> {
+        // read everything with the old passphrase before anything is written with the new one
+        let mut keyring = Vec::new();
+        for user in self.list()? {
+            let db = self.db(&user)?;
+            let keys = db.get()?;
+            keyring.push((db, keys));
+        }
+        let sealing_key = Rc::new(RefCell::new(passphrase.as_ref().map(SealingKey::new).transpose()?));
+        for (mut db, keys) in keyring {
+            match &passphrase {
+                Some(passphrase) => {
+                    db.unlock(passphrase.clone());
+                    db.share_sealing_key(sealing_key.clone());
+                },
+                None => db.set_passphrase(None),
+            }
+            db.save(keys)?;
+            db.erase_backup()?;
+        }
+        self.passphrase = passphrase;
+        self.sealing_key = sealing_key;
+        Ok(())
+    }
+
+    /// Rewrite every key file written in an older layout. Returns how many there were
+    pub fn migrate(&mut self) -> Result<usize, RSAError> {
+        let mut migrated = 0;
+        for user in self.list()? {
+            let db = self.db(&user)?;
+            let key = db.get()?;
+            if db.is_outdated() {
+                db.save(key)?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", encode_file_name(user), KEY_EXTENSION))
+    }
+
+    // the key file of `user`, creating it if it does not exist, opened with our passphrase
+    fn db(&self, user: &str) -> Result<SimpleDB<Option<UserKeys>>, RSAError> {
+        let mut db = SimpleDB::new(self.path(user))?;
+        db.share_sealing_key(self.sealing_key.clone());
+        if let Some(passphrase) = &self.passphrase {
+            db.unlock(passphrase.clone());
+        }
+        Ok(db)
+    }
+}
+
+// user names can contain anything; keep the readable ones as they are and percent-encode the rest
+fn encode_file_name(user: &str) -> String {
+    let mut name = String::new();
+    for (i, byte) in user.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => name.push_str("%2E"),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'@' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+fn decode_file_name(name: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((byte, tail)) = rest.split_first() {
+        if *byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(*byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl KeyStore for DirectoryStore {
+    fn get(&self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        let path = self.path(user);
+        if !path.exists() {
+            self.loaded.borrow_mut().remove(user);
+            return Ok(None);
+        }
+        let keys = self.db(user)?.get()?;
+        self.loaded.borrow_mut().insert(user.to_string(), keys.clone());
+        Ok(keys)
+    }
+
+    fn put(&mut self, user: &str, keys: UserKeys) -> Result<(), RSAError> {
+        let loaded = self.loaded.borrow().get(user).cloned().flatten();
+        // someone else changed this user since we read them; neither version can win
+        let unchanged = |theirs: &Option<UserKeys>| -> Result<(), RSAError> {
+            if *theirs != loaded {
+                Err(ErrorKind::DatabaseChanged)?
+            }
+            Ok(())
+        };
+        // compare now, and again under the lock if the file changes before it is written
+        let db = self.db(user)?;
+        unchanged(&db.get()?)?;
+        let saved = db.save_with(Some(keys), |theirs, ours| unchanged(&theirs).map(|_| ours))?;
+        self.loaded.borrow_mut().insert(user.to_string(), saved);
+        Ok(())
+    }
+
+    fn delete(&mut self, user: &str) -> Result<Option<UserKeys>, RSAError> {
+        let key = self.get(user)?;
+        if key.is_some() {
+            self.db(user)?.destroy()?;
+        }
+        self.loaded.borrow_mut().remove(user);
+        Ok(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, RSAError> {
+        let mut users = Vec::new();
+        for entry in fs::read_dir(&self.dir).context(ErrorKind::Database)? {
+            let path = entry.context(ErrorKind::Database)?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(KEY_EXTENSION) {
+                continue;
+            }
+            if let Some(user) = path.file_stem().and_then(|s| s.to_str()).and_then(decode_file_name) {
+                users.push(user);
+            }
+        }
+        Ok(users)
+    }
+
+    fn location(&self) -> String {
+        self.dir.canonicalize().unwrap_or_else(|_| self.dir.clone()).display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rsa::RSA;
+    use crate::primes::KeySize;
+    use num_bigint::BigUint;
+
+    fn exercise(store: &mut impl KeyStore) {
+        for user in &["alice", "bob/../eve", ".hidden"] {
+            store.put(user, UserKeys::new(RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve))).unwrap();
+        }
+        assert_eq!(store.get("alice").unwrap().unwrap().primary().unwrap().public(), &BigUint::from(3233u32));
+        assert!(store.get("carol").unwrap().is_none());
+        assert!(store.delete("alice").unwrap().is_some());
+        assert!(store.delete("alice").unwrap().is_none());
+        let users: Vec<String> = store.iter().unwrap().map(|(user, _)| user).collect();
+        assert_eq!(users, vec![".hidden", "bob/../eve"]);
+    }
+
+    #[test]
+    fn should_store_keys_in_memory() {
+        exercise(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn should_store_keys_in_a_directory() {
+        let dir = PathBuf::from("/tmp/RSA_KEYSTORE_DIR_TEST");
+        let _ = fs::remove_dir_all(&dir);
+        exercise(&mut DirectoryStore::open(dir.clone()).unwrap());
+        assert!(dir.join("bob%2F..%2Feve.key").exists());
+        assert!(dir.join("%2Ehidden.key").exists());
+        assert!(!dir.join("alice.key.lock").exists());
+    }
+
+    #[test]
+    fn should_seal_every_file_of_a_directory() {
+        let dir = PathBuf::from("/tmp/RSA_KEYSTORE_SEALED_DIR_TEST");
+        let _ = fs::remove_dir_all(&dir);
+        let passphrase = |p: &str| Passphrase::new(p.to_string());
+        let sealed = |user: &str| crate::seal::is_sealed(&fs::read(dir.join(format!("{}.key", user))).unwrap());
+        let mut store = DirectoryStore::open(dir.clone()).unwrap();
+        store.put("alice", UserKeys::new(RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve))).unwrap();
+        store.set_passphrase(Some(passphrase("correct horse"))).unwrap();
+        store.put("bob", UserKeys::new(RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve))).unwrap();
+        assert!(sealed("alice") && sealed("bob"));
+        assert!(!dir.join("alice.key.bak").exists());
+
+        let mut store = DirectoryStore::open(dir.clone()).unwrap();
+        assert_eq!(store.get("alice").unwrap_err().kind(), ErrorKind::PassphraseRequired);
+        store.unlock(passphrase("battery staple"));
+        assert_eq!(store.get("alice").unwrap_err().kind(), ErrorKind::WrongPassphrase);
+        let mut store = DirectoryStore::open(dir.clone()).unwrap();
+        store.unlock(passphrase("correct horse"));
+        assert_eq!(store.iter().unwrap().count(), 2);
+        store.set_passphrase(None).unwrap();
+        assert!(!sealed("alice") && !sealed("bob"));
+        assert_eq!(DirectoryStore::open(dir).unwrap().iter().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn should_store_keys_in_a_file() {
+        let path = PathBuf::from("/tmp/RSA_KEYSTORE_FILE_TEST");
+        let _ = fs::remove_file(&path);
+        let mut store = FileStore::open(path.clone()).unwrap();
+        exercise(&mut store);
+        store.flush().unwrap();
+        assert_eq!(FileStore::open(path).unwrap().list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn should_merge_changes_from_other_processes() {
+        let path = PathBuf::from("/tmp/RSA_KEYSTORE_MERGE_TEST");
+        let _ = fs::remove_file(&path);
+        let key = |n: u32| UserKeys::new(RSA::new(BigUint::from(n), None, KeySize::FiveTwelve));
+        let mut ours = FileStore::open(path.clone()).unwrap();
+        let mut theirs = FileStore::open(path.clone()).unwrap();
+        ours.put("alice", key(1)).unwrap();
+        theirs.put("bob", key(2)).unwrap();
+        theirs.flush().unwrap();
+        ours.flush().unwrap();
+        assert_eq!(FileStore::open(path.clone()).unwrap().list().unwrap().len(), 2);
+
+        let mut ours = FileStore::open(path.clone()).unwrap();
+        let mut theirs = FileStore::open(path.clone()).unwrap();
+        ours.put("alice", key(3)).unwrap();
+        theirs.put("alice", key(4)).unwrap();
+        theirs.flush().unwrap();
+        assert_eq!(ours.flush().unwrap_err().kind(), ErrorKind::DatabaseChanged);
+        assert_eq!(FileStore::open(path).unwrap().get("alice").unwrap().unwrap().primary().unwrap().public(), &BigUint::from(4u32));
+    }
+
+    #[test]
+    fn should_refuse_changes_to_key_files_written_by_others() {
+        let dir = PathBuf::from("/tmp/RSA_KEYSTORE_DIR_MERGE_TEST");
+        let _ = fs::remove_dir_all(&dir);
+        let key = |n: u32| UserKeys::new(RSA::new(BigUint::from(n), None, KeySize::FiveTwelve));
+        let mut ours = DirectoryStore::open(dir.clone()).unwrap();
+        let mut theirs = DirectoryStore::open(dir.clone()).unwrap();
+        ours.put("alice", key(1)).unwrap();
+        theirs.put("bob", key(2)).unwrap();
+        assert_eq!(DirectoryStore::open(dir.clone()).unwrap().list().unwrap().len(), 2);
+
+        // both read alice, then both change their keys
+        assert!(ours.get("alice").unwrap().is_some() && theirs.get("alice").unwrap().is_some());
+        ours.put("alice", key(3)).unwrap();
+        assert_eq!(theirs.put("alice", key(4)).unwrap_err().kind(), ErrorKind::DatabaseChanged);
+        // both found no carol, then both added keys for carol
+        assert!(ours.get("carol").unwrap().is_none() && theirs.get("carol").unwrap().is_none());
+        theirs.put("carol", key(5)).unwrap();
+        assert_eq!(ours.put("carol", key(6)).unwrap_err().kind(), ErrorKind::DatabaseChanged);
+
+        let store = DirectoryStore::open(dir).unwrap();
+        assert_eq!(store.get("alice").unwrap().unwrap().primary().unwrap().public(), &BigUint::from(3u32));
+        assert_eq!(store.get("carol").unwrap().unwrap().primary().unwrap().public(), &BigUint::from(5u32));
+        // writing what we read again is fine
+        ours.get("alice").unwrap();
+        ours.put("alice", key(7)).unwrap();
+    }
+
+    #[test]
+    fn should_migrate_every_historical_layout() {
+        let fixtures: &[(&str, &[u8], Codec)] = &[
+            ("json", include_bytes!("../tests/fixtures/keyring-v0-json.db"), Codec::Json),
+            ("msgpack", include_bytes!("../tests/fixtures/keyring-v0-msgpack.db"), Codec::MessagePack),
+            ("bincode", include_bytes!("../tests/fixtures/keyring-v0-bincode.db"), Codec::Bincode),
+            ("v1-json", include_bytes!("../tests/fixtures/keyring-v1-json.db"), Codec::Json),
+            ("v1-msgpack", include_bytes!("../tests/fixtures/keyring-v1-msgpack.db"), Codec::MessagePack),
+            ("v1-bincode", include_bytes!("../tests/fixtures/keyring-v1-bincode.db"), Codec::Bincode),
+            ("v2-json", include_bytes!("../tests/fixtures/keyring-v2-json.db"), Codec::Json),
+            ("v2-msgpack", include_bytes!("../tests/fixtures/keyring-v2-msgpack.db"), Codec::MessagePack),
+            ("v2-bincode", include_bytes!("../tests/fixtures/keyring-v2-bincode.db"), Codec::Bincode),
+            ("v3-json", include_bytes!("../tests/fixtures/keyring-v3-json.db"), Codec::Json),
+            ("v3-msgpack", include_bytes!("../tests/fixtures/keyring-v3-msgpack.db"), Codec::MessagePack),
+            ("v3-bincode", include_bytes!("../tests/fixtures/keyring-v3-bincode.db"), Codec::Bincode),
+            ("v4-json", include_bytes!("../tests/fixtures/keyring-v4-json.db"), Codec::Json),
+            ("v4-msgpack", include_bytes!("../tests/fixtures/keyring-v4-msgpack.db"), Codec::MessagePack),
+            ("v4-bincode", include_bytes!("../tests/fixtures/keyring-v4-bincode.db"), Codec::Bincode),
+            ("v5-json", include_bytes!("../tests/fixtures/keyring-v5-json.db"), Codec::Json),
+            ("v5-msgpack", include_bytes!("../tests/fixtures/keyring-v5-msgpack.db"), Codec::MessagePack),
+            ("v5-bincode", include_bytes!("../tests/fixtures/keyring-v5-bincode.db"), Codec::Bincode),
+            ("v6-json", include_bytes!("../tests/fixtures/keyring-v6-json.db"), Codec::Json),
+            ("v6-msgpack", include_bytes!("../tests/fixtures/keyring-v6-msgpack.db"), Codec::MessagePack),
+            ("v6-bincode", include_bytes!("../tests/fixtures/keyring-v6-bincode.db"), Codec::Bincode),
+        ];
+        for (name, fixture, codec) in fixtures {
+            let path = PathBuf::from(format!("/tmp/RSA_MIGRATE_TEST_{}", name));
+            fs::write(&path, fixture).unwrap();
+            let mut store = FileStore::open(path.clone()).unwrap();
+            let users: Vec<String> = store.iter().unwrap().map(|(user, _)| user).collect();
+            assert_eq!(users[..2], ["alice", "bob"]);
+            assert!(store.migrate().unwrap());
+
+            let mut store = FileStore::open(path.clone()).unwrap();
+            assert!(!store.migrate().unwrap());
+            assert_eq!(store.db.codec(), *codec);
+            assert_eq!(store.get("alice").unwrap().unwrap().ownership(), crate::trust::Ownership::Own);
+            if name.starts_with("v6") {
+                // bob was verified when verification was a level of trust
+                let bob = store.get("bob").unwrap().unwrap();
+                assert_eq!((bob.is_verified(), bob.trust()), (true, crate::trust::Trust::Unknown));
+            }
+            let rsa = crate::rsa::AlgoRSA::new(store);
+            let ciphertext = rsa.encrypt("alice", b"migrated").unwrap();
+            assert_eq!(rsa.decrypt("alice", ciphertext).unwrap(), b"migrated");
+        }
+
+        // the key files of a `DirectoryStore`: headerless JSON in layout 0, MessagePack since
+        let directories: &[(&str, &[u8], &[u8])] = &[
+            ("v0", include_bytes!("../tests/fixtures/keyring-v0-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v0-dir/bob.key")),
+            ("v1", include_bytes!("../tests/fixtures/keyring-v1-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v1-dir/bob.key")),
+            ("v2", include_bytes!("../tests/fixtures/keyring-v2-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v2-dir/bob.key")),
+            ("v3", include_bytes!("../tests/fixtures/keyring-v3-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v3-dir/bob.key")),
+            ("v4", include_bytes!("../tests/fixtures/keyring-v4-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v4-dir/bob.key")),
+            ("v5", include_bytes!("../tests/fixtures/keyring-v5-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v5-dir/bob.key")),
+            ("v6", include_bytes!("../tests/fixtures/keyring-v6-dir/alice.key"), include_bytes!("../tests/fixtures/keyring-v6-dir/bob.key")),
+        ];
+        for (name, alice, bob) in directories {
+            let dir = PathBuf::from(format!("/tmp/RSA_MIGRATE_DIR_TEST_{}", name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("alice.key"), alice).unwrap();
+            fs::write(dir.join("bob.key"), bob).unwrap();
+            let mut store = DirectoryStore::open(dir.clone()).unwrap();
+            let users: Vec<String> = store.iter().unwrap().map(|(user, _)| user).collect();
+            assert_eq!(users, ["alice", "bob"]);
+            assert_eq!(store.migrate().unwrap(), 2);
+            assert_eq!(store.migrate().unwrap(), 0);
+
+            assert_eq!(store.get("alice").unwrap().unwrap().ownership(), crate::trust::Ownership::Own);
+            assert_eq!(store.get("bob").unwrap().unwrap().ownership(), crate::trust::Ownership::Contact);
+            let rsa = crate::rsa::AlgoRSA::new(store);
+            let ciphertext = rsa.encrypt("alice", b"migrated").unwrap();
+            assert_eq!(rsa.decrypt("alice", ciphertext).unwrap(), b"migrated");
+        }
+    }
+}
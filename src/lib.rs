@@ -0,0 +1,45 @@
+//! RSA key generation, encryption and signatures, with a small on-disk keyring
+//!
+//! Keys are generated with `PrimeFinder` and kept per-user in an `AlgoRSA` keyring, which is
+//! persisted through a `KeyStore`. On top of the keyring sit interoperable formats: JWS/JWT (`jwt`),
+//! CMS/S-MIME (`cms`) and SSH signatures (`sshsig`).
+//!
+//! ```no_run
+//! use rsa::{AlgoRSA, KeySize, FileStore, SignatureScheme, HashAlgorithm};
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> Result<(), rsa::RSAError> {
+//! let rsa = AlgoRSA::new(FileStore::open(PathBuf::from("keys.db"))?);
+//! rsa.create("alice", &KeySize::TwentyFourtyEight)?;
+//! let scheme = SignatureScheme::Pss(HashAlgorithm::Sha256);
+//! let signature = rsa.sign("alice", scheme, b"hello")?;
+//! rsa.verify("alice", scheme, b"hello", &signature)?;
+//! rsa.save_keys()?;
+//! # Ok(())
+//! # }
+//! ```
+#![allow(clippy::upper_case_acronyms)]
+pub mod primes;
+pub mod rsa;
+pub mod err;
+pub mod simpledb;
+pub mod codec;
+pub mod keystore;
+mod math;
+mod seal;
+pub mod jwt;
+pub mod der;
+pub mod x509;
+pub mod cms;
+pub mod sshsig;
+pub mod revocation;
+pub mod trust;
+
+pub use crate::rsa::{AlgoRSA, RSA, UserKeys, UserInfo, KeyInfo, Message, Usage, KeyType, E, verify_signature, HashAlgorithm, SignatureScheme, EncryptionScheme};
+pub use crate::primes::{KeySize, PrimeFinder};
+pub use crate::simpledb::{SimpleDB, Schema};
+pub use crate::codec::Codec;
+pub use crate::trust::{Ownership, Trust};
+pub use crate::seal::Passphrase;
+pub use crate::keystore::{KeyStore, MemoryStore, FileStore, DirectoryStore};
+pub use crate::err::{ErrorKind, RSAError};
@@ -1,15 +1,23 @@
-mod primes;
-mod rsa;
-mod err;
-mod simpledb;
-mod math;
+//! Command-line front-end to the `rsa` library
+#![allow(clippy::upper_case_acronyms)]
 mod cli;
 
-use cli::App;
+use cli::{App, OutputFormat};
+use failure::Fail;
 
 fn main() {
-
-    if let Err(e) = App::run() {
-        eprintln!("{}", e);
+    let app = App::from_args();
+    let output = app.output();
+    if let Err(e) = app.run() {
+        if output == OutputFormat::Json {
+            eprintln!("{}", cli::error_json(&e));
+        } else {
+            eprintln!("{}", e);
+            let fail: &dyn Fail = &e;
+            for cause in fail.iter_causes() {
+                eprintln!("  caused by: {}", cause);
+            }
+        }
+        std::process::exit(e.kind().exit_code());
     }
 }
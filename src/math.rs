@@ -2,11 +2,10 @@
 //! NOTE: Fermat and Rabin-Miller used for Primality testing may be found implementede within the 'ProbableVariant' enum inside primes/gen.rs
 //! These functions are not exposed as public-api because their use is strictly for generating large prime numbers
 //! These are generally helper functions
-use crate::err::ErrorKind;
+use crate::err::{ErrorKind, RSAError};
 
 use num_bigint::{BigUint, BigInt, ToBigInt};
 use num_traits::{One, Zero};
-use failure::Error;
 
 
 pub trait Mod<B = Self> {
@@ -54,7 +53,7 @@ pub fn prime_phi(p: &BigUint, q: &BigUint) -> BigUint {
 }
 
 // Euclids Extended GCD
-pub fn egcd(a: &BigUint, b: &BigUint) -> Result<(BigInt, BigInt, BigInt), Error> {
+pub fn egcd(a: &BigUint, b: &BigUint) -> Result<(BigInt, BigInt, BigInt), RSAError> {
     let (mut a, mut b) = (a.to_bigint().ok_or(ErrorKind::BigNumConversion)?, b.to_bigint().ok_or(ErrorKind::BigNumConversion)?);
     let (mut x, mut y, mut u, mut v) = (BigInt::zero(), BigInt::one(), BigInt::one(), BigInt::zero());
 
@@ -73,20 +72,19 @@ pub fn egcd(a: &BigUint, b: &BigUint) -> Result<(BigInt, BigInt, BigInt), Error>
         u = m.clone();
         v = n.clone();
     }
-    return Ok((b.clone(), x, y))
+    Ok((b.clone(), x, y))
 }
 
 // TODO: figure out a way to avoid using BigInts altogether
 // usually E, Phi_n
-pub fn modinv(a: &BigUint, b: &BigUint) -> Result<BigUint, Error> {
-    let (g, x, _) = egcd(&a, &b)?;
+pub fn modinv(a: &BigUint, b: &BigUint) -> Result<BigUint, RSAError> {
+    let (g, x, _) = egcd(a, b)?;
     let b = b.to_bigint().ok_or(ErrorKind::BigNumConversion)?;
-    if g == BigInt::one() {
-        return Ok((x.modulus(b)).to_biguint().ok_or(ErrorKind::BigNumConversion)?);
-    } else {
-        // This will never (hopefully, EVER) happen since p and q are real primes and the gcd phi_n is always 1. Q.E.D
-        panic!("P or Q are not real primes such that gcd(e, phi(n)) == 1. Aborting Execution.");
+    // no inverse exists unless gcd(a, b) == 1; key generation picks primes so that gcd(e, phi_n) == 1
+    if g != BigInt::one() || b.is_zero() {
+        Err(ErrorKind::NoModularInverse)?
     }
+    Ok((x.modulus(b)).to_biguint().ok_or(ErrorKind::BigNumConversion)?)
 }
 
 #[cfg(test)]
@@ -101,6 +99,12 @@ mod tests {
         assert_eq!(modinv(&BigUint::from(3361usize), &BigUint::from(211usize)).unwrap(), 14usize.into());
     }
 
+    #[test]
+    fn should_refuse_numbers_without_inverse() {
+        assert_eq!(modinv(&BigUint::from(6usize), &BigUint::from(9usize)).unwrap_err().kind(), ErrorKind::NoModularInverse);
+        assert_eq!(modinv(&BigUint::from(1usize), &BigUint::zero()).unwrap_err().kind(), ErrorKind::NoModularInverse);
+    }
+
     #[test]
     fn should_find_egcd() {
         assert_eq!(egcd(&BigUint::from(23usize), &BigUint::from(3usize)).unwrap(), (BigInt::from(1), BigInt::from(-1), BigInt::from(8)));
@@ -1,12 +1,11 @@
 //! Generates Random Numbers for use in Prime Number Choosing
-use crate::err::ErrorKind;
+use crate::err::{ErrorKind, RSAError};
 use super::*;
 
 use rand::rngs::EntropyRng;
 use rand::Rng;
 use num_bigint::{BigUint, RandBigInt};
 use num_traits::{One, Zero};
-use failure::{Error};
 
 
 const MINIMUM_KEY_LENGTH: usize = 256;
@@ -29,15 +28,15 @@ impl<'a> NumberGenerator<'a> {
     /// Instantiate a new NumberGenerator
     /// "size" corresponds to the size in bits the number must be
     /// Size must be larger than 512 and a power of 2
-    pub fn new(size: &'a KeySize) -> Result<Self, Error> {
+    pub fn new(size: &'a KeySize) -> Result<Self, RSAError> {
 
         // must be larger than 512 bits and a power of 2
-        if size.as_num() < MINIMUM_KEY_LENGTH  || !((size.as_num() & (size.as_num() - 1 )) == 0) {
+        if size.as_num() < MINIMUM_KEY_LENGTH  || ((size.as_num() & (size.as_num() - 1 )) != 0) {
             Err(ErrorKind::InvalidKeyLength)?
         }
 
         Ok(NumberGenerator {
-            size: size,
+            size,
             generator: EntropyRng::new()
         })
     }
@@ -107,7 +106,7 @@ impl ProbableVariant {
         let mut rng = rand::thread_rng();
         let a = rng.gen_biguint_range(&BigUint::one(), &(candidate - BigUint::one()));
 
-        if a.modpow(&(candidate - BigUint::one()), &candidate) == BigUint::one() {
+        if a.modpow(&(candidate - BigUint::one()), candidate) == BigUint::one() {
             ProbableVariant::Prime
         } else {
             ProbableVariant::Composite
@@ -130,18 +129,18 @@ impl ProbableVariant {
         // find a d such that 2^s*d = n - 1
         while (d.clone() % 2usize) == BigUint::zero() {
             s += 1;
-            d = d / BigUint::from(2usize);
+            d /= BigUint::from(2usize);
         }
 
         for _ in 0..rounds {
             let a = rng.gen_biguint_range(&BigUint::from(2usize), &(candidate - 2usize));
-            let mut x = a.modpow(&d, &candidate);
+            let mut x = a.modpow(&d, candidate);
             if x == BigUint::one() || x == candidate_minus_one {
                 continue;
             }
             let mut r = 1;
             while r < s {
-                x = x.modpow(&BigUint::from(2usize), &candidate);
+                x = x.modpow(&BigUint::from(2usize), candidate);
 
                 if x == BigUint::one() {
                     return ProbableVariant::Composite;
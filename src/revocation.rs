@@ -0,0 +1,125 @@
+//! Revocation certificates
+//! A revocation is signed by the key it revokes, so anyone holding the public key can check it, and it can be
+//! handed around like a public key
+use crate::rsa::{HashAlgorithm, SignatureScheme, E, verify_signature};
+use crate::err::{ErrorKind, RSAError};
+use num_bigint::BigUint;
+use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+
+const DOMAIN: &[u8] = b"RSA KEY REVOCATION\0";
+
+/// Signature scheme of revocations. PKCS#1 v1.5 so that even 512-bit keys can revoke themselves
+pub const SCHEME: SignatureScheme = SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256);
+
+/// Why a key was revoked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The private key may be known to someone else. Nothing it ever signed can be trusted
+    Compromised,
+    /// Replaced by a newer key. Signatures made before the revocation stay valid
+    Superseded,
+    /// No longer used. Signatures made before the revocation stay valid
+    Retired,
+    /// Treated like `Compromised`, since nothing says otherwise
+    Unspecified,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 4] = [Reason::Compromised, Reason::Superseded, Reason::Retired, Reason::Unspecified];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::Compromised => "compromised",
+            Reason::Superseded => "superseded",
+            Reason::Retired => "retired",
+            Reason::Unspecified => "unspecified",
+        }
+    }
+
+    /// Whether signatures made before the revocation are invalid too
+    pub fn is_hard(self) -> bool {
+        matches!(self, Reason::Compromised | Reason::Unspecified)
+    }
+}
+
+impl FromStr for Reason {
+    type Err = RSAError;
+
+    fn from_str(s: &str) -> Result<Self, RSAError> {
+        Ok(*Reason::ALL.iter().find(|r| r.as_str() == s).ok_or(ErrorKind::UnknownReason)?)
+    }
+}
+
+/// A signed statement that a key must no longer be used
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revocation {
+    pub reason: Reason,
+    /// When the key was revoked, in seconds since the epoch
+    pub revoked_at: u64,
+    signature: Vec<u8>,
+}
+
+impl Revocation {
+    /// The bytes the key with modulus `n` signs to revoke itself
+    pub fn signed_data(n: &BigUint, reason: Reason, revoked_at: u64) -> Vec<u8> {
+        let mut data = DOMAIN.to_vec();
+        data.extend_from_slice(&n.to_bytes_be());
+        data.push(0);
+        data.extend_from_slice(reason.as_str().as_bytes());
+        data.extend_from_slice(&revoked_at.to_be_bytes());
+        data
+    }
+
+    /// A revocation from its signature over `signed_data`
+    pub fn new(reason: Reason, revoked_at: u64, signature: Vec<u8>) -> Self {
+        Revocation { reason, revoked_at, signature }
+    }
+
+    /// Check that the key with modulus `n` signed this revocation
+    pub fn verify(&self, n: &BigUint) -> Result<(), RSAError> {
+        verify_signature(n, &E.into(), SCHEME, &Self::signed_data(n, self.reason, self.revoked_at), &self.signature)
+    }
+
+    /// Whether a signature made at `signed_at` is invalidated by this revocation. Without a time, only a hard
+    /// revocation invalidates it
+    pub fn invalidates(&self, signed_at: Option<u64>) -> bool {
+        self.reason.is_hard() || signed_at.is_some_and(|t| t >= self.revoked_at)
+    }
+
+    /// Whether this revocation should replace `other` on the same key. A hard revocation wins over a soft one,
+    /// and between two of the same strength the earlier one does
+    pub fn supersedes(&self, other: &Revocation) -> bool {
+        match (self.reason.is_hard(), other.reason.is_hard()) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => self.revoked_at < other.revoked_at,
+        }
+    }
+
+    /// The revocation of the key with modulus `n`, for export
+    pub fn to_bytes(&self, n: &BigUint) -> Result<Vec<u8>, RSAError> {
+        Ok(bincode::serialize(&(n.to_bytes_be(), self))?)
+    }
+
+    /// Parse an exported revocation, returning the modulus of the revoked key with it
+    pub fn from_bytes(bytes: &[u8]) -> Result<(BigUint, Self), RSAError> {
+        let (n, revocation): (Vec<u8>, Revocation) = bincode::deserialize(bytes)?;
+        Ok((BigUint::from_bytes_be(&n), revocation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_prefer_hard_and_then_earlier_revocations() {
+        let revocation = |reason, revoked_at| Revocation::new(reason, revoked_at, Vec::new());
+        assert!(revocation(Reason::Compromised, 20).supersedes(&revocation(Reason::Superseded, 10)));
+        assert!(!revocation(Reason::Retired, 10).supersedes(&revocation(Reason::Unspecified, 20)));
+        assert!(revocation(Reason::Retired, 10).supersedes(&revocation(Reason::Superseded, 20)));
+        assert!(!revocation(Reason::Compromised, 20).supersedes(&revocation(Reason::Compromised, 10)));
+        assert!(!revocation(Reason::Superseded, 10).supersedes(&revocation(Reason::Superseded, 10)));
+    }
+}
@@ -1,3 +1,7 @@
+//! RSA keys, the keyring, and the padding schemes used to encrypt and sign with them
 mod lib;
+mod padding;
 
-pub use lib::{AlgoRSA, RSA, KeyType};
+pub use lib::{AlgoRSA, RSA, UserKeys, UserInfo, KeyInfo, Message, Usage, KeyType, E, verify_signature};
+pub(crate) use lib::{KeyV1, UserKeysV2, UserKeysV3, UserKeysV4, UserKeysV5, UserKeysV6};
+pub use padding::{HashAlgorithm, SignatureScheme, EncryptionScheme};
@@ -1,15 +1,18 @@
 //! Where the magic happens
 use crate::primes::{KeySize, PrimeFinder};
-use crate::simpledb::SimpleDB;
+use crate::keystore::{KeyStore, FileStore};
 use crate::math;
-use crate::err::ErrorKind;
-use std::collections::HashMap;
+use crate::err::{ErrorKind, RSAError};
+use crate::revocation::{self, Reason, Revocation};
+use crate::trust::{self, Ownership, Trust, Certification};
+use super::padding::{SignatureScheme, EncryptionScheme, HashAlgorithm};
+use crate::x509;
 use std::cell::RefCell;
-use num_bigint::{BigInt, BigUint};
+use zeroize::Zeroizing;
+use num_bigint::BigUint;
 use num_traits::{Zero};
 use serde::{Serialize, Deserialize};
 use rayon::prelude::*;
-use failure::{Error};
 
 pub const E: usize = 65537; // the encryption exponent
 
@@ -22,34 +25,334 @@ pub const E: usize = 65537; // the encryption exponent
  * 6. Bob decrypts by computing m = c^d (mod n)
  */
 
+/// Which half of a key pair to export
 pub enum KeyType {
     Public,
     Private
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What a key may be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    Encrypt,
+    Sign,
+    /// Sign certificates for keys, its own or those of others
+    Certify,
+}
+
+impl Usage {
+    pub const ALL: [Usage; 3] = [Usage::Encrypt, Usage::Sign, Usage::Certify];
+
+    fn all() -> Vec<Usage> {
+        Usage::ALL.to_vec()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Usage::Encrypt => "encrypt",
+            Usage::Sign => "sign",
+            Usage::Certify => "certify",
+        }
+    }
+}
+
+impl std::str::FromStr for Usage {
+    type Err = RSAError;
+
+    fn from_str(s: &str) -> Result<Self, RSAError> {
+        Ok(*Usage::ALL.iter().find(|u| u.as_str() == s).ok_or(ErrorKind::UnknownUsage)?)
+    }
+}
+
+/// A key as stored in the keyring: the modulus and, for our own keys, the private exponent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct RSA {
     n: BigUint,
     d: Option<BigUint>,
-    size: KeySize
+    size: KeySize,
+    /// DER-encoded X.509 certificate for this key, if one has been generated or imported
+    #[serde(default)]
+    certificate: Option<Vec<u8>>,
+    /// When the key was generated or imported, in seconds since the epoch. Unknown for keys from older databases
+    #[serde(default)]
+    created: Option<u64>,
+    #[serde(default)]
+    primary: bool,
+    /// After this time, in seconds since the epoch, the key no longer encrypts or signs
+    #[serde(default)]
+    expires: Option<u64>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default = "Usage::all")]
+    usages: Vec<Usage>,
+    #[serde(default)]
+    revocation: Option<Revocation>,
+}
+
+/// A key as laid out in databases before users could have several keys
+#[derive(Deserialize)]
+pub(crate) struct KeyV1 {
+    n: BigUint,
+    d: Option<BigUint>,
+    size: KeySize,
+    certificate: Option<Vec<u8>>,
+}
+
+impl From<KeyV1> for RSA {
+    fn from(key: KeyV1) -> RSA {
+        RSA { n: key.n, d: key.d, size: key.size, certificate: key.certificate, created: None, primary: true, expires: None, comment: None, usages: Usage::all(), revocation: None }
+    }
+}
+
+/// A key as laid out in databases before keys had metadata
+#[derive(Deserialize)]
+pub(crate) struct KeyV2 {
+    n: BigUint,
+    d: Option<BigUint>,
+    size: KeySize,
+    certificate: Option<Vec<u8>>,
+    created: Option<u64>,
+    primary: bool,
+}
+
+/// The keys of a user as laid out in databases before keys had metadata
+#[derive(Deserialize)]
+pub(crate) struct UserKeysV2 {
+    keys: Vec<KeyV2>,
+}
+
+impl From<UserKeysV2> for UserKeys {
+    fn from(old: UserKeysV2) -> UserKeys {
+        let keys = old.keys.into_iter()
+            .map(|key| RSA { n: key.n, d: key.d, size: key.size, certificate: key.certificate, created: key.created, primary: key.primary, expires: None, comment: None, usages: Usage::all(), revocation: None })
+            .collect();
+        UserKeys::from_keys(keys)
+    }
+}
+
+/// A key as laid out in databases before keys could be revoked
+#[derive(Deserialize)]
+pub(crate) struct KeyV3 {
+    n: BigUint,
+    d: Option<BigUint>,
+    size: KeySize,
+    certificate: Option<Vec<u8>>,
+    created: Option<u64>,
+    primary: bool,
+    expires: Option<u64>,
+    comment: Option<String>,
+    usages: Vec<Usage>,
+}
+
+/// The keys of a user as laid out in databases before keys could be revoked
+#[derive(Deserialize)]
+pub(crate) struct UserKeysV3 {
+    keys: Vec<KeyV3>,
+}
+
+impl From<UserKeysV3> for UserKeys {
+    fn from(old: UserKeysV3) -> UserKeys {
+        let keys = old.keys.into_iter()
+            .map(|key| RSA {
+                n: key.n, d: key.d, size: key.size, certificate: key.certificate, created: key.created, primary: key.primary,
+                expires: key.expires, comment: key.comment, usages: key.usages, revocation: None,
+            })
+            .collect();
+        UserKeys::from_keys(keys)
+    }
+}
+
+/// The keys of a user as laid out in databases before contacts were told apart from our own keys
+#[derive(Deserialize)]
+pub(crate) struct UserKeysV4 {
+    keys: Vec<RSA>,
+}
+
+impl From<UserKeysV4> for UserKeys {
+    fn from(old: UserKeysV4) -> UserKeys {
+        UserKeys::from_keys(old.keys)
+    }
+}
+
+/// Trust as laid out in databases before verifying a contact was kept apart from trusting them
+#[derive(Deserialize)]
+pub(crate) enum TrustV6 {
+    Unknown,
+    Marginal,
+    Full,
+    Verified,
+}
+
+impl From<TrustV6> for Trust {
+    // the verified fingerprint is kept on its own; it never said how far the contact is trusted
+    fn from(old: TrustV6) -> Trust {
+        match old {
+            TrustV6::Unknown | TrustV6::Verified => Trust::Unknown,
+            TrustV6::Marginal => Trust::Marginal,
+            TrustV6::Full => Trust::Full,
+        }
+    }
+}
+
+/// The keys of a user as laid out in databases before keys could be certified
+#[derive(Deserialize)]
+pub(crate) struct UserKeysV5 {
+    keys: Vec<RSA>,
+    ownership: Ownership,
+    trust: TrustV6,
+    verified: Option<String>,
+}
+
+impl From<UserKeysV5> for UserKeys {
+    fn from(old: UserKeysV5) -> UserKeys {
+        UserKeys { keys: old.keys, ownership: old.ownership, trust: old.trust.into(), verified: old.verified, certifications: Vec::new() }
+    }
+}
+
+/// The keys of a user as laid out in databases before verifying a contact was kept apart from trusting them
+#[derive(Deserialize)]
+pub(crate) struct UserKeysV6 {
+    keys: Vec<RSA>,
+    ownership: Ownership,
+    trust: TrustV6,
+    verified: Option<String>,
+    certifications: Vec<Certification>,
+}
+
+impl From<UserKeysV6> for UserKeys {
+    fn from(old: UserKeysV6) -> UserKeys {
+        UserKeys { keys: old.keys, ownership: old.ownership, trust: old.trust.into(), verified: old.verified, certifications: old.certifications }
+    }
+}
+
+fn now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
 }
 
 type PrivateKey = BigUint;
 type PublicKey = BigUint;
 
 impl RSA {
+    /// A key from its modulus, private exponent if we have it, and size, created now
     pub fn new(n: BigUint, d: Option<BigUint>, size: KeySize) -> Self {
-        RSA { n,d,size }
+        RSA { n, d, size, certificate: None, created: Some(now()), primary: false, expires: None, comment: None, usages: Usage::all(), revocation: None }
+    }
+
+    /// Short identifier of the key, as shown by `list` and recorded in messages encrypted to it: the first 64 bits
+    /// of the fingerprint, in hex
+    pub fn id(&self) -> String {
+        self.fingerprint_hex()[..16].to_string()
+    }
+
+    /// SHA-256 over the DER SubjectPublicKeyInfo of the key, as used by certificates and other tools
+    pub fn fingerprint(&self) -> Vec<u8> {
+        HashAlgorithm::Sha256.digest(&x509::subject_public_key_info(&self.n, &E.into()))
+    }
+
+    /// The fingerprint in uppercase hex
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint().iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    /// The fingerprint written like OpenSSH does, `SHA256:` and unpadded base64
+    pub fn fingerprint_base64(&self) -> String {
+        format!("SHA256:{}", base64::encode_config(&self.fingerprint(), base64::STANDARD_NO_PAD))
+    }
+
+    /// Whether `id` names this key: its fingerprint in hex or base64, at least 8 leading hex digits of it (the key
+    /// id is the first 16), or the identifier recorded in messages before keys had fingerprints
+    pub fn matches(&self, id: &str) -> bool {
+        let hex: String = id.trim_start_matches("0x").chars().filter(|c| *c != ':').collect::<String>().to_ascii_uppercase();
+        (hex.len() >= 8 && hex.chars().all(|c| c.is_ascii_hexdigit()) && self.fingerprint_hex().starts_with(&hex))
+            || id == self.fingerprint_base64()
+            || id == legacy_identifier(self)
+    }
+
+    /// When the key was generated or imported, in seconds since the epoch, if known
+    pub fn created(&self) -> Option<u64> {
+        self.created
+    }
+
+    /// Whether this is the key its user encrypts and signs with
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    /// When the key expires, in seconds since the epoch, if it does
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    pub fn set_expires(&mut self, expires: Option<u64>) {
+        self.expires = expires;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+
+    /// A note for humans, such as what the key is for
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
+
+    /// What the key may be used for
+    pub fn usages(&self) -> &[Usage] {
+        &self.usages
     }
 
+    pub fn set_usages(&mut self, usages: Vec<Usage>) {
+        self.usages = usages;
+    }
+
+    /// This key without its private exponent
+    pub fn to_public(&self) -> RSA {
+        RSA { d: None, ..self.clone() }
+    }
+
+    /// The revocation of this key, if it was revoked
+    pub fn revocation(&self) -> Option<&Revocation> {
+        self.revocation.as_ref()
+    }
+
+    /// Record `revocation`, unless the key already carries one that takes precedence. Returns the one kept
+    fn add_revocation(&mut self, revocation: Revocation) -> &Revocation {
+        match &mut self.revocation {
+            Some(existing) if !revocation.supersedes(existing) => (),
+            existing => *existing = Some(revocation),
+        }
+        self.revocation.as_ref().unwrap()
+    }
+
+    /// Refuse to use a revoked or expired key, or a key for something it is not meant for
+    pub fn check(&self, usage: Usage) -> Result<(), RSAError> {
+        if self.revocation.is_some() {
+            Err(ErrorKind::KeyRevoked)?
+        }
+        if self.is_expired() {
+            Err(ErrorKind::KeyExpired)?
+        }
+        if !self.usages.contains(&usage) {
+            Err(ErrorKind::KeyUsageNotAllowed)?
+        }
+        Ok(())
+    }
+
+    /// Whether this is one of our own keys
     pub fn private_exists(&self) -> bool {
         self.d.is_some()
     }
 
-    pub fn private(&self) -> Result<&PrivateKey, Error> {
+    /// The private exponent d
+    pub fn private(&self) -> Result<&PrivateKey, RSAError> {
         Ok(self.d.as_ref().ok_or(ErrorKind::PrivateKeyNotFound)?)
     }
 
+    /// The modulus n
     pub fn public(&self) -> &PublicKey {
         &self.n
     }
@@ -57,149 +360,1061 @@ impl RSA {
     pub fn size(&self) -> &KeySize {
         &self.size
     }
+
+    /// Actual length of the modulus in bits
+    pub fn bits(&self) -> usize {
+        self.n.bits()
+    }
+
+    // modpow panics on a zero modulus, which a corrupt or hand-written import can contain
+    fn modulus(&self) -> Result<&BigUint, RSAError> {
+        if self.n.is_zero() {
+            Err(ErrorKind::KeyTooSmall)?
+        }
+        Ok(&self.n)
+    }
+}
+
+/// The keys of one user, oldest first. One of them is primary and is used to encrypt and sign; the others are
+/// kept to decrypt and verify what was made with them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct UserKeys {
+    keys: Vec<RSA>,
+    #[serde(default)]
+    ownership: Ownership,
+    #[serde(default)]
+    trust: Trust,
+    /// The fingerprint, in hex, of the key that was verified with `verify_contact`
+    #[serde(default)]
+    verified: Option<String>,
+    /// Certifications of these keys by other users
+    #[serde(default)]
+    certifications: Vec<Certification>,
+}
+
+impl UserKeys {
+    /// A user with the single, primary key `key`. They are a contact unless we have the private key
+    pub fn new(key: RSA) -> Self {
+        let mut keys = UserKeys::from_keys(Vec::new());
+        keys.add(key);
+        keys.ownership = Self::ownership_of(&keys.keys);
+        keys
+    }
+
+    // keys that are ours if we have any of their private keys
+    fn from_keys(keys: Vec<RSA>) -> Self {
+        UserKeys { ownership: Self::ownership_of(&keys), keys, trust: Trust::Unknown, verified: None, certifications: Vec::new() }
+    }
+
+    fn ownership_of(keys: &[RSA]) -> Ownership {
+        if keys.iter().any(RSA::private_exists) { Ownership::Own } else { Ownership::Contact }
+    }
+
+    /// Whether these are our keys or a contact's
+    pub fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    /// How far the contact is trusted to certify other keys. Means nothing for our own keys
+    pub fn trust(&self) -> Trust {
+        self.trust
+    }
+
+    /// Certifications of these keys by other users, not necessarily checked
+    pub fn certifications(&self) -> &[Certification] {
+        &self.certifications
+    }
+
+    // keep one certification per certifier and certified key, the newest
+    fn add_certification(&mut self, certification: Certification) -> bool {
+        let same = |c: &Certification| c.key == certification.key && c.certifier == certification.certifier;
+        if self.certifications.iter().any(|c| same(c) && c.created >= certification.created) || self.get(&certification.key).is_none() {
+            return false;
+        }
+        self.certifications.retain(|c| !same(c));
+        self.certifications.push(certification);
+        true
+    }
+
+    /// Whether the primary key can be relied on to belong to the user: it is ours, or its fingerprint was verified
+    pub fn is_verified(&self) -> bool {
+        self.ownership == Ownership::Own || self.primary().is_ok_and(|k| self.verified.as_ref() == Some(&k.fingerprint_hex()))
+    }
+
+    /// Add `key` as the new primary key
+    pub fn add(&mut self, mut key: RSA) {
+        self.keys.iter_mut().for_each(|k| k.primary = false);
+        key.primary = true;
+        self.keys.push(key);
+    }
+
+    /// The key used to encrypt and sign
+    pub fn primary(&self) -> Result<&RSA, RSAError> {
+        Ok(self.keys.iter().find(|k| k.primary).or_else(|| self.keys.last()).ok_or(ErrorKind::UserNotFound)?)
+    }
+
+    fn primary_mut(&mut self) -> Result<&mut RSA, RSAError> {
+        let index = self.keys.iter().position(|k| k.primary).or_else(|| self.keys.len().checked_sub(1));
+        Ok(&mut self.keys[index.ok_or(ErrorKind::UserNotFound)?])
+    }
+
+    /// The key that `id` names, as a key id, fingerprint or prefix of one
+    pub fn get(&self, id: &str) -> Option<&RSA> {
+        self.keys.iter().find(|k| k.matches(id))
+    }
+
+    // the key with the id `id`, or the primary key
+    fn get_mut(&mut self, id: Option<&str>) -> Result<&mut RSA, RSAError> {
+        match id {
+            Some(id) => Ok(self.keys.iter_mut().find(|k| k.matches(id)).ok_or(ErrorKind::KeyNotFound)?),
+            None => self.primary_mut(),
+        }
+    }
+
+    /// Make the key with the id `id` the primary key
+    pub fn set_primary(&mut self, id: &str) -> Result<(), RSAError> {
+        let n = self.get(id).ok_or(ErrorKind::KeyNotFound)?.n.clone();
+        self.keys.iter_mut().for_each(|k| k.primary = k.n == n);
+        Ok(())
+    }
+
+    /// All keys, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RSA> {
+        self.keys.iter()
+    }
+
+    // the primary key first, then the rest from newest to oldest
+    fn by_preference(&self) -> impl Iterator<Item = &RSA> {
+        self.keys.iter().filter(|k| k.primary).chain(self.keys.iter().rev().filter(|k| !k.primary))
+    }
+}
+
+const MESSAGE_MAGIC: &[u8] = b"RSAM";
+const MESSAGE_MAGIC_V2: &[u8] = b"RSM2";
+
+/// A message encrypted by `AlgoRSA::encrypt`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The id of the key the message was encrypted to. Messages from before ids were recorded have none
+    pub key_id: Option<String>,
+    /// The length of the plaintext. Older messages have none; their blocks were as long as the modulus and lost
+    /// their leading zero bytes, so only text survives them intact
+    pub length: Option<u64>,
+    pub blocks: Vec<BigUint>,
+}
+
+impl Message {
+    /// The serialized message, as armored by the CLI
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RSAError> {
+        let key_id = self.key_id.as_deref().unwrap_or_default();
+        let mut bytes;
+        match self.length {
+            None if self.key_id.is_none() => bytes = bincode::serialize(&self.blocks)?,
+            Some(length) => {
+                bytes = MESSAGE_MAGIC_V2.to_vec();
+                bincode::serialize_into(&mut bytes, &(key_id, length, &self.blocks))?;
+            },
+            None => {
+                bytes = MESSAGE_MAGIC.to_vec();
+                bincode::serialize_into(&mut bytes, &(key_id, &self.blocks))?;
+            },
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a serialized message, including those from before messages recorded a key id or their length
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RSAError> {
+        if let Some(rest) = bytes.strip_prefix(MESSAGE_MAGIC_V2) {
+            let (key_id, length, blocks): (String, u64, Vec<BigUint>) = bincode::deserialize(rest)?;
+            return Ok(Message { key_id: Some(key_id), length: Some(length), blocks });
+        }
+        match bytes.strip_prefix(MESSAGE_MAGIC) {
+            Some(rest) => {
+                let (key_id, blocks): (String, Vec<BigUint>) = bincode::deserialize(rest)?;
+                Ok(Message { key_id: Some(key_id), length: None, blocks })
+            },
+            None => Ok(Message { key_id: None, length: None, blocks: bincode::deserialize(bytes)? }),
+        }
+    }
 }
 
-pub struct AlgoRSA {
-    db: SimpleDB<HashMap<String, RSA>>,
-    // HashMap extracted from DB
-    map: RefCell<HashMap<String, RSA>>,
+/// A key as described by `AlgoRSA::inspect`, for machine-readable output. Times are seconds since the epoch
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub id: String,
+    /// The SHA-256 fingerprint, in hex
+    pub fingerprint: String,
+    /// The same fingerprint as OpenSSH prints it, `SHA256:` and base64
+    pub fingerprint_base64: String,
+    pub bits: usize,
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub expired: bool,
+    pub revoked_at: Option<u64>,
+    pub revocation_reason: Option<&'static str>,
+    pub usages: Vec<&'static str>,
+    pub primary: bool,
+    /// Whether the private key is in the database
+    pub private: bool,
+    pub comment: Option<String>,
 }
 
-impl AlgoRSA {
+impl KeyInfo {
+    fn new(rsa: &RSA) -> Self {
+        KeyInfo {
+            id: rsa.id(),
+            fingerprint: rsa.fingerprint_hex(),
+            fingerprint_base64: rsa.fingerprint_base64(),
+            bits: rsa.size().as_num(),
+            created: rsa.created(),
+            expires: rsa.expires(),
+            expired: rsa.is_expired(),
+            revoked_at: rsa.revocation().map(|r| r.revoked_at),
+            revocation_reason: rsa.revocation().map(|r| r.reason.as_str()),
+            usages: rsa.usages().iter().map(|u| u.as_str()).collect(),
+            primary: rsa.is_primary(),
+            private: rsa.private_exists(),
+            comment: rsa.comment().map(String::from),
+        }
+    }
+}
+
+/// A user and their keys, as described by `AlgoRSA::inspect`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub user: String,
+    /// `own` or `contact`
+    pub ownership: &'static str,
+    pub trust: &'static str,
+    /// Whether the primary key is ours or its fingerprint was checked with the contact
+    pub verified: bool,
+    /// Whether the primary key is ours, verified, or certified by someone trusted
+    pub valid: bool,
+    pub keys: Vec<KeyInfo>,
+}
+
+/// Verify a signature with a public key that does not need to be in the database
+pub fn verify_signature(n: &BigUint, e: &BigUint, scheme: SignatureScheme, data: &[u8], signature: &[u8]) -> Result<(), RSAError> {
+    let signature = BigUint::from_bytes_be(signature);
+    if n.is_zero() || &signature >= n {
+        Err(ErrorKind::InvalidSignature)?
+    }
+    let em = signature.modpow(e, n);
+    scheme.verify(data, &to_key_length(&em, n.bits()), n.bits())
+}
+
+/// The keyring. Holds the keys of every user in a `KeyStore` and performs all private-key operations
+pub struct AlgoRSA<S: KeyStore = FileStore> {
+    store: RefCell<S>,
+}
 
-    pub fn new(db: SimpleDB<HashMap<String, RSA>>) -> Result<Self, Error> {
-        let map = db.get()?;
-        Ok(AlgoRSA {
-            db,
-            map: RefCell::new(map)
-        })
+impl<S: KeyStore> AlgoRSA<S> {
+
+    /// A keyring over `store`. Changes to buffering stores are only written back by `save_keys`
+    pub fn new(store: S) -> Self {
+        AlgoRSA { store: RefCell::new(store) }
     }
 
     // could extract finding D logic to a different method maybe?
-    fn generate(size: &KeySize) -> Result<RSA, Error> {
-        let multiple_size = size.as_half();
+    fn generate(size: &KeySize) -> Result<RSA, RSAError> {
+        let multiple_size = size.as_half()?;
+        // E is prime, so gcd(E, (p - 1)(q - 1)) == 1 unless E divides p - 1 or q - 1
+        let suitable = |prime: &BigUint| (prime - 1u32) % E != BigUint::zero();
         let mut p = PrimeFinder::find(&multiple_size)?;
+        while !suitable(&p) {
+            p = PrimeFinder::find(&multiple_size)?;
+        }
         let mut q = PrimeFinder::find(&multiple_size)?;
-        loop {
-            if &p % E == BigUint::zero() {
-                p = PrimeFinder::find(&multiple_size)?;
-            }
-
-            if &q % E == BigUint::zero() {
-                q = PrimeFinder::find(&multiple_size)?;
-            }
-
-            if &p % E != BigUint::zero() && &q % E != BigUint::zero() {
-                break;
-            }
+        while !suitable(&q) || q == p {
+            q = PrimeFinder::find(&multiple_size)?;
         }
         let n = &p * &q;
         let phi_n = math::prime_phi(&p, &q);
         let d = math::modinv(&E.into(), &phi_n)?;
 
-        return Ok(RSA { d: Some(d), n, size: size.clone() })
+        Ok(RSA::new(n, Some(d), size.clone()))
     }
 
-    /// Creates a new key and adds it to the Database
-    pub fn create(&self, user: &String, size: &KeySize) -> Result<(), Error> {
-        let rsa = Self::generate(&size)?;
-        self.map.borrow_mut().insert(user.clone(), rsa);
-        Ok(())
+    /// All keys of `user`, or UserNotFound
+    pub fn keys(&self, user: &str) -> Result<UserKeys, RSAError> {
+        Ok(self.store.borrow().get(user)?.ok_or(ErrorKind::UserNotFound)?)
+    }
+
+    // the primary key of `user`
+    fn key(&self, user: &str) -> Result<RSA, RSAError> {
+        Ok(self.keys(user)?.primary()?.clone())
+    }
+
+    fn put(&self, user: &str, keys: UserKeys) -> Result<(), RSAError> {
+        self.store.borrow_mut().put(user, keys)
+    }
+
+    /// Creates a new key and adds it to the Database as the primary key of `user`, keeping any older keys.
+    /// Returns the id of the new key
+    pub fn create(&self, user: &str, size: &KeySize) -> Result<String, RSAError> {
+        let rsa = Self::generate(size)?;
+        let id = rsa.id();
+        let mut keys = self.store.borrow().get(user)?.unwrap_or_default();
+        keys.add(rsa);
+        keys.ownership = Ownership::Own;
+        self.put(user, keys)?;
+        Ok(id)
+    }
+
+    /// Replace the primary key of `user` with a new key of the same size, and revoke the old key as superseded.
+    /// The old key still decrypts. Returns the id of the new key
+    pub fn rotate(&self, user: &str) -> Result<String, RSAError> {
+        let old = self.key(user)?;
+        let id = self.create(user, old.size())?;
+        self.revoke(user, Some(&old.id()), Reason::Superseded)?;
+        Ok(id)
+    }
+
+    /// Decrypt `message` with whichever key of `user` it was encrypted to, and encrypt it again to the primary key
+    pub fn reencrypt(&self, user: &str, message: Message) -> Result<Message, RSAError> {
+        let data = Zeroizing::new(self.decrypt(user, message)?);
+        self.encrypt(user, &data)
+    }
+
+    /// Remove `user` and all of their keys. Returns the keys that were removed
+    pub fn delete(&self, user: &str) -> Result<UserKeys, RSAError> {
+        Ok(self.store.borrow_mut().delete(user)?.ok_or(ErrorKind::UserNotFound)?)
+    }
+
+    /// Move all keys of `user` to the new name `to`, which must not be taken
+    pub fn rename(&self, user: &str, to: &str) -> Result<(), RSAError> {
+        if self.user_exists(to) {
+            Err(ErrorKind::UserExists)?
+        }
+        let keys = self.delete(user)?;
+        self.put(to, keys)
+    }
+
+    /// Add the public keys of `user` under the alias `to`, which must not be taken. Private keys are not copied
+    pub fn copy(&self, user: &str, to: &str) -> Result<(), RSAError> {
+        if self.user_exists(to) {
+            Err(ErrorKind::UserExists)?
+        }
+        let keys = self.keys(user)?;
+        let mut alias = UserKeys::from_keys(keys.iter().map(RSA::to_public).collect());
+        alias.trust = keys.trust;
+        alias.verified = keys.verified;
+        self.put(to, alias)
+    }
+
+    /// Set how far the contact `user` is trusted
+    pub fn set_trust(&self, user: &str, trust: Trust) -> Result<(), RSAError> {
+        let mut keys = self.keys(user)?;
+        keys.trust = trust;
+        self.put(user, keys)
+    }
+
+    /// Record that `fingerprint`, in hex or as printed by `list`, was checked with the contact `user` out of band.
+    /// Fails if it is not the fingerprint of their primary key
+    pub fn verify_contact(&self, user: &str, fingerprint: &str) -> Result<(), RSAError> {
+        let mut keys = self.keys(user)?;
+        let primary = keys.primary()?.fingerprint_hex();
+        let given: String = fingerprint.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+        if given != primary && fingerprint.trim() != keys.primary()?.fingerprint_base64() {
+            Err(ErrorKind::FingerprintMismatch)?
+        }
+        keys.verified = Some(primary);
+        self.put(user, keys)
+    }
+
+    /// Whether the primary key of `user` can be relied on to be theirs because it is ours or was verified
+    pub fn is_verified(&self, user: &str) -> Result<bool, RSAError> {
+        Ok(self.keys(user)?.is_verified())
+    }
+
+    /// Certify with the primary key of `certifier` that the primary key of `user` belongs to them
+    pub fn certify(&self, certifier: &str, user: &str) -> Result<(), RSAError> {
+        let signer = self.key(certifier)?;
+        signer.check(Usage::Certify)?;
+        let mut keys = self.keys(user)?;
+        let key = keys.primary()?.clone();
+        let created = now();
+        let signature = Self::sign_with(&signer, trust::SCHEME, &Certification::signed_data(&key.n, user, created))?;
+        keys.add_certification(Certification::new(&key, user, &signer, created, signature));
+        self.put(user, keys)
+    }
+
+    /// The certifications of the keys of `user`, for export
+    pub fn export_certifications(&self, user: &str) -> Result<Vec<u8>, RSAError> {
+        Certification::to_bytes(self.keys(user)?.certifications())
+    }
+
+    /// Add exported certifications to the keys of `user`. Certifications of keys they do not have are skipped;
+    /// the others are only checked when they are used. Returns how many were new
+    pub fn import_certifications(&self, user: &str, certifications: &[u8]) -> Result<usize, RSAError> {
+        let mut keys = self.keys(user)?;
+        let added = Certification::from_bytes(certifications)?.into_iter()
+            .filter(|certification| keys.add_certification(certification.clone()))
+            .count();
+        self.put(user, keys)?;
+        Ok(added)
+    }
+
+    /// The users through whose certifications the primary key of `user` is valid, starting from one of our own
+    /// keys or a verified contact and ending with `user`. `None` if it is not valid
+    pub fn trust_path(&self, user: &str) -> Result<Option<Vec<String>>, RSAError> {
+        self.keys(user)?;
+        let keyring: Vec<(String, UserKeys)> = self.store.borrow().iter()?.collect();
+        Ok(trust::trust_paths(&keyring).remove(user))
+    }
+
+    /// Whether the primary key of `user` can be relied on to be theirs: it is ours, was verified, or is certified
+    /// by someone we trust
+    pub fn is_valid(&self, user: &str) -> Result<bool, RSAError> {
+        Ok(self.is_verified(user)? || self.trust_path(user)?.is_some())
+    }
+
+    /// Change the primary key of `user` with `edit`, for instance to set its metadata
+    pub fn edit_key<F: FnOnce(&mut RSA)>(&self, user: &str, edit: F) -> Result<(), RSAError> {
+        let mut keys = self.keys(user)?;
+        edit(keys.primary_mut()?);
+        self.put(user, keys)
+    }
+
+    /// Revoke the key of `user` with the id `key_id`, or the primary key, signing the revocation with the key itself.
+    /// A key that was already revoked keeps its revocation if that one takes precedence. Returns the revocation
+    /// certificate in force, for export
+    pub fn revoke(&self, user: &str, key_id: Option<&str>, reason: Reason) -> Result<Vec<u8>, RSAError> {
+        let mut keys = self.keys(user)?;
+        let rsa = keys.get_mut(key_id)?;
+        let revoked_at = now();
+        let signature = Self::sign_with(rsa, revocation::SCHEME, &Revocation::signed_data(&rsa.n, reason, revoked_at))?;
+        let n = rsa.n.clone();
+        let exported = rsa.add_revocation(Revocation::new(reason, revoked_at, signature)).to_bytes(&n)?;
+        self.put(user, keys)?;
+        Ok(exported)
+    }
+
+    /// The revocation certificate of the key of `user` with the id `key_id`, or of the primary key
+    pub fn export_revocation(&self, user: &str, key_id: Option<&str>) -> Result<Vec<u8>, RSAError> {
+        let mut keys = self.keys(user)?;
+        let rsa = keys.get_mut(key_id)?;
+        rsa.revocation.as_ref().ok_or(ErrorKind::RevocationNotFound)?.to_bytes(&rsa.n)
+    }
+
+    /// Check a revocation certificate and mark the key it revokes as revoked, unless it already carries a
+    /// revocation that takes precedence. Returns the user of the key
+    pub fn import_revocation(&self, certificate: &[u8]) -> Result<String, RSAError> {
+        let (n, revocation) = Revocation::from_bytes(certificate)?;
+        revocation.verify(&n)?;
+        let user = self.find_public(&n)?;
+        let mut keys = self.keys(&user)?;
+        let rsa = keys.keys.iter_mut().find(|k| k.n == n).ok_or(ErrorKind::KeyNotFound)?;
+        rsa.add_revocation(revocation);
+        self.put(&user, keys)?;
+        Ok(user)
+    }
+
+    /// Make the key with the id `key_id` the one `user` encrypts and signs with
+    pub fn set_primary(&self, user: &str, key_id: &str) -> Result<(), RSAError> {
+        let mut keys = self.keys(user)?;
+        keys.set_primary(key_id)?;
+        self.put(user, keys)
     }
     // 5.Alice encrypts m as c = m^e (mod n) and sends c to bob
     // 6. Bob decrypts by computing m = c^d (mod n)
     //
     // User here is the user the message is being encrypted for
     //accepts a message *as bytes* allowing for anything to be encrypted
-    pub fn encrypt(&self, user: &String, data: &[u8]) -> Result<Vec<BigUint>, Error> {
+    pub fn encrypt(&self, user: &str, data: &[u8]) -> Result<Message, RSAError> {
         // TODO: change so base64 is only used once
-        if let Some(rsa) = self.map.borrow().get(user) {
-            let mut encrypted = Vec::new();
-            for block in data.chunks(Self::chunk_size(rsa.size())) {
-                let num = BigUint::from_bytes_be(block).modpow(&E.into(), rsa.public());
-                encrypted.push(num)
+        let rsa = self.key(user)?;
+        rsa.check(Usage::Encrypt)?;
+        let mut encrypted = Vec::new();
+        for block in data.chunks(Self::chunk_size(rsa.size())) {
+            let num = BigUint::from_bytes_be(block).modpow(&E.into(), rsa.modulus()?);
+            encrypted.push(num)
+        }
+        Ok(Message { key_id: Some(rsa.id()), length: Some(data.len() as u64), blocks: encrypted })
+    }
+
+    /// Decrypt a message to `user` with the key it was encrypted to. Messages that do not say which key
+    /// predate users having several keys, so they are decrypted with the oldest key
+    pub fn decrypt(&self, user: &str, message: Message) -> Result<Vec<u8>, RSAError> {
+        let keys = self.keys(user)?;
+        let rsa = match &message.key_id {
+            Some(id) => keys.get(id).ok_or(ErrorKind::KeyNotFound)?,
+            None => keys.iter().next().ok_or(ErrorKind::UserNotFound)?,
+        };
+        let (private, modulus) = (rsa.private()?, rsa.modulus()?);
+        // the length comes from the message, so it has to fit the blocks before anything is sized by it
+        let chunk_size = Self::chunk_size(rsa.size());
+        let fits = |length: u64| {
+            let full = message.blocks.len() as u64 * chunk_size as u64;
+            length <= full && full - length < chunk_size as u64
+        };
+        if message.length.is_some_and(|length| !fits(length)) {
+            Err(ErrorKind::Decryption)?
+        }
+        let blocks: Vec<Vec<u8>> = message.blocks.into_par_iter().map(|data_chunk| {
+            data_chunk.modpow(private, modulus).to_bytes_be()
+        }).collect();
+        let length = match message.length {
+            Some(length) => length as usize,
+            None => return Ok(blocks.concat()),
+        };
+        // every block but the last is a full chunk; put back the leading zeros the numbers dropped
+        let mut data = Vec::with_capacity(length);
+        for (i, block) in blocks.iter().enumerate() {
+            let size = chunk_size.min(length.saturating_sub(i * chunk_size));
+            if block.len() > size {
+                Err(ErrorKind::Decryption)?
             }
-            Ok(encrypted)
-        } else {
-            return Err(ErrorKind::UserNotFound)?;
+            data.resize(data.len() + size - block.len(), 0);
+            data.extend_from_slice(block);
         }
+        if data.len() != length {
+            Err(ErrorKind::Decryption)?
+        }
+        Ok(data)
+    }
+
+    /// Sign `data` with the private key of `user`, returning a signature as long as the modulus
+    pub fn sign(&self, user: &str, scheme: SignatureScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        self.sign_as(user, Usage::Sign, scheme, data)
+    }
+
+    /// Sign like `sign`, with a key that allows `usage`, such as `Certify` for certificates
+    pub fn sign_as(&self, user: &str, usage: Usage, scheme: SignatureScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let rsa = self.key(user)?;
+        rsa.check(usage)?;
+        Self::sign_with(&rsa, scheme, data)
+    }
+
+    fn sign_with(rsa: &RSA, scheme: SignatureScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let em = scheme.encode(data, rsa.bits())?;
+        let signature = BigUint::from_bytes_be(&em).modpow(rsa.private()?, rsa.modulus()?);
+        Ok(to_key_length(&signature, rsa.bits()))
     }
 
-    pub fn decrypt(&self, user: &String, data: Vec<BigUint>) -> Result<Vec<u8>, Error> {
-        if let Some(rsa) = self.map.borrow().get(user) {
-            let private = rsa.private()?;
-            Ok(data.into_par_iter().map(|data_chunk| {
-                data_chunk.modpow(private, rsa.public()).to_bytes_be()
-            }).flatten().collect())
-        } else {
-            return Err(ErrorKind::UserNotFound)?;
+    /// Verify a signature over `data` made by any of the keys of `user`
+    pub fn verify(&self, user: &str, scheme: SignatureScheme, data: &[u8], signature: &[u8]) -> Result<(), RSAError> {
+        self.verify_at(user, scheme, data, signature, None)
+    }
+
+    /// Verify like `verify`, for a signature made at `signed_at` if the format records when. Fails with
+    /// `KeyRevoked` if the key that made the signature was revoked in a way that invalidates it
+    pub fn verify_at(&self, user: &str, scheme: SignatureScheme, data: &[u8], signature: &[u8], signed_at: Option<u64>) -> Result<(), RSAError> {
+        self.verify_key_at(user, None, scheme, data, signature, signed_at)
+    }
+
+    /// Verify like `verify_at`, but only with the key of `user` that `key_id` names, if one is given
+    pub fn verify_key_at(&self, user: &str, key_id: Option<&str>, scheme: SignatureScheme, data: &[u8], signature: &[u8], signed_at: Option<u64>) -> Result<(), RSAError> {
+        if self.signing_key(user, key_id, scheme, data, signature)?.revocation().is_some_and(|r| r.invalidates(signed_at)) {
+            Err(ErrorKind::KeyRevoked)?
         }
+        Ok(())
     }
 
-    fn chunk_size(key_size: &KeySize) -> usize {
-        ((key_size.as_num()) / 8)
+    /// The revocation of the key of `user` that made `signature`, if that key was revoked
+    pub fn signature_revocation(&self, user: &str, scheme: SignatureScheme, data: &[u8], signature: &[u8]) -> Result<Option<Revocation>, RSAError> {
+        Ok(self.signing_key(user, None, scheme, data, signature)?.revocation)
     }
 
-    pub fn import(&self, user: &str, opts: RSA) {
-        // if user already exists in DB, we might only want to add the private key
-        if opts.private_exists() {
-            if let Some(rsa) = self.map.borrow_mut().get_mut(user) { // if the user already exists in the DB
-                (*rsa).d = opts.d;
-                (*rsa).n = opts.n;
-                (*rsa).size = opts.size;
+    fn signing_key(&self, user: &str, key_id: Option<&str>, scheme: SignatureScheme, data: &[u8], signature: &[u8]) -> Result<RSA, RSAError> {
+        let mut error = ErrorKind::InvalidSignature.into();
+        for rsa in self.keys(user)?.by_preference().filter(|k| key_id.is_none_or(|id| k.matches(id))) {
+            match verify_signature(rsa.public(), &E.into(), scheme, data, signature) {
+                Ok(()) => return Ok(rsa.clone()),
+                Err(e) => error = e,
             }
-        } else {
-            self.map.borrow_mut().insert(user.to_string(), opts);
         }
+        Err(error)
+    }
+
+    /// Encrypt a single padded block (such as a symmetric key) to `user`
+    pub fn encrypt_block(&self, user: &str, scheme: EncryptionScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let rsa = self.key(user)?;
+        rsa.check(Usage::Encrypt)?;
+        let em = scheme.encode(data, rsa.bits().div_ceil(8))?;
+        let encrypted = BigUint::from_bytes_be(&em).modpow(&E.into(), rsa.modulus()?);
+        Ok(to_key_length(&encrypted, rsa.bits()))
     }
 
-    pub fn import_private(&self, user: &str, private_key: &BigUint) -> Result<(), Error> {
-        if self.map.borrow().contains_key(user) {
-            if let Some(rsa) = self.map.borrow_mut().get_mut(user) {
-                (*rsa).d = Some(private_key.to_owned());
+    /// Decrypt a single padded block encrypted to any of the private keys of `user`
+    pub fn decrypt_block(&self, user: &str, scheme: EncryptionScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let mut result = Err(ErrorKind::PrivateKeyNotFound.into());
+        for rsa in self.keys(user)?.by_preference().filter(|k| k.private_exists()) {
+            // the padding check tells the right key from the wrong ones
+            result = Self::decrypt_block_with(rsa, scheme, data);
+            if result.is_ok() {
+                break;
             }
-        } else {
-            Err(ErrorKind::ImportOrder)?
         }
-        Ok(())
+        result
     }
 
-    pub fn export(&self, user: &str, key: KeyType) -> Result<String, Error> {
-        if let Some(rsa) = self.map.borrow().get(user) {
-            match key {
-                KeyType::Private => Ok(base64::encode(&rsa.private()?.to_bytes_be())),
-                KeyType::Public => Ok(base64::encode(&rsa.public().to_bytes_be()))
-            }
-        } else {
-            Err(ErrorKind::UserNotFound)?
+    fn decrypt_block_with(rsa: &RSA, scheme: EncryptionScheme, data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let encrypted = BigUint::from_bytes_be(data);
+        if &encrypted >= rsa.public() {
+            Err(ErrorKind::Decryption)?
+        }
+        let em = encrypted.modpow(rsa.private()?, rsa.modulus()?);
+        scheme.decode(&to_key_length(&em, rsa.bits()), rsa.bits().div_ceil(8))
+    }
+
+    // a byte shorter than the modulus, so that every block is smaller than it
+    fn chunk_size(key_size: &KeySize) -> usize {
+        (key_size.as_num()) / 8 - 1
+    }
+
+    /// Add `opts` to the keys of `user` as the primary key. If the user already has this key, only its private
+    /// exponent is taken, if it has one
+    pub fn import(&self, user: &str, opts: RSA) -> Result<(), RSAError> {
+        let mut keys = match self.store.borrow().get(user)? {
+            Some(keys) => keys,
+            None => UserKeys::from_keys(Vec::new()),
+        };
+        if opts.private_exists() {
+            keys.ownership = Ownership::Own;
         }
+        match keys.keys.iter_mut().find(|rsa| rsa.n == opts.n) {
+            Some(rsa) => {
+                if opts.private_exists() {
+                    rsa.d = opts.d;
+                }
+            },
+            None => keys.add(opts),
+        }
+        self.put(user, keys)
+    }
+
+    /// Add the private exponent of the primary key of `user`
+    pub fn import_private(&self, user: &str, private_key: &BigUint) -> Result<(), RSAError> {
+        let mut keys = self.store.borrow().get(user)?.ok_or(ErrorKind::ImportOrder)?;
+        keys.primary_mut()?.d = Some(private_key.to_owned());
+        keys.ownership = Ownership::Own;
+        self.put(user, keys)
+    }
+
+    pub fn export(&self, user: &str, key: KeyType) -> Result<String, RSAError> {
+        let rsa = self.key(user)?;
+        match key {
+            KeyType::Private => Ok(base64::encode(&rsa.private()?.to_bytes_be())),
+            KeyType::Public => Ok(base64::encode(&rsa.public().to_bytes_be()))
+        }
+    }
+
+    /// The DER-encoded certificate stored for `user`
+    pub fn certificate(&self, user: &str) -> Result<Vec<u8>, RSAError> {
+        Ok(self.key(user)?.certificate.ok_or(ErrorKind::CertificateNotFound)?)
+    }
+
+    /// Store a DER-encoded certificate for the primary key of `user`. The caller is responsible for checking that
+    /// it certifies that key
+    pub fn set_certificate(&self, user: &str, certificate: Vec<u8>) -> Result<(), RSAError> {
+        let mut keys = self.keys(user)?;
+        keys.primary_mut()?.certificate = Some(certificate);
+        self.put(user, keys)
+    }
+
+    /// The public modulus of the primary key of `user`
+    pub fn public_key(&self, user: &str) -> Result<BigUint, RSAError> {
+        Ok(self.key(user)?.n)
+    }
+
+    /// Find the user with a key whose public modulus is `n`
+    pub fn find_public(&self, n: &BigUint) -> Result<String, RSAError> {
+        self.store.borrow().iter()?
+            .find(|(_, keys)| keys.iter().any(|rsa| rsa.public() == n))
+            .map(|(user, _)| user)
+            .ok_or(ErrorKind::KeyNotFound)
+            .map_err(Into::into)
+    }
+
+    /// All users in the database
+    pub fn users(&self) -> Result<Vec<String>, RSAError> {
+        let mut users = self.store.borrow().list()?;
+        users.sort();
+        Ok(users)
+    }
+
+    /// Whether the private half of the primary key of `user` is in the database
+    pub fn private_exists(&self, user: &str) -> bool {
+        self.key(user).map(|rsa| rsa.private_exists()).unwrap_or(false)
     }
 
     // if the user exists, the private key must exist
     pub fn user_exists(&self, user: &str) -> bool {
-        self.map.borrow().contains_key(user)
+        self.key(user).is_ok()
     }
 
-    pub fn list(&self) -> Result<String, Error> {
+    pub fn list(&self) -> Result<String, RSAError> {
         let mut list = String::new();
-        list.push_str(&format!("{}\n", self.db.file_path().canonicalize()?.to_str().unwrap()));
+        list.push_str(&format!("{}\n", self.store.borrow().location()));
         list.push_str("------------------------------------------\n");
-        for (user, rsa) in self.map.borrow().iter() {
-            list.push_str(&format!("{}: rsa{}/{}\n", user, rsa.size().as_string(), self.public_identifier(rsa)));
+        for (user, keys) in self.store.borrow().iter()? {
+            let several = keys.iter().count() > 1;
+            for rsa in keys.iter() {
+                list.push_str(&format!("{}: rsa{}/{}", user, rsa.size().as_string(), rsa.id()));
+                if let Some(created) = rsa.created().and_then(|c| chrono::DateTime::from_timestamp(c as i64, 0)) {
+                    list.push_str(&format!(" created {}", created.format("%Y-%m-%d")));
+                }
+                if let Some(expires) = rsa.expires().and_then(|e| chrono::DateTime::from_timestamp(e as i64, 0)) {
+                    let verb = if rsa.is_expired() { "expired" } else { "expires" };
+                    list.push_str(&format!(" {} {}", verb, expires.format("%Y-%m-%d")));
+                }
+                if let Some(revocation) = rsa.revocation().and_then(|r| Some((r, chrono::DateTime::from_timestamp(r.revoked_at as i64, 0)?))) {
+                    list.push_str(&format!(" revoked {} {}", revocation.1.format("%Y-%m-%d"), revocation.0.reason.as_str()));
+                }
+                if rsa.usages().len() < Usage::ALL.len() {
+                    let usages: Vec<&str> = rsa.usages().iter().map(|u| u.as_str()).collect();
+                    list.push_str(&format!(" usage {}", usages.join(",")));
+                }
+                if several && rsa.is_primary() {
+                    list.push_str(" [primary]");
+                }
+                if keys.ownership() == Ownership::Contact {
+                    let verified = if keys.is_verified() {
+                        ", verified"
+                    } else if keys.verified.is_some() {
+                        ", verified for an older key"
+                    } else {
+                        ""
+                    };
+                    list.push_str(&format!(" [contact, trust {}{}]", keys.trust().as_str(), verified));
+                }
+                if let Some(comment) = rsa.comment() {
+                    list.push_str(&format!(" ({})", comment));
+                }
+                list.push_str(&format!("\n    {}\n", rsa.fingerprint_base64()));
+            }
         }
         Ok(list)
     }
 
-    fn public_identifier(&self, rsa: &RSA) -> String {
-        let key = base64::encode(rsa.public().to_bytes_be().as_slice());
-        key[0..16].to_string().to_ascii_uppercase()
+    /// `user` and their keys, for machine-readable output
+    pub fn inspect(&self, user: &str) -> Result<UserInfo, RSAError> {
+        let keys = self.keys(user)?;
+        Ok(Self::user_info(user, &keys, self.is_valid(user)?))
+    }
+
+    /// Every user and their keys, sorted by name
+    pub fn inspect_all(&self) -> Result<Vec<UserInfo>, RSAError> {
+        let mut keyring: Vec<(String, UserKeys)> = self.store.borrow().iter()?.collect();
+        keyring.sort_by(|a, b| a.0.cmp(&b.0));
+        let paths = trust::trust_paths(&keyring);
+        Ok(keyring.iter()
+            .map(|(user, keys)| Self::user_info(user, keys, keys.is_verified() || paths.contains_key(user)))
+            .collect())
+    }
+
+    fn user_info(user: &str, keys: &UserKeys, valid: bool) -> UserInfo {
+        UserInfo {
+            user: user.to_string(),
+            ownership: keys.ownership().as_str(),
+            trust: keys.trust().as_str(),
+            verified: keys.is_verified(),
+            valid,
+            keys: keys.iter().map(KeyInfo::new).collect(),
+        }
+    }
+
+    /// Short identifier of the primary key of `user`, as shown by `list`
+    pub fn key_id(&self, user: &str) -> Result<String, RSAError> {
+        Ok(self.key(user)?.id())
+    }
+
+    /// Find the user with a key that `key_id` names, as a key id, fingerprint or prefix of one
+    pub fn find_key_id(&self, key_id: &str) -> Result<String, RSAError> {
+        let mut users = self.store.borrow().iter()?
+            .filter(|(_, keys)| keys.get(key_id).is_some())
+            .map(|(user, _)| user);
+        let user = users.next().ok_or(ErrorKind::KeyNotFound)?;
+        if users.next().is_some() {
+            Err(ErrorKind::AmbiguousKeyId)?
+        }
+        Ok(user)
+    }
+
+    /// Write the changes of buffering stores back now, before acting on them outside the keyring
+    pub fn flush(&self) -> Result<(), RSAError> {
+        self.store.borrow_mut().flush()
     }
 
     // consumes self, saving data to our database.
     // Should be used at the end of the program
-    pub fn save_keys(self) -> Result<(), Error> {
-        let map = self.map.into_inner();
-        self.db.save(map)?;
-        Ok(())
+    pub fn save_keys(self) -> Result<(), RSAError> {
+        self.store.into_inner().flush()
+    }
+
+    /// The store, for operations specific to one kind of store. Changes are not flushed
+    pub fn into_store(self) -> S {
+        self.store.into_inner()
+    }
+}
+
+// I2OSP: big-endian bytes left-padded with zeros to the length of the modulus
+fn to_key_length(num: &BigUint, bits: usize) -> Vec<u8> {
+    let bytes = num.to_bytes_be();
+    let k = bits.div_ceil(8);
+    let mut out = vec![0u8; k.saturating_sub(bytes.len())];
+    out.extend(bytes);
+    out
+}
+
+// the key id before there were fingerprints: the start of the modulus in base64, which is nearly the same for every key
+fn legacy_identifier(rsa: &RSA) -> String {
+    let key = base64::encode(rsa.public().to_bytes_be().as_slice());
+    key.chars().take(16).collect::<String>().to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rsa::HashAlgorithm;
+    use crate::keystore::MemoryStore;
+    use std::path::PathBuf;
+
+    #[test]
+    fn should_not_create_256_bit_keys() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        assert_eq!(rsa.create("tiny", &KeySize::TwoFiftySix).unwrap_err().kind(), ErrorKind::InvalidKeyLength);
+    }
+
+    #[test]
+    fn should_refuse_tiny_moduli() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        // the textbook key n = 61 * 53, d = 2753
+        rsa.import("tiny", RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve)).unwrap();
+        rsa.import_private("tiny", &BigUint::from(2753u32)).unwrap();
+        let pss = SignatureScheme::Pss(HashAlgorithm::Sha256);
+        assert_eq!(rsa.sign("tiny", pss, b"data").unwrap_err().kind(), ErrorKind::KeyTooSmall);
+        assert_eq!(rsa.sign("tiny", SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256), b"data").unwrap_err().kind(), ErrorKind::KeyTooSmall);
+        assert!(rsa.verify("tiny", pss, b"data", &[1, 2]).is_err());
+        assert_eq!(rsa.encrypt_block("tiny", EncryptionScheme::Pkcs1v15, b"key").unwrap_err().kind(), ErrorKind::MessageTooLong);
+        assert!(rsa.decrypt_block("tiny", EncryptionScheme::Pkcs1v15, &[1, 2]).is_err());
+
+        rsa.import("zero", RSA::new(BigUint::zero(), None, KeySize::FiveTwelve)).unwrap();
+        assert_eq!(rsa.encrypt("zero", b"data").unwrap_err().kind(), ErrorKind::KeyTooSmall);
+        assert!(rsa.encrypt_block("zero", EncryptionScheme::Pkcs1v15, b"key").is_err());
+        assert!(rsa.verify("zero", pss, b"data", &[]).is_err());
+        assert!(rsa.key_id("zero").is_ok());
+    }
+
+    #[test]
+    fn should_identify_keys_by_fingerprint_and_key_id() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.import("tiny", RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve)).unwrap();
+        // sha256 of the DER SubjectPublicKeyInfo, as `openssl pkey -pubin -outform DER | sha256sum` prints it
+        let key = rsa.keys("tiny").unwrap().primary().unwrap().clone();
+        assert_eq!(key.fingerprint_hex(), "D5D3062149E14B313800BD39051130B0BE28626A1DA76E5AE871FD6BA081E413");
+        assert_eq!(rsa.key_id("tiny").unwrap(), "D5D3062149E14B31");
+        for id in &["d5d30621", "0xD5D3062149E14B31", "D5D3:0621:49E1", &key.fingerprint_base64(), "DKE="] {
+            assert!(key.matches(id), "{}", id);
+            assert_eq!(rsa.find_key_id(id).unwrap(), "tiny");
+        }
+        // too short to be told apart from other keys
+        assert!(!key.matches("D5D3"));
+        assert_eq!(rsa.find_key_id("D5D3").unwrap_err().kind(), ErrorKind::KeyNotFound);
+        assert_eq!(rsa.find_key_id("0123456789ABCDEF").unwrap_err().kind(), ErrorKind::KeyNotFound);
+
+        rsa.copy("tiny", "alias").unwrap();
+        assert_eq!(rsa.find_key_id("D5D3062149E14B31").unwrap_err().kind(), ErrorKind::AmbiguousKeyId);
+    }
+
+    #[test]
+    fn should_keep_old_keys_when_generating_new_ones() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        let old = rsa.create("alice", &KeySize::FiveTwelve).unwrap();
+        let archived = rsa.encrypt("alice", b"archive").unwrap();
+        let signature = rsa.sign("alice", SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256), b"signed").unwrap();
+        let new = rsa.create("alice", &KeySize::FiveTwelve).unwrap();
+
+        assert_eq!(rsa.key_id("alice").unwrap(), new);
+        assert_eq!(rsa.encrypt("alice", b"fresh").unwrap().key_id, Some(new.clone()));
+        assert_eq!(rsa.decrypt("alice", archived.clone()).unwrap(), b"archive");
+        let restored = Message::from_bytes(&archived.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored, archived);
+        rsa.verify("alice", SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256), b"signed", &signature).unwrap();
+
+        rsa.set_primary("alice", &old).unwrap();
+        assert_eq!(rsa.key_id("alice").unwrap(), old);
+        assert_eq!(rsa.set_primary("alice", "NOPE").unwrap_err().kind(), ErrorKind::KeyNotFound);
+        let unknown = Message { key_id: Some("NOPE".to_string()), length: archived.length, blocks: archived.blocks.clone() };
+        assert_eq!(rsa.decrypt("alice", unknown).unwrap_err().kind(), ErrorKind::KeyNotFound);
+    }
+
+    #[test]
+    fn should_refuse_expired_keys_and_disallowed_usages() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("carol", &KeySize::FiveTwelve).unwrap();
+        let scheme = SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256);
+        let signature = rsa.sign("carol", scheme, b"data").unwrap();
+
+        rsa.edit_key("carol", |key| key.set_usages(vec![Usage::Encrypt])).unwrap();
+        assert_eq!(rsa.sign("carol", scheme, b"data").unwrap_err().kind(), ErrorKind::KeyUsageNotAllowed);
+        assert!(rsa.encrypt("carol", b"data").is_ok());
+
+        rsa.edit_key("carol", |key| {
+            key.set_usages(Usage::ALL.to_vec());
+            key.set_expires(Some(1));
+            key.set_comment(Some("laptop".to_string()));
+        }).unwrap();
+        assert_eq!(rsa.encrypt("carol", b"data").unwrap_err().kind(), ErrorKind::KeyExpired);
+        assert_eq!(rsa.sign("carol", scheme, b"data").unwrap_err().kind(), ErrorKind::KeyExpired);
+        // what was made before the key expired still verifies
+        rsa.verify("carol", scheme, b"data", &signature).unwrap();
+        assert!(rsa.list().unwrap().contains("expired 1970-01-01 (laptop)"));
+    }
+
+    #[test]
+    fn should_revoke_keys_and_carry_revocations_to_other_keyrings() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("dave", &KeySize::FiveTwelve).unwrap();
+        let scheme = SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256);
+        let signature = rsa.sign("dave", scheme, b"data").unwrap();
+        let contacts = AlgoRSA::new(MemoryStore::new());
+        contacts.import("dave", RSA::new(rsa.public_key("dave").unwrap(), None, KeySize::FiveTwelve)).unwrap();
+
+        let certificate = rsa.revoke("dave", None, Reason::Superseded).unwrap();
+        assert_eq!(rsa.encrypt("dave", b"data").unwrap_err().kind(), ErrorKind::KeyRevoked);
+        assert_eq!(rsa.sign("dave", scheme, b"data").unwrap_err().kind(), ErrorKind::KeyRevoked);
+        assert_eq!(rsa.export_revocation("dave", None).unwrap(), certificate);
+
+        // superseded keys keep the signatures they made before the revocation
+        contacts.verify("dave", scheme, b"data", &signature).unwrap();
+        assert_eq!(contacts.import_revocation(&certificate).unwrap(), "dave");
+        assert_eq!(contacts.encrypt("dave", b"data").unwrap_err().kind(), ErrorKind::KeyRevoked);
+        contacts.verify("dave", scheme, b"data", &signature).unwrap();
+        assert_eq!(contacts.verify_at("dave", scheme, b"data", &signature, Some(u64::MAX)).unwrap_err().kind(), ErrorKind::KeyRevoked);
+
+        let compromised = rsa.revoke("dave", None, Reason::Compromised).unwrap();
+        contacts.import_revocation(&compromised).unwrap();
+        assert_eq!(contacts.verify("dave", scheme, b"data", &signature).unwrap_err().kind(), ErrorKind::KeyRevoked);
+        assert_eq!(contacts.signature_revocation("dave", scheme, b"data", &signature).unwrap().unwrap().reason, Reason::Compromised);
+
+        // a soft revocation never replaces a hard one, whether made here or imported
+        assert_eq!(rsa.revoke("dave", None, Reason::Retired).unwrap(), compromised);
+        contacts.import_revocation(&certificate).unwrap();
+        assert_eq!(contacts.export_revocation("dave", None).unwrap(), compromised);
+
+        // a revocation has to be signed by the key it revokes
+        let (n, _) = Revocation::from_bytes(&compromised).unwrap();
+        let forged = Revocation::new(Reason::Compromised, 0, vec![0; 64]).to_bytes(&n).unwrap();
+        assert_eq!(contacts.import_revocation(&forged).unwrap_err().kind(), ErrorKind::InvalidSignature);
+    }
+
+    #[test]
+    fn should_delete_rename_and_copy_users() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("frank", &KeySize::FiveTwelve).unwrap();
+        rsa.create("grace", &KeySize::FiveTwelve).unwrap();
+        assert_eq!(rsa.rename("frank", "grace").unwrap_err().kind(), ErrorKind::UserExists);
+        rsa.rename("frank", "francis").unwrap();
+        assert!(!rsa.user_exists("frank"));
+        assert!(rsa.private_exists("francis"));
+
+        rsa.copy("francis", "fran").unwrap();
+        assert_eq!(rsa.public_key("fran").unwrap(), rsa.public_key("francis").unwrap());
+        assert!(!rsa.private_exists("fran"));
+        assert_eq!(rsa.copy("francis", "fran").unwrap_err().kind(), ErrorKind::UserExists);
+
+        rsa.delete("francis").unwrap();
+        assert_eq!(rsa.delete("francis").unwrap_err().kind(), ErrorKind::UserNotFound);
+        assert_eq!(rsa.users().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn should_tell_contacts_from_own_keys_and_verify_them() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("henry", &KeySize::FiveTwelve).unwrap();
+        let key = rsa.keys("henry").unwrap().primary().unwrap().to_public();
+        rsa.import("ida", key.clone()).unwrap();
+        let ida = rsa.keys("ida").unwrap();
+        assert_eq!((ida.ownership(), ida.trust()), (Ownership::Contact, Trust::Unknown));
+        assert!(rsa.is_verified("henry").unwrap());
+        assert!(!rsa.is_verified("ida").unwrap());
+        rsa.set_trust("ida", Trust::Marginal).unwrap();
+        assert!(!rsa.is_verified("ida").unwrap());
+
+        assert_eq!(rsa.verify_contact("ida", "D5D3062149E14B31").unwrap_err().kind(), ErrorKind::FingerprintMismatch);
+        rsa.verify_contact("ida", &key.fingerprint_hex().to_ascii_lowercase()).unwrap();
+        assert!(rsa.is_verified("ida").unwrap());
+        assert!(rsa.list().unwrap().contains("[contact, trust marginal, verified]"));
+        let info = rsa.inspect("ida").unwrap();
+        assert_eq!((info.ownership, info.trust, info.verified, info.valid), ("contact", "marginal", true, true));
+        // trusting them more does not undo the verification, nor does verifying them change the trust
+        rsa.set_trust("ida", Trust::Full).unwrap();
+        rsa.verify_contact("ida", &key.fingerprint_hex()).unwrap();
+        assert_eq!((rsa.is_verified("ida").unwrap(), rsa.keys("ida").unwrap().trust()), (true, Trust::Full));
+        assert_eq!(info.keys[0].fingerprint, key.fingerprint_hex());
+        assert!(!info.keys[0].private);
+        let users: Vec<String> = rsa.inspect_all().unwrap().into_iter().map(|info| info.user).collect();
+        assert_eq!(users, ["henry", "ida"]);
+
+        // a new key has to be verified again
+        rsa.import("ida", RSA::new(BigUint::from(3233u32), None, KeySize::FiveTwelve)).unwrap();
+        assert!(!rsa.is_verified("ida").unwrap());
+        assert!(rsa.list().unwrap().contains("[contact, trust full, verified for an older key]"));
+        rsa.import_private("ida", &BigUint::from(2753u32)).unwrap();
+        assert_eq!(rsa.keys("ida").unwrap().ownership(), Ownership::Own);
+    }
+
+    #[test]
+    fn should_rotate_keys_and_reencrypt_old_messages() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        let old = rsa.create("erin", &KeySize::FiveTwelve).unwrap();
+        let message = rsa.encrypt("erin", b"archived").unwrap();
+
+        let new = rsa.rotate("erin").unwrap();
+        let keys = rsa.keys("erin").unwrap();
+        assert_eq!(keys.primary().unwrap().id(), new);
+        assert_eq!(keys.get(&old).unwrap().revocation().unwrap().reason, Reason::Superseded);
+
+        let message = rsa.reencrypt("erin", message).unwrap();
+        assert_eq!(message.key_id.as_deref(), Some(new.as_str()));
+        assert_eq!(rsa.decrypt("erin", message).unwrap(), b"archived");
+    }
+
+    #[test]
+    fn should_round_trip_binary_data() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("bob", &KeySize::FiveTwelve).unwrap();
+        // blocks of 0xff used to be larger than the modulus, and leading zeros of a block were lost
+        let data: Vec<u8> = (0..500).map(|i| if i % 63 < 3 || i > 490 { 0 } else { 0xff }).collect();
+        let message = Message::from_bytes(&rsa.encrypt("bob", &data).unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(message.length, Some(500));
+        assert_eq!(rsa.decrypt("bob", message).unwrap(), data);
+        assert_eq!(rsa.decrypt("bob", rsa.encrypt("bob", b"").unwrap()).unwrap(), b"");
+    }
+
+    #[test]
+    fn should_refuse_lengths_that_do_not_fit_the_blocks() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("bob", &KeySize::FiveTwelve).unwrap();
+        let message = rsa.encrypt("bob", &[7; 100]).unwrap();
+        assert_eq!(message.blocks.len(), 2);
+        for length in [0, 63, 127, u64::MAX] {
+            let forged = Message { length: Some(length), ..message.clone() };
+            assert_eq!(rsa.decrypt("bob", forged).unwrap_err().kind(), ErrorKind::Decryption);
+        }
+        assert_eq!(rsa.decrypt("bob", message).unwrap(), [7; 100]);
+    }
+
+    #[test]
+    fn should_decrypt_messages_without_a_key_id_with_the_oldest_key() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("bob", &KeySize::FiveTwelve).unwrap();
+        let message = rsa.encrypt("bob", b"legacy").unwrap();
+        let legacy = bincode::serialize(&message.blocks).unwrap();
+        rsa.create("bob", &KeySize::FiveTwelve).unwrap();
+        let parsed = Message::from_bytes(&legacy).unwrap();
+        assert_eq!(parsed.key_id, None);
+        assert_eq!(rsa.decrypt("bob", parsed).unwrap(), b"legacy");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_list_databases_with_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(b"/tmp/RSA_\xff_TEST"));
+        let rsa = AlgoRSA::new(FileStore::open(path).unwrap());
+        assert!(rsa.list().unwrap().starts_with("/tmp/RSA_"));
     }
 }
@@ -0,0 +1,332 @@
+//! Signature Padding Schemes (EMSA-PKCS1-v1_5 and EMSA-PSS from RFC 8017)
+//! Raw RSA on a digest is not a signature; these encodings are what make the output of
+//! `m^d (mod n)` interoperable with other implementations (JWS, CMS, SSH, ...)
+use crate::err::{ErrorKind, RSAError};
+use rand::Rng;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+/// Hash functions that may be used within a signature
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Sha384 => Sha384::digest(data).to_vec(),
+            HashAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+
+    /// length of the digest in bytes
+    pub fn output_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha384 => 48,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    /// DER encoding of the DigestInfo prefix (AlgorithmIdentifier + OCTET STRING header) from RFC 8017 Section 9.2
+    fn digest_info_prefix(self) -> &'static [u8] {
+        match self {
+            HashAlgorithm::Sha256 => &[0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20],
+            HashAlgorithm::Sha384 => &[0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05, 0x00, 0x04, 0x30],
+            HashAlgorithm::Sha512 => &[0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40],
+        }
+    }
+}
+
+/// How a message is encoded before being signed with the private exponent
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// RSASSA-PKCS1-v1_5
+    Pkcs1v15(HashAlgorithm),
+    /// RSASSA-PSS with MGF1 using the same hash, and a salt as long as the digest
+    Pss(HashAlgorithm),
+}
+
+impl SignatureScheme {
+    /// Encode `data` into a message representative of `mod_bits` bits, ready for `m^d (mod n)`
+    /// The result is always exactly as many bytes as the modulus
+    pub fn encode(self, data: &[u8], mod_bits: usize) -> Result<Vec<u8>, RSAError> {
+        let k = mod_bits.div_ceil(8);
+        match self {
+            SignatureScheme::Pkcs1v15(hash) => pkcs1v15_encode(hash, &hash.digest(data), k),
+            SignatureScheme::Pss(hash) => {
+                let em = pss_encode(hash, &hash.digest(data), mod_bits.saturating_sub(1))?;
+                // emLen may be one shorter than k when modBits - 1 is a multiple of 8
+                let mut out = vec![0u8; k - em.len()];
+                out.extend(em);
+                Ok(out)
+            }
+        }
+    }
+
+    /// Check that `em` (the signature raised to `e`, as `k` bytes) is a valid encoding of `data`
+    pub fn verify(self, data: &[u8], em: &[u8], mod_bits: usize) -> Result<(), RSAError> {
+        let k = mod_bits.div_ceil(8);
+        if em.len() != k {
+            Err(ErrorKind::InvalidSignature)?
+        }
+        match self {
+            SignatureScheme::Pkcs1v15(hash) => {
+                let expected = pkcs1v15_encode(hash, &hash.digest(data), k)?;
+                if expected != em {
+                    Err(ErrorKind::InvalidSignature)?
+                }
+                Ok(())
+            },
+            SignatureScheme::Pss(hash) => {
+                let em_bits = mod_bits.saturating_sub(1);
+                let em_len = em_bits.div_ceil(8);
+                let (zeros, em) = em.split_at(k - em_len);
+                if zeros.iter().any(|b| *b != 0) {
+                    Err(ErrorKind::InvalidSignature)?
+                }
+                pss_verify(hash, &hash.digest(data), em, em_bits)
+            }
+        }
+    }
+}
+
+/// How a single block (usually a symmetric key) is padded before being encrypted with the public key
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EncryptionScheme {
+    /// RSAES-PKCS1-v1_5. Only kept for decrypting data produced by other implementations
+    Pkcs1v15,
+    /// RSAES-OAEP with MGF1 using the same hash, and an empty label
+    Oaep(HashAlgorithm),
+}
+
+impl EncryptionScheme {
+    /// Pad `data` into a block of `k` bytes
+    pub fn encode(self, data: &[u8], k: usize) -> Result<Vec<u8>, RSAError> {
+        let mut rng = rand::thread_rng();
+        match self {
+            EncryptionScheme::Pkcs1v15 => {
+                if data.len() + 11 > k {
+                    Err(ErrorKind::MessageTooLong)?
+                }
+                let mut em = vec![0x00, 0x02];
+                while em.len() < k - data.len() - 1 {
+                    let byte: u8 = rng.gen();
+                    if byte != 0 {
+                        em.push(byte);
+                    }
+                }
+                em.push(0x00);
+                em.extend_from_slice(data);
+                Ok(em)
+            },
+            EncryptionScheme::Oaep(hash) => {
+                let h_len = hash.output_len();
+                if data.len() + 2 * h_len + 2 > k {
+                    Err(ErrorKind::MessageTooLong)?
+                }
+                let mut db = hash.digest(&[]);
+                db.resize(k - data.len() - h_len - 2, 0);
+                db.push(0x01);
+                db.extend_from_slice(data);
+
+                let mut seed = vec![0u8; h_len];
+                rng.fill(seed.as_mut_slice());
+                let db_mask = mgf1(hash, &seed, db.len());
+                db.iter_mut().zip(db_mask.iter()).for_each(|(b, m)| *b ^= m);
+                let seed_mask = mgf1(hash, &db, h_len);
+                seed.iter_mut().zip(seed_mask.iter()).for_each(|(b, m)| *b ^= m);
+
+                let mut em = vec![0x00];
+                em.extend(seed);
+                em.extend(db);
+                Ok(em)
+            }
+        }
+    }
+
+    /// Remove the padding from a decrypted block of `k` bytes
+    pub fn decode(self, em: &[u8], k: usize) -> Result<Vec<u8>, RSAError> {
+        // both schemes need at least 11 bytes; checked first so that short blocks are never indexed
+        if em.len() != k || k < 11 || em[0] != 0x00 {
+            Err(ErrorKind::Decryption)?
+        }
+        match self {
+            EncryptionScheme::Pkcs1v15 => {
+                if em[1] != 0x02 {
+                    Err(ErrorKind::Decryption)?
+                }
+                let separator = em[2..].iter().position(|b| *b == 0).ok_or(ErrorKind::Decryption)? + 2;
+                if separator < 10 {
+                    Err(ErrorKind::Decryption)?
+                }
+                Ok(em[separator + 1..].to_vec())
+            },
+            EncryptionScheme::Oaep(hash) => {
+                let h_len = hash.output_len();
+                if k < 2 * h_len + 2 {
+                    Err(ErrorKind::Decryption)?
+                }
+                let (masked_seed, masked_db) = em[1..].split_at(h_len);
+                let seed_mask = mgf1(hash, masked_db, h_len);
+                let seed: Vec<u8> = masked_seed.iter().zip(seed_mask.iter()).map(|(b, m)| b ^ m).collect();
+                let db_mask = mgf1(hash, &seed, masked_db.len());
+                let db: Vec<u8> = masked_db.iter().zip(db_mask.iter()).map(|(b, m)| b ^ m).collect();
+
+                if db[..h_len] != hash.digest(&[])[..] {
+                    Err(ErrorKind::Decryption)?
+                }
+                let separator = db[h_len..].iter().position(|b| *b != 0).ok_or(ErrorKind::Decryption)? + h_len;
+                if db[separator] != 0x01 {
+                    Err(ErrorKind::Decryption)?
+                }
+                Ok(db[separator + 1..].to_vec())
+            }
+        }
+    }
+}
+
+fn pkcs1v15_encode(hash: HashAlgorithm, digest: &[u8], k: usize) -> Result<Vec<u8>, RSAError> {
+    let prefix = hash.digest_info_prefix();
+    let t_len = prefix.len() + digest.len();
+    if k < t_len + 11 {
+        Err(ErrorKind::KeyTooSmall)?
+    }
+    let mut em = Vec::with_capacity(k);
+    em.push(0x00);
+    em.push(0x01);
+    em.resize(k - t_len - 1, 0xff);
+    em.push(0x00);
+    em.extend_from_slice(prefix);
+    em.extend_from_slice(digest);
+    Ok(em)
+}
+
+/// Mask Generation Function from RFC 8017 Appendix B.2.1
+fn mgf1(hash: HashAlgorithm, seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + hash.output_len());
+    let mut counter: u32 = 0;
+    while mask.len() < len {
+        let mut block = seed.to_vec();
+        block.extend_from_slice(&counter.to_be_bytes());
+        mask.extend(hash.digest(&block));
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+fn pss_encode(hash: HashAlgorithm, m_hash: &[u8], em_bits: usize) -> Result<Vec<u8>, RSAError> {
+    let h_len = hash.output_len();
+    let s_len = h_len;
+    let em_len = em_bits.div_ceil(8);
+    if em_len < h_len + s_len + 2 {
+        Err(ErrorKind::KeyTooSmall)?
+    }
+
+    let mut salt = vec![0u8; s_len];
+    rand::thread_rng().fill(salt.as_mut_slice());
+
+    let mut m_prime = vec![0u8; 8];
+    m_prime.extend_from_slice(m_hash);
+    m_prime.extend_from_slice(&salt);
+    let h = hash.digest(&m_prime);
+
+    let mut db = vec![0u8; em_len - s_len - h_len - 2];
+    db.push(0x01);
+    db.extend_from_slice(&salt);
+    let mask = mgf1(hash, &h, db.len());
+    db.iter_mut().zip(mask.iter()).for_each(|(b, m)| *b ^= m);
+    db[0] &= 0xff >> (8 * em_len - em_bits);
+
+    let mut em = db;
+    em.extend_from_slice(&h);
+    em.push(0xbc);
+    Ok(em)
+}
+
+fn pss_verify(hash: HashAlgorithm, m_hash: &[u8], em: &[u8], em_bits: usize) -> Result<(), RSAError> {
+    let h_len = hash.output_len();
+    let s_len = h_len;
+    let em_len = em_bits.div_ceil(8);
+    if em_len < h_len + s_len + 2 || em.len() != em_len || em[em_len - 1] != 0xbc {
+        Err(ErrorKind::InvalidSignature)?
+    }
+
+    let (masked_db, rest) = em.split_at(em_len - h_len - 1);
+    let h = &rest[..h_len];
+    let top_mask = 0xffu8 >> (8 * em_len - em_bits);
+    if masked_db[0] & !top_mask != 0 {
+        Err(ErrorKind::InvalidSignature)?
+    }
+
+    let mask = mgf1(hash, h, masked_db.len());
+    let mut db: Vec<u8> = masked_db.iter().zip(mask.iter()).map(|(b, m)| b ^ m).collect();
+    db[0] &= top_mask;
+
+    let pad_len = em_len - h_len - s_len - 2;
+    if db[..pad_len].iter().any(|b| *b != 0) || db[pad_len] != 0x01 {
+        Err(ErrorKind::InvalidSignature)?
+    }
+    let salt = &db[pad_len + 1..];
+
+    let mut m_prime = vec![0u8; 8];
+    m_prime.extend_from_slice(m_hash);
+    m_prime.extend_from_slice(salt);
+    if hash.digest(&m_prime) != h {
+        Err(ErrorKind::InvalidSignature)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_pkcs1v15() {
+        let em = SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256).encode(b"abc", 1024).unwrap();
+        assert_eq!(em.len(), 128);
+        assert_eq!(&em[0..3], &[0x00, 0x01, 0xff]);
+        assert_eq!(&em[128 - 32..], HashAlgorithm::Sha256.digest(b"abc").as_slice());
+        SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256).verify(b"abc", &em, 1024).unwrap();
+        assert!(SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256).verify(b"abd", &em, 1024).is_err());
+    }
+
+    #[test]
+    fn should_roundtrip_pss() {
+        for bits in &[1023, 1024, 2048] {
+            let em = SignatureScheme::Pss(HashAlgorithm::Sha256).encode(b"hello", *bits).unwrap();
+            SignatureScheme::Pss(HashAlgorithm::Sha256).verify(b"hello", &em, *bits).unwrap();
+            assert!(SignatureScheme::Pss(HashAlgorithm::Sha256).verify(b"hellp", &em, *bits).is_err());
+        }
+    }
+
+    #[test]
+    fn should_roundtrip_encryption_padding() {
+        for scheme in &[EncryptionScheme::Pkcs1v15, EncryptionScheme::Oaep(HashAlgorithm::Sha256)] {
+            let em = scheme.encode(b"a content encryption key", 256).unwrap();
+            assert_eq!(em.len(), 256);
+            assert_eq!(scheme.decode(&em, 256).unwrap(), b"a content encryption key");
+        }
+        let mut em = EncryptionScheme::Oaep(HashAlgorithm::Sha256).encode(b"key", 128).unwrap();
+        em[100] ^= 1;
+        assert!(EncryptionScheme::Oaep(HashAlgorithm::Sha256).decode(&em, 128).is_err());
+    }
+
+    #[test]
+    fn should_refuse_small_keys() {
+        assert!(SignatureScheme::Pkcs1v15(HashAlgorithm::Sha512).encode(b"abc", 512).is_err());
+        for bits in &[0, 1, 8] {
+            assert!(SignatureScheme::Pss(HashAlgorithm::Sha256).encode(b"abc", *bits).is_err());
+            assert!(SignatureScheme::Pss(HashAlgorithm::Sha256).verify(b"abc", &vec![0; bits / 8], *bits).is_err());
+        }
+        for k in 0..3 {
+            assert!(EncryptionScheme::Pkcs1v15.decode(&vec![0; k], k).is_err());
+            assert!(EncryptionScheme::Oaep(HashAlgorithm::Sha256).decode(&vec![0; k], k).is_err());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Passphrase encryption of database files
+//! The key is derived from the passphrase with Argon2id and the payload is sealed with XChaCha20-Poly1305.
+//! Everything needed to derive the key again, except the passphrase, is stored in the header
+use crate::err::{ErrorKind, RSAError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8] = b"RSAS";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+// magic, version, three u32 Argon2 parameters, salt, nonce
+const HEADER_LEN: usize = 4 + 1 + 12 + SALT_LEN + NONCE_LEN;
+
+/// The most memory, in KiB, that the header of a sealed file may ask Argon2id to use: 1 GiB
+pub const MAX_M_COST: u32 = 1 << 20;
+/// The most passes that the header of a sealed file may ask Argon2id to make
+pub const MAX_T_COST: u32 = 16;
+/// The most lanes that the header of a sealed file may ask Argon2id to use
+pub const MAX_P_COST: u32 = 16;
+
+/// Whether `data` was produced by `SealingKey::seal`
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// A passphrase, wiped from memory when dropped
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Passphrase {
+    pub fn new(passphrase: String) -> Self {
+        Passphrase(Zeroizing::new(passphrase))
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// A key derived from a passphrase, together with the salt and parameters it was derived with
+pub struct SealingKey {
+    salt: [u8; SALT_LEN],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl std::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SealingKey").field("m_cost", &self.m_cost).field("t_cost", &self.t_cost).finish()
+    }
+}
+
+impl SealingKey {
+    /// Derive a key from `passphrase` with a fresh salt and the default Argon2id parameters
+    pub fn new(passphrase: &Passphrase) -> Result<Self, RSAError> {
+        let params = Params::default();
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill(&mut salt);
+        Self::derive(passphrase, salt, params.m_cost(), params.t_cost(), params.p_cost())
+    }
+
+    fn derive(passphrase: &Passphrase, salt: [u8; SALT_LEN], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Self, RSAError> {
+        let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|_| ErrorKind::WrongPassphrase)?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.0.as_bytes(), &salt, key.as_mut())
+            .map_err(|_| ErrorKind::WrongPassphrase)?;
+        Ok(SealingKey { salt, m_cost, t_cost, p_cost, key })
+    }
+
+    /// Whether the key was derived with the default Argon2id parameters, which everything is sealed with
+    pub fn has_default_params(&self) -> bool {
+        let params = Params::default();
+        (self.m_cost, self.t_cost, self.p_cost) == (params.m_cost(), params.t_cost(), params.p_cost())
+    }
+
+    /// Derive the key that `sealed` was sealed with, reusing `cached` if it was derived the same way. The header is
+    /// not authenticated until the key is derived, so parameters weaker than the defaults or above `MAX_M_COST`,
+    /// `MAX_T_COST` and `MAX_P_COST` are refused
+    pub fn for_sealed(cached: Option<Self>, passphrase: &Passphrase, sealed: &[u8]) -> Result<Self, RSAError> {
+        if !is_sealed(sealed) || sealed.len() < HEADER_LEN || sealed[MAGIC.len()] != VERSION {
+            Err(ErrorKind::Database)?
+        }
+        let u32_at = |i: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&sealed[i..i + 4]);
+            u32::from_be_bytes(bytes)
+        };
+        let (m_cost, t_cost, p_cost) = (u32_at(5), u32_at(9), u32_at(13));
+        let defaults = Params::default();
+        if !(defaults.m_cost()..=MAX_M_COST).contains(&m_cost)
+            || !(defaults.t_cost()..=MAX_T_COST).contains(&t_cost)
+            || !(defaults.p_cost()..=MAX_P_COST).contains(&p_cost) {
+            Err(ErrorKind::Database)?
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&sealed[17..17 + SALT_LEN]);
+        match cached {
+            Some(key) if key.salt == salt && (key.m_cost, key.t_cost, key.p_cost) == (m_cost, t_cost, p_cost) => Ok(key),
+            _ => Self::derive(passphrase, salt, m_cost, t_cost, p_cost),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(self.key.as_ref()))
+    }
+
+    /// Encrypt and authenticate `plaintext`, with a fresh nonce
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        for param in &[self.m_cost, self.t_cost, self.p_cost] {
+            header.extend_from_slice(&param.to_be_bytes());
+        }
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&nonce);
+        let ciphertext = self.cipher()
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &header })
+            .map_err(|_| ErrorKind::Database)?;
+        header.extend(ciphertext);
+        Ok(header)
+    }
+
+    /// Check and decrypt data sealed with this key
+    pub fn open(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, RSAError> {
+        if sealed.len() < HEADER_LEN {
+            Err(ErrorKind::Database)?
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let nonce = XNonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+        let plaintext = self.cipher()
+            .decrypt(nonce, Payload { msg: ciphertext, aad: header })
+            .map_err(|_| ErrorKind::WrongPassphrase)?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_seal_and_open() {
+        let passphrase = |p: &str| Passphrase::new(p.to_string());
+        let key = SealingKey::new(&passphrase("correct horse")).unwrap();
+        let sealed = key.seal(b"private exponents").unwrap();
+        assert!(is_sealed(&sealed));
+        let again = SealingKey::for_sealed(None, &passphrase("correct horse"), &sealed).unwrap();
+        assert_eq!(again.open(&sealed).unwrap().as_slice(), b"private exponents");
+
+        let wrong = SealingKey::for_sealed(None, &passphrase("battery staple"), &sealed).unwrap();
+        assert_eq!(wrong.open(&sealed).unwrap_err().kind(), ErrorKind::WrongPassphrase);
+        let mut tampered = sealed.clone();
+        tampered[HEADER_LEN - 1] ^= 1;
+        assert!(key.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn should_refuse_argon2_parameters_out_of_range() {
+        let passphrase = Passphrase::new("correct horse".to_string());
+        let sealed = SealingKey::new(&passphrase).unwrap().seal(b"private exponents").unwrap();
+        let defaults = Params::default();
+        for (offset, cost) in [(5, 1), (5, MAX_M_COST + 1), (9, defaults.t_cost() - 1), (9, u32::MAX), (13, 0), (13, MAX_P_COST + 1)] {
+            let mut forged = sealed.clone();
+            forged[offset..offset + 4].copy_from_slice(&u32::to_be_bytes(cost));
+            assert_eq!(SealingKey::for_sealed(None, &passphrase, &forged).unwrap_err().kind(), ErrorKind::Database);
+        }
+
+        // stronger than the defaults is fine, but is not kept for sealing again
+        let mut stronger = sealed.clone();
+        stronger[9..13].copy_from_slice(&u32::to_be_bytes(defaults.t_cost() + 1));
+        let key = SealingKey::for_sealed(None, &passphrase, &stronger).unwrap();
+        assert!(!key.has_default_params());
+        assert!(SealingKey::for_sealed(None, &passphrase, &sealed).unwrap().has_default_params());
+    }
+}
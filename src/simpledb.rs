@@ -1,11 +1,12 @@
-//! a simple database that serializes to/from JSON
+//! a simple database that serializes to/from JSON, bincode or MessagePack
 use log::*;
 use std::{
     io::prelude::*,
-    io::SeekFrom,
+    cell::{Cell, RefCell},
+    rc::Rc,
     fs::{self, File, OpenOptions},
     marker::PhantomData,
-    path::PathBuf,
+    path::{Path, PathBuf},
     default::Default,
 };
 use flate2::{
@@ -17,98 +18,366 @@ use serde::{
     Serialize,
     de::DeserializeOwned
 };
-use failure::{Error, ResultExt};
-use crate::err::{ErrorKind};
+use failure::ResultExt;
+use fs2::FileExt;
+use crate::err::{ErrorKind, RSAError};
+use crate::rsa::HashAlgorithm;
+use crate::codec::Codec;
+use crate::seal::{self, Passphrase, SealingKey};
+use zeroize::Zeroizing;
+use rand::Rng;
 
+/// A file holding one serialized value of type `D`
+/// Readers take a shared lock and writers an exclusive lock on a `.lock` file next to the database, so that
+/// several processes can use the same database
+/// With a passphrase set, the file is encrypted; see `set_passphrase`
 #[derive(Debug)]
-pub struct SimpleDB<D: DeserializeOwned + Serialize + Default> {
+pub struct SimpleDB<D: Schema> {
     path: PathBuf,
+    /// SHA-256 of the file as last read or written by us, to notice changes made by others in between
+    version: RefCell<Option<Vec<u8>>>,
+    passphrase: RefCell<Option<Passphrase>>,
+    /// the key derived from the passphrase, kept so that Argon2 runs once rather than on every read and write
+    sealing_key: Rc<RefCell<Option<SealingKey>>>,
+    /// a passphrase change waiting for the next save, where `Some(None)` removes the passphrase
+    new_passphrase: RefCell<Option<Option<Passphrase>>>,
+    /// the codec the file was written with, or the one to write it with next
+    codec: Cell<Option<Codec>>,
+    /// the layout version of the file as last read or written
+    stored_version: Cell<Option<u32>>,
     _marker: PhantomData<D>,
 }
 
-// TODO: Figure out a way to use MessagePack instead of JSON
-// JSON is OK because we compress it
-// compression bench: of ETH tipjar addr txs, block 0-6mil - uncompressed 100MB, compressed 3.9MB
-/// A simple DB that allows saving/retrieving structures to/from a (compressed) file,
-impl<D> SimpleDB<D> where D: DeserializeOwned + Serialize + Default {
+/// A type that can be stored in a `SimpleDB`, and how to read the layouts it had in the past
+/// Bump `VERSION` whenever the serialized layout changes, and teach `migrate` to read the previous one
+pub trait Schema: DeserializeOwned + Serialize + Default {
+    /// The layout written by this version of the program. Files from before layouts were versioned are layout 0
+    const VERSION: u32 = 0;
 
+    /// Decode `payload`, written by `codec` in the older layout `version`, into the current layout
+    fn migrate(_version: u32, _codec: Codec, _payload: &[u8]) -> Result<Self, RSAError> {
+        Err(ErrorKind::Database)?
+    }
+}
 
-    pub fn new(path: PathBuf) -> Result<Self, Error> {
+// compression bench (JSON): of ETH tipjar addr txs, block 0-6mil - uncompressed 100MB, compressed 3.9MB
+/// A simple DB that allows saving/retrieving structures to/from a (compressed) file,
+impl<D> SimpleDB<D> where D: Schema {
+
+    /// Open the database at `path`, creating an empty file if it does not exist
+    pub fn new(path: PathBuf) -> Result<Self, RSAError> {
         if !path.as_path().exists() {
             File::create(path.as_path()).context(ErrorKind::Database)?;
         }
         Ok(SimpleDB {
             path,
+            version: RefCell::new(None),
+            passphrase: RefCell::new(None),
+            sealing_key: Rc::new(RefCell::new(None)),
+            new_passphrase: RefCell::new(None),
+            codec: Cell::new(None),
+            stored_version: Cell::new(None),
             _marker: PhantomData
         })
     }
 
+    /// Whether the file on disk is encrypted with a passphrase
+    pub fn is_encrypted(&self) -> Result<bool, RSAError> {
+        let _lock = self.lock(false)?;
+        let mut magic = Vec::new();
+        File::open(&self.path).context(ErrorKind::Database)?.take(4).read_to_end(&mut magic).context(ErrorKind::Database)?;
+        Ok(seal::is_sealed(&magic))
+    }
+
+    /// The passphrase to decrypt the file with, also used to encrypt it again when saving
+    pub fn unlock(&self, passphrase: Passphrase) {
+        self.passphrase.replace(Some(passphrase));
+    }
+
+    /// Keep the key derived from the passphrase in `key`, shared with other databases sealed with the same
+    /// passphrase, so that it is derived once for all of them
+    pub fn share_sealing_key(&mut self, key: Rc<RefCell<Option<SealingKey>>>) {
+        self.sealing_key = key;
+    }
+
+    /// Change the passphrase the file is encrypted with on the next save. `None` saves it unencrypted
+    /// The previous version and its backup are erased on that save rather than kept under the old passphrase
+    pub fn set_passphrase(&self, passphrase: Option<Passphrase>) {
+        self.new_passphrase.replace(Some(passphrase));
+    }
+
+    /// The codec the database is saved with: the one it was read with, or the default for a new database
+    pub fn codec(&self) -> Codec {
+        self.codec.get().unwrap_or_default()
+    }
+
+    /// Save with `codec` from now on
+    pub fn set_codec(&self, codec: Codec) {
+        self.codec.set(Some(codec));
+    }
+
+    /// Whether the file was written in an older layout and is only migrated in memory until it is saved
+    pub fn is_outdated(&self) -> bool {
+        self.stored_version.get().is_some_and(|version| version < D::VERSION)
+    }
+
+    /// The file backing this database
     pub fn file_path(&self) -> &PathBuf {
         &self.path
     }
 
-    /// Save structure to a file, serializing to JSON and then compressing with DEFLATE
-    pub fn save(&self, data: D) -> Result<(), Error> {
-        self.mutate(|file| {
-            let ser_data = serde_json::ser::to_vec(&data).context(ErrorKind::Database)?;
-            let mut e = DeflateEncoder::new(file, Compression::default());
-            e.write_all(ser_data.as_slice()).context(ErrorKind::Database)?;
-            e.finish().context(ErrorKind::Database)?;
-            Ok(())
-        }).context(ErrorKind::Database)?;
+    /// Save structure to a file, serializing with `codec()` and then compressing with DEFLATE
+    /// Fails with `DatabaseChanged` if another process saved the database since we last read it
+    pub fn save(&self, data: D) -> Result<(), RSAError> {
+        self.save_with(data, |_, _| Err(ErrorKind::DatabaseChanged)?).map(|_| ())
+    }
+
+    /// Save like `save`, but if another process saved the database since we last read it, write
+    /// `merge(theirs, ours)` instead. Returns what was written
+    pub fn save_with<F>(&self, data: D, merge: F) -> Result<D, RSAError>
+    where
+        F: FnOnce(D, D) -> Result<D, RSAError>
+    {
+        let _lock = self.lock(true)?;
+        let data = match self.version.borrow().as_ref() {
+            Some(version) => {
+                let (theirs, current) = self.load()?;
+                if &current != version {
+                    info!("Database changed since it was read, merging");
+                    merge(theirs, data)?
+                } else {
+                    data
+                }
+            },
+            // never read, so there is nothing to compare against
+            None => data,
+        };
+        let ser_data = Zeroizing::new(self.codec().encode(&data, D::VERSION)?);
+        let written = self.write(&ser_data)?;
+        self.stored_version.set(Some(D::VERSION));
+        self.version.replace(Some(written));
+        Ok(data)
+    }
+
+    /// The path of the backup of the previous version
+    pub fn backup_path(&self) -> PathBuf {
+        self.sibling("bak")
+    }
+
+    /// Overwrite the backup of the previous version before removing it, so that keys which are gone from the
+    /// database cannot be read back from it
+    pub fn erase_backup(&self) -> Result<(), RSAError> {
+        let _lock = self.lock(true)?;
+        erase_file(&self.backup_path()).context(ErrorKind::Database)?;
         Ok(())
     }
 
-    /// Get structure from file, DEFLATING and then deserializing from JSON
-    pub fn get(&self) -> Result<D, Error> {
-        let meta = fs::metadata(self.path.as_path()).context(ErrorKind::Database)?;
-        if meta.len() == 0 {
-            info!("File length is 0");
-            return Ok(D::default());
-        }
-        self.read(|file| {
-            let mut deflater = DeflateDecoder::new(file);
-            let mut s = String::new();
-            let bytes_read = deflater.read_to_string(&mut s).context(ErrorKind::Database)?;
-            info!("Read {} bytes from database file", bytes_read);
-            Ok(serde_json::de::from_str(&s)?)
-        })
+    /// Remove the database with its backup and lock file, overwriting the database and backup first
+    pub fn destroy(self) -> Result<(), RSAError> {
+        {
+            let _lock = self.lock(true)?;
+            erase_file(&self.path).context(ErrorKind::Database)?;
+            erase_file(&self.backup_path()).context(ErrorKind::Database)?;
+        }
+        fs::remove_file(self.sibling("lock")).context(ErrorKind::Database)?;
+        Ok(())
     }
 
-    /// open backend
-    fn open(&self) -> Result<File, Error> {
-        Ok(OpenOptions::new().create(true).read(true).write(true).open(self.path.as_path()).context(ErrorKind::Database)?)
+    // `path` with `.suffix` appended to the file name, in the same directory so that rename stays atomic
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        name.push(".");
+        name.push(suffix);
+        self.path.with_file_name(name)
     }
 
-    /// mutate the file, always setting seek back to beginning
-    fn mutate<F>(&self, mut fun: F) -> Result<(), Error>
-    where
-        F: FnMut(&mut File) -> Result<(), Error>
-    {
-        let mut file = self.open().context(ErrorKind::Database)?;
-        fun(&mut file).context(ErrorKind::Database)?;
-        file.seek(SeekFrom::Start(0)).context(ErrorKind::Database)?;
+    // The new contents are written to a temporary file which replaces the database only once it is
+    // safely on disk, so a crash leaves either the old or the new database. The previous version is kept as `.bak`
+    fn write(&self, ser_data: &[u8]) -> Result<Vec<u8>, RSAError> {
+        let mut compressed = DeflateEncoder::new(Vec::new(), Compression::default());
+        compressed.write_all(ser_data).context(ErrorKind::Database)?;
+        let mut compressed = compressed.finish().context(ErrorKind::Database)?;
+        let rekey = self.new_passphrase.borrow().clone();
+        let passphrase = rekey.clone().unwrap_or_else(|| self.passphrase.borrow().clone());
+        if let Some(passphrase) = passphrase {
+            let mut key = self.sealing_key.borrow_mut();
+            // a new passphrase gets a new salt, and files sealed with other parameters are sealed again with the defaults
+            if rekey.is_some() || !key.as_ref().is_some_and(SealingKey::has_default_params) {
+                *key = Some(SealingKey::new(&passphrase)?);
+            }
+            compressed = key.as_ref().expect("set above").seal(&compressed)?;
+        }
+
+        let tmp = self.sibling(&format!("{}.tmp", std::process::id()));
+        let replaced = self.replace_with(&tmp, &compressed, rekey.is_none());
+        if replaced.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        replaced.context(ErrorKind::Database)?;
+        if let Some(passphrase) = self.new_passphrase.replace(None) {
+            self.passphrase.replace(passphrase);
+        }
+        Ok(HashAlgorithm::Sha256.digest(&compressed))
+    }
+
+    // The previous version becomes the backup by linking its file rather than copying it, so no copy of it is left
+    // behind to be read back. With `keep_backup` false, it is erased instead, because it is encrypted with a
+    // passphrase that was changed (or not encrypted at all)
+    fn replace_with(&self, tmp: &Path, compressed: &[u8], keep_backup: bool) -> Result<(), RSAError> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+        let previous = fs::metadata(&self.path).ok();
+        if let Some(meta) = &previous {
+            // the database holds private keys; never loosen its permissions
+            fs::set_permissions(tmp, meta.permissions())?;
+        }
+        file.write_all(compressed)?;
+        file.sync_all()?;
+
+        let backup = self.backup_path();
+        // a crash between linking the backup and replacing the database leaves both names on the same file
+        if same_file(&backup, &self.path) {
+            fs::remove_file(&backup)?;
+        } else {
+            erase_file(&backup)?;
+        }
+        if previous.map(|meta| meta.len() > 0).unwrap_or(false) {
+            link_backup(&self.path, &backup)?;
+        }
+        if let Err(e) = fs::rename(tmp, &self.path) {
+            // leave the previous version where it was
+            if same_file(&backup, &self.path) {
+                let _ = fs::remove_file(&backup);
+            } else if !self.path.exists() {
+                let _ = fs::rename(&backup, &self.path);
+            }
+            return Err(e.into());
+        }
+        // make the rename itself durable
+        #[cfg(unix)]
+        File::open(self.path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new(".")))?.sync_all()?;
+        if !keep_backup {
+            erase_file(&backup)?;
+        }
         Ok(())
     }
 
-    /// read file, setting seek back to the start
-    fn read<F>(&self, fun: F) -> Result<D, Error>
-    where F: Fn(&File) -> Result<D, Error>
+    /// Get structure from file, DEFLATING and then deserializing with the codec named in its header
+    pub fn get(&self) -> Result<D, RSAError> {
+        let _lock = self.lock(false)?;
+        let (data, version) = self.load()?;
+        self.version.replace(Some(version));
+        Ok(data)
+    }
+
+    // the contents of the file and their hash
+    fn load(&self) -> Result<(D, Vec<u8>), RSAError> {
+        let raw = fs::read(self.path.as_path()).context(ErrorKind::Database)?;
+        let version = HashAlgorithm::Sha256.digest(&raw);
+        if raw.is_empty() {
+            info!("File length is 0");
+            return Ok((D::default(), version));
+        }
+        let compressed = if seal::is_sealed(&raw) {
+            let passphrase = self.passphrase.borrow();
+            let passphrase = passphrase.as_ref().ok_or(ErrorKind::PassphraseRequired)?;
+            let key = SealingKey::for_sealed(self.sealing_key.replace(None), passphrase, &raw)?;
+            let opened = key.open(&raw)?;
+            self.sealing_key.replace(Some(key));
+            opened
+        } else {
+            Zeroizing::new(raw)
+        };
+        let mut deflater = DeflateDecoder::new(compressed.as_slice());
+        let mut ser_data = Zeroizing::new(Vec::new());
+        let bytes_read = deflater.read_to_end(&mut ser_data).context(ErrorKind::Database)?;
+        info!("Read {} bytes from database file", bytes_read);
+        let (codec, layout, payload) = Codec::unwrap(&ser_data)?;
+        let data = if layout == D::VERSION {
+            codec.decode(payload)?
+        } else if layout < D::VERSION {
+            info!("Migrating database from layout {} to {}", layout, D::VERSION);
+            D::migrate(layout, codec, payload)?
+        } else {
+            Err(ErrorKind::DatabaseTooNew)?
+        };
+        if self.codec.get().is_none() {
+            self.codec.set(Some(codec));
+        }
+        self.stored_version.set(Some(layout));
+        Ok((data, version))
+    }
+
+    /// Lock the database, exclusively to write or shared to read, until the returned file is dropped
+    /// The lock is on a separate file because saving replaces the database file itself
+    fn lock(&self, exclusive: bool) -> Result<File, RSAError> {
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(self.sibling("lock")).context(ErrorKind::Database)?;
+        if exclusive {
+            lock.lock_exclusive().context(ErrorKind::Database)?;
+        } else {
+            lock.lock_shared().context(ErrorKind::Database)?;
+        }
+        Ok(lock)
+    }
+}
+
+// Give the file at `path` the name `backup` as well, or only `backup` where hard links are not available or cannot
+// be told apart from separate files by `same_file`
+fn link_backup(path: &Path, backup: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
     {
-        let mut file = self.open().context(ErrorKind::Database)?;
-        let ret = fun(&file).context(ErrorKind::Database)?;
-        file.seek(SeekFrom::Start(0)).context(ErrorKind::Database)?;
-        Ok(ret)
+        if fs::hard_link(path, backup).is_ok() {
+            return Ok(());
+        }
+    }
+    fs::rename(path, backup)
+}
+
+// Whether both paths name the same file, as hard links do
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
     }
 }
 
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+// Overwrite `path` with random bytes, wait for them to reach the disk and remove it. A missing file is already gone.
+// Filesystems that copy on write or SSDs that remap blocks may still keep the old contents somewhere
+fn erase_file(path: &Path) -> std::io::Result<()> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut remaining = file.metadata()?.len() as usize;
+    let mut noise = [0u8; 4096];
+    while remaining > 0 {
+        let len = remaining.min(noise.len());
+        rand::thread_rng().fill(&mut noise[..len]);
+        file.write_all(&noise[..len])?;
+        remaining -= len;
+    }
+    file.sync_all()?;
+    fs::remove_file(path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashMap;
+
+    impl Schema for HashMap<String, usize> {}
+    impl Schema for HashMap<String, String> {}
+
     #[test]
     fn save() {
-        pretty_env_logger::try_init();
+        let _ = pretty_env_logger::try_init();
         let db = SimpleDB::<HashMap<String, usize>>::new(PathBuf::from("/tmp/SOME")).unwrap();
         let mut data = HashMap::new();
         data.insert("Hello".to_string(), 45);
@@ -118,7 +387,7 @@ mod tests {
 
     #[test]
     fn get() {
-        pretty_env_logger::try_init();
+        let _ = pretty_env_logger::try_init();
         let db = SimpleDB::<HashMap<String, usize>>::new(PathBuf::from("/tmp/SOME")).unwrap();
         let mut data = HashMap::new();
         data.insert("Hello".to_string(), 45);
@@ -126,4 +395,132 @@ mod tests {
         db.save(data.clone()).unwrap();
         info!("DATA: {:?}", db.get().unwrap());
     }
+
+    #[test]
+    fn should_replace_atomically_and_keep_a_backup() {
+        let path = PathBuf::from("/tmp/RSA_ATOMIC_TEST");
+        let _ = fs::remove_file(&path);
+        let db = SimpleDB::<HashMap<String, String>>::new(path.clone()).unwrap();
+        let mut large = HashMap::new();
+        large.insert("key".to_string(), "x".repeat(4096));
+        large.insert("other".to_string(), (0..2048).map(|i| format!("{}", i)).collect());
+        db.save(large.clone()).unwrap();
+        let mut small = HashMap::new();
+        small.insert("key".to_string(), "y".to_string());
+        #[cfg(unix)]
+        let previous = fs::metadata(&path).unwrap();
+        db.save(small.clone()).unwrap();
+
+        assert_eq!(db.get().unwrap(), small);
+        let backup = SimpleDB::<HashMap<String, String>>::new(db.backup_path()).unwrap();
+        assert_eq!(backup.get().unwrap(), large);
+        // the backup is the previous file itself, not a copy that would leave the original behind
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(fs::metadata(db.backup_path()).unwrap().ino(), previous.ino());
+        }
+        let leftovers = fs::read_dir("/tmp").unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with("RSA_ATOMIC_TEST.") && e.file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn should_erase_the_backup_and_destroy_the_database() {
+        let path = PathBuf::from("/tmp/RSA_ERASE_TEST");
+        let db = SimpleDB::<HashMap<String, usize>>::new(path.clone()).unwrap();
+        db.save(HashMap::new()).unwrap();
+        db.save(HashMap::new()).unwrap();
+        assert!(db.backup_path().exists());
+        db.erase_backup().unwrap();
+        assert!(!db.backup_path().exists());
+        db.erase_backup().unwrap();
+
+        db.destroy().unwrap();
+        assert!(!path.exists());
+        assert!(!PathBuf::from("/tmp/RSA_ERASE_TEST.lock").exists());
+    }
+
+    #[test]
+    fn should_notice_concurrent_changes() {
+        let path = PathBuf::from("/tmp/RSA_CONCURRENT_TEST");
+        let _ = fs::remove_file(&path);
+        let ours = SimpleDB::<HashMap<String, usize>>::new(path.clone()).unwrap();
+        let theirs = SimpleDB::<HashMap<String, usize>>::new(path.clone()).unwrap();
+        let mut data = ours.get().unwrap();
+        theirs.get().unwrap();
+        theirs.save(vec![("theirs".to_string(), 1)].into_iter().collect()).unwrap();
+
+        data.insert("ours".to_string(), 2);
+        assert_eq!(ours.save(data.clone()).unwrap_err().kind(), ErrorKind::DatabaseChanged);
+        let merged = ours.save_with(data, |mut theirs, ours| {
+            theirs.extend(ours);
+            Ok(theirs)
+        }).unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(theirs.get().unwrap(), merged);
+        // both have now seen the latest version
+        ours.save(merged).unwrap();
+    }
+
+    #[test]
+    fn should_load_legacy_json_and_switch_codecs() {
+        let path = PathBuf::from("/tmp/RSA_CODEC_TEST");
+        let mut legacy = DeflateEncoder::new(Vec::new(), Compression::default());
+        legacy.write_all(br#"{"Hello":45}"#).unwrap();
+        fs::write(&path, legacy.finish().unwrap()).unwrap();
+
+        let db = SimpleDB::<HashMap<String, usize>>::new(path.clone()).unwrap();
+        let mut data = db.get().unwrap();
+        assert_eq!(data["Hello"], 45);
+        assert_eq!(db.codec(), Codec::Json);
+        data.insert("Byte".to_string(), 34);
+        db.set_codec(Codec::Bincode);
+        db.save(data.clone()).unwrap();
+
+        let reopened = SimpleDB::<HashMap<String, usize>>::new(path).unwrap();
+        assert_eq!(reopened.get().unwrap(), data);
+        assert_eq!(reopened.codec(), Codec::Bincode);
+    }
+
+    #[test]
+    fn should_encrypt_with_a_passphrase() {
+        let path = PathBuf::from("/tmp/RSA_PASSPHRASE_TEST");
+        let _ = fs::remove_file(&path);
+        let db = SimpleDB::<HashMap<String, String>>::new(path.clone()).unwrap();
+        let mut data = HashMap::new();
+        data.insert("secret".to_string(), "exponent".to_string());
+        db.save(data.clone()).unwrap();
+        db.save(data.clone()).unwrap();
+        assert!(!db.is_encrypted().unwrap());
+        assert!(db.backup_path().exists());
+        let plaintext = fs::read(&path).unwrap();
+        let mut previous = File::open(&path).unwrap();
+        let mut backup = File::open(db.backup_path()).unwrap();
+
+        db.set_passphrase(Some(Passphrase::new("hunter2".to_string())));
+        db.save(data.clone()).unwrap();
+        assert!(db.is_encrypted().unwrap());
+        // the plaintext backup would defeat the point, and neither it nor the plaintext database may linger
+        assert!(!db.backup_path().exists());
+        for file in [&mut previous, &mut backup] {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents.len(), plaintext.len());
+            assert_ne!(contents, plaintext);
+        }
+
+        let other = SimpleDB::<HashMap<String, String>>::new(path.clone()).unwrap();
+        assert_eq!(other.get().unwrap_err().kind(), ErrorKind::PassphraseRequired);
+        other.unlock(Passphrase::new("hunter3".to_string()));
+        assert_eq!(other.get().unwrap_err().kind(), ErrorKind::WrongPassphrase);
+        other.unlock(Passphrase::new("hunter2".to_string()));
+        assert_eq!(other.get().unwrap(), data);
+
+        other.set_passphrase(None);
+        other.save(data.clone()).unwrap();
+        assert!(!db.is_encrypted().unwrap());
+    }
 }
@@ -0,0 +1,413 @@
+//! SSH signatures (the SSHSIG format from OpenSSH's PROTOCOL.sshsig), as produced by `ssh-keygen -Y sign`
+//! Git uses these to sign commits and tags when `gpg.format = ssh`
+use crate::rsa::{AlgoRSA, HashAlgorithm, SignatureScheme, E, verify_signature};
+use crate::keystore::KeyStore;
+use crate::err::{ErrorKind, RSAError};
+use num_bigint::BigUint;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use failure::ResultExt;
+
+const MAGIC: &[u8] = b"SSHSIG";
+const VERSION: u32 = 1;
+const BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const END: &str = "-----END SSH SIGNATURE-----";
+
+/// Serializes the SSH wire types from RFC 4251 Section 5
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(mut self, n: u32) -> Self {
+        self.0.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn string(mut self, data: &[u8]) -> Self {
+        self = self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    fn mpint(self, n: &BigUint) -> Self {
+        let mut bytes = n.to_bytes_be();
+        if bytes == [0] {
+            bytes.clear();
+        } else if bytes[0] & 0x80 != 0 {
+            bytes.insert(0, 0);
+        }
+        self.string(&bytes)
+    }
+
+    fn raw(mut self, data: &[u8]) -> Self {
+        self.0.extend_from_slice(data);
+        self
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RSAError> {
+        if self.0.len() < len {
+            Err(ErrorKind::MalformedSignature)?
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, RSAError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], RSAError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn mpint(&mut self) -> Result<BigUint, RSAError> {
+        let bytes = self.string()?;
+        if bytes.first().map(|b| b & 0x80 != 0).unwrap_or(false) {
+            Err(ErrorKind::MalformedSignature)?
+        }
+        Ok(BigUint::from_bytes_be(bytes))
+    }
+
+    fn finish(&self) -> Result<(), RSAError> {
+        if !self.0.is_empty() {
+            Err(ErrorKind::MalformedSignature)?
+        }
+        Ok(())
+    }
+}
+
+/// An RSA public key, as it appears in SSH
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    pub n: BigUint,
+    pub e: BigUint,
+}
+
+impl PublicKey {
+    pub fn new(n: BigUint) -> Self {
+        PublicKey { n, e: E.into() }
+    }
+
+    /// The `ssh-rsa` wire encoding
+    pub fn blob(&self) -> Vec<u8> {
+        Writer::default().string(b"ssh-rsa").mpint(&self.e).mpint(&self.n).0
+    }
+
+    fn from_blob(blob: &[u8]) -> Result<Self, RSAError> {
+        let mut reader = Reader(blob);
+        if reader.string()? != b"ssh-rsa" {
+            Err(ErrorKind::UnsupportedKey)?
+        }
+        let e = reader.mpint()?;
+        let n = reader.mpint()?;
+        reader.finish()?;
+        Ok(PublicKey { n, e })
+    }
+
+    /// Parse an `ssh-rsa AAAA... [comment]` line, as found in `.pub` and allowed_signers files
+    /// A `key::` prefix (used by git for literal keys) is ignored
+    pub fn parse(line: &str) -> Result<Self, RSAError> {
+        let line = line.trim();
+        let line = line.strip_prefix("key::").unwrap_or(line);
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("ssh-rsa") {
+            Err(ErrorKind::UnsupportedKey)?
+        }
+        let blob = base64::decode(fields.next().ok_or(ErrorKind::MalformedSignature)?).context(ErrorKind::MalformedSignature)?;
+        Self::from_blob(&blob)
+    }
+
+    /// A line suitable for a `.pub` file or `user.signingkey`
+    pub fn to_line(&self, comment: &str) -> String {
+        format!("ssh-rsa {} {}", base64::encode(&self.blob()), comment).trim_end().to_string()
+    }
+
+    /// The SHA-256 fingerprint, as printed by `ssh-keygen -l`
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", base64::encode_config(&HashAlgorithm::Sha256.digest(&self.blob()), base64::STANDARD_NO_PAD))
+    }
+}
+
+/// A parsed (but not yet verified) SSH signature
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub public_key: PublicKey,
+    pub namespace: String,
+    hash: HashAlgorithm,
+    algorithm: String,
+    signature: Vec<u8>,
+}
+
+fn hash_name(hash: HashAlgorithm) -> &'static str {
+    match hash {
+        HashAlgorithm::Sha256 => "sha256",
+        HashAlgorithm::Sha384 => "sha384",
+        HashAlgorithm::Sha512 => "sha512",
+    }
+}
+
+fn signed_data(namespace: &str, hash: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+    Writer::default()
+        .raw(MAGIC)
+        .string(namespace.as_bytes())
+        .string(b"")
+        .string(hash_name(hash).as_bytes())
+        .string(&hash.digest(data))
+        .0
+}
+
+/// Sign `data` with the primary key of `user` using rsa-sha2-512, returning an armored signature
+pub fn sign(rsa: &AlgoRSA<impl KeyStore>, user: &str, namespace: &str, data: &[u8]) -> Result<String, RSAError> {
+    if namespace.is_empty() {
+        Err(ErrorKind::MalformedSignature)?
+    }
+    let hash = HashAlgorithm::Sha512;
+    let signature = rsa.sign(user, SignatureScheme::Pkcs1v15(HashAlgorithm::Sha512), &signed_data(namespace, hash, data))?;
+    let blob = Writer::default()
+        .raw(MAGIC)
+        .u32(VERSION)
+        .string(&PublicKey::new(rsa.public_key(user)?).blob())
+        .string(namespace.as_bytes())
+        .string(b"")
+        .string(hash_name(hash).as_bytes())
+        .string(&Writer::default().string(b"rsa-sha2-512").string(&signature).0)
+        .0;
+    let encoded = base64::encode(&blob);
+    let lines: Vec<&str> = encoded.as_bytes().chunks(70).map(|c| std::str::from_utf8(c).unwrap_or_default()).collect();
+    Ok(format!("{}\n{}\n{}\n", BEGIN, lines.join("\n"), END))
+}
+
+impl Signature {
+    pub fn parse(armored: &str) -> Result<Self, RSAError> {
+        let start = armored.find(BEGIN).ok_or(ErrorKind::MalformedSignature)? + BEGIN.len();
+        let stop = armored[start..].find(END).ok_or(ErrorKind::MalformedSignature)? + start;
+        let body: String = armored[start..stop].chars().filter(|c| !c.is_whitespace()).collect();
+        let blob = base64::decode(&body).context(ErrorKind::MalformedSignature)?;
+
+        let mut reader = Reader(&blob);
+        if reader.take(MAGIC.len())? != MAGIC || reader.u32()? != VERSION {
+            Err(ErrorKind::MalformedSignature)?
+        }
+        let public_key = PublicKey::from_blob(reader.string()?)?;
+        let namespace = String::from_utf8(reader.string()?.to_vec()).context(ErrorKind::MalformedSignature)?;
+        reader.string()?; // reserved
+        let hash = match reader.string()? {
+            b"sha256" => HashAlgorithm::Sha256,
+            b"sha512" => HashAlgorithm::Sha512,
+            _ => Err(ErrorKind::UnsupportedAlgorithm)?
+        };
+        let mut signature = Reader(reader.string()?);
+        reader.finish()?;
+        let algorithm = String::from_utf8(signature.string()?.to_vec()).context(ErrorKind::MalformedSignature)?;
+        let sig = signature.string()?.to_vec();
+        signature.finish()?;
+        Ok(Signature { public_key, namespace, hash, algorithm, signature: sig })
+    }
+
+    /// Check the signature over `data` with the embedded public key, and that it was made for `namespace`
+    /// This says nothing about who made the signature; see `AllowedSigners`
+    pub fn verify(&self, namespace: &str, data: &[u8]) -> Result<(), RSAError> {
+        if self.namespace != namespace {
+            Err(ErrorKind::InvalidSignature)?
+        }
+        let scheme = match self.algorithm.as_str() {
+            "rsa-sha2-256" => SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256),
+            "rsa-sha2-512" => SignatureScheme::Pkcs1v15(HashAlgorithm::Sha512),
+            _ => Err(ErrorKind::UnsupportedAlgorithm)?
+        };
+        verify_signature(&self.public_key.n, &self.public_key.e, scheme, &signed_data(namespace, self.hash, data), &self.signature)
+    }
+}
+
+/// One line of an allowed_signers file (see ALLOWED SIGNERS in ssh-keygen(1))
+#[derive(Debug, Clone)]
+struct AllowedSigner {
+    principals: Vec<String>,
+    namespaces: Option<Vec<String>>,
+    valid_after: Option<DateTime<Utc>>,
+    valid_before: Option<DateTime<Utc>>,
+    public_key: PublicKey,
+}
+
+/// The set of keys trusted to sign on behalf of principals
+#[derive(Debug, Clone, Default)]
+pub struct AllowedSigners(Vec<AllowedSigner>);
+
+/// Match `s` against a comma-separated list of patterns with `*` and `?` wildcards, where a `!` prefix negates
+fn match_list(patterns: &str, s: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if wildcard(negated.as_bytes(), s.as_bytes()) {
+                return false;
+            }
+        } else if wildcard(pattern.as_bytes(), s.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn wildcard(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard(&pattern[1..], s) || (!s.is_empty() && wildcard(pattern, &s[1..])),
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &s[1..]),
+        (Some(p), Some(c)) if p == c => wildcard(&pattern[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+/// Parse the `YYYYMMDD[HHMM[SS]]` timestamps used by allowed_signers and `-Overify-time`
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, RSAError> {
+    let s = s.trim_end_matches('Z');
+    let time = match s.len() {
+        8 => NaiveDate::parse_from_str(s, "%Y%m%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default()),
+        12 => NaiveDateTime::parse_from_str(s, "%Y%m%d%H%M"),
+        14 => NaiveDateTime::parse_from_str(s, "%Y%m%d%H%M%S"),
+        _ => Err(ErrorKind::MalformedSignature)?
+    };
+    Ok(time.context(ErrorKind::MalformedSignature)?.and_utc())
+}
+
+/// Split `s` on commas that are not inside double quotes
+fn split_options(s: &str) -> Vec<&str> {
+    let mut options = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                options.push(&s[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+    options.push(&s[start..]);
+    options
+}
+
+/// Split off the first whitespace-separated field, honouring double quotes
+fn next_field(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let mut in_quotes = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => return (&line[..i], &line[i..]),
+            _ => (),
+        }
+    }
+    (line, "")
+}
+
+impl AllowedSigners {
+    pub fn parse(text: &str) -> Result<Self, RSAError> {
+        let mut signers = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (principals, rest) = next_field(line);
+            let (mut field, mut rest) = next_field(rest);
+            let mut signer = AllowedSigner {
+                principals: vec![principals.trim_matches('"').to_string()],
+                namespaces: None,
+                valid_after: None,
+                valid_before: None,
+                public_key: PublicKey::new(BigUint::from(0u32)),
+            };
+            // options come before the key type, and are never themselves a key type
+            let mut cert_authority = false;
+            if !field.starts_with("ssh-") {
+                for option in split_options(field) {
+                    let (name, value) = match option.find('=') {
+                        Some(i) => (&option[..i], option[i + 1..].trim_matches('"')),
+                        None => (option, ""),
+                    };
+                    match name.to_ascii_lowercase().as_str() {
+                        "namespaces" => signer.namespaces = Some(value.split(',').map(String::from).collect()),
+                        "valid-after" => signer.valid_after = Some(parse_time(value)?),
+                        "valid-before" => signer.valid_before = Some(parse_time(value)?),
+                        "cert-authority" => cert_authority = true,
+                        _ => (),
+                    }
+                }
+                let next = next_field(rest);
+                field = next.0;
+                rest = next.1;
+            }
+            // certificate authorities sign OpenSSH certificates, which this program does not produce
+            if cert_authority || field != "ssh-rsa" {
+                continue;
+            }
+            let (key, _comment) = next_field(rest);
+            signer.public_key = PublicKey::parse(&format!("{} {}", field, key))?;
+            signers.push(signer);
+        }
+        Ok(AllowedSigners(signers))
+    }
+
+    /// Principals allowed to make `signature` in `namespace` at `time`
+    pub fn find_principals(&self, signature: &Signature, namespace: &str, time: &DateTime<Utc>) -> Vec<String> {
+        self.0.iter()
+            .filter(|s| s.public_key == signature.public_key)
+            .filter(|s| s.namespaces.as_ref().map(|ns| ns.iter().any(|n| match_list(n, namespace))).unwrap_or(true))
+            .filter(|s| s.valid_after.map(|after| *time >= after).unwrap_or(true))
+            .filter(|s| s.valid_before.map(|before| *time <= before).unwrap_or(true))
+            .flat_map(|s| s.principals.clone())
+            .collect()
+    }
+
+    /// Whether `principal` may make `signature` in `namespace` at `time`
+    pub fn is_allowed(&self, principal: &str, signature: &Signature, namespace: &str, time: &DateTime<Utc>) -> bool {
+        self.find_principals(signature, namespace, time).iter().any(|patterns| match_list(patterns, principal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::MemoryStore;
+    use crate::primes::KeySize;
+
+    #[test]
+    fn should_match_wildcards() {
+        assert!(match_list("*@example.com", "alice@example.com"));
+        assert!(match_list("a?ice,bob", "alice"));
+        assert!(!match_list("*,!mallory", "mallory"));
+        assert!(!match_list("bob", "alice"));
+    }
+
+    #[test]
+    fn should_sign_and_check_allowed_signers() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("frank", &KeySize::TenTwentyFour).unwrap();
+        let key = PublicKey::new(rsa.public_key("frank").unwrap());
+        assert_eq!(PublicKey::parse(&key.to_line("frank")).unwrap(), key);
+
+        let armored = sign(&rsa, "frank", "git", b"tree 1234\n").unwrap();
+        let signature = Signature::parse(&armored).unwrap();
+        signature.verify("git", b"tree 1234\n").unwrap();
+        assert!(signature.verify("file", b"tree 1234\n").is_err());
+        assert!(signature.verify("git", b"tree 1235\n").is_err());
+
+        let now = Utc::now();
+        let allowed = AllowedSigners::parse(&format!("# comment\nfrank@example.com namespaces=\"git\" {}\n", key.to_line("frank"))).unwrap();
+        assert!(allowed.is_allowed("frank@example.com", &signature, "git", &now));
+        assert!(!allowed.is_allowed("frank@example.com", &signature, "file", &now));
+        assert!(!allowed.is_allowed("eve@example.com", &signature, "git", &now));
+
+        let expired = AllowedSigners::parse(&format!("frank valid-before=20200101 {}\n", key.to_line(""))).unwrap();
+        assert!(expired.find_principals(&signature, "git", &now).is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! Whose keys are in the keyring, and how far they are trusted
+//! Our own keys are valid by definition. A contact's key is valid once its fingerprint was checked out of band,
+//! or once it is certified by someone we trust (a web of trust)
+use crate::err::{ErrorKind, RSAError};
+use crate::rsa::{RSA, UserKeys, Usage, HashAlgorithm, SignatureScheme, E, verify_signature};
+use num_bigint::BigUint;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Whether the keys of a user are ours or someone else's
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ownership {
+    /// Generated here, or imported with a private key
+    #[default]
+    Own,
+    /// Only the public keys were imported
+    Contact,
+}
+
+impl Ownership {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Ownership::Own => "own",
+            Ownership::Contact => "contact",
+        }
+    }
+}
+
+/// How far a contact is trusted to certify the keys of others, from least to most. Whether their own key was
+/// verified is kept apart from it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Trust {
+    #[default]
+    Unknown,
+    Marginal,
+    Full,
+}
+
+impl Trust {
+    pub const ALL: [Trust; 3] = [Trust::Unknown, Trust::Marginal, Trust::Full];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trust::Unknown => "unknown",
+            Trust::Marginal => "marginal",
+            Trust::Full => "full",
+        }
+    }
+}
+
+impl FromStr for Trust {
+    type Err = RSAError;
+
+    fn from_str(s: &str) -> Result<Self, RSAError> {
+        Ok(*Trust::ALL.iter().find(|t| t.as_str() == s).ok_or(ErrorKind::UnknownTrust)?)
+    }
+}
+
+const DOMAIN: &[u8] = b"RSA KEY CERTIFICATION\0";
+
+/// Signature scheme of certifications, like that of revocations
+pub const SCHEME: SignatureScheme = SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256);
+
+/// How many certifications by marginally trusted users make a key valid
+pub const MARGINALS_NEEDED: usize = 3;
+
+/// How many certifications may lie between our own keys and a valid key
+pub const MAX_DEPTH: usize = 5;
+
+/// A statement, signed by the key `certifier`, that the key `key` belongs to `name`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Certification {
+    /// Fingerprint, in hex, of the certified key
+    pub key: String,
+    /// The name the key was certified for
+    pub name: String,
+    /// Fingerprint, in hex, of the certifying key
+    pub certifier: String,
+    /// When the certification was made, in seconds since the epoch
+    pub created: u64,
+    signature: Vec<u8>,
+}
+
+impl Certification {
+    /// The bytes a certifier signs to certify the key with modulus `n` for `name`
+    pub fn signed_data(n: &BigUint, name: &str, created: u64) -> Vec<u8> {
+        let mut data = DOMAIN.to_vec();
+        data.extend_from_slice(&n.to_bytes_be());
+        data.push(0);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&created.to_be_bytes());
+        data
+    }
+
+    /// A certification of `key` from the signature of `certifier` over `signed_data`
+    pub fn new(key: &RSA, name: &str, certifier: &RSA, created: u64, signature: Vec<u8>) -> Self {
+        Certification { key: key.fingerprint_hex(), name: name.to_string(), certifier: certifier.fingerprint_hex(), created, signature }
+    }
+
+    /// Whether `certifier` made this certification of `key` for `name`, and could at the time
+    pub fn is_valid(&self, key: &RSA, name: &str, certifier: &RSA) -> bool {
+        self.key == key.fingerprint_hex()
+            && self.name == name
+            && self.certifier == certifier.fingerprint_hex()
+            && certifier.usages().contains(&Usage::Certify)
+            && !certifier.revocation().is_some_and(|r| r.invalidates(Some(self.created)))
+            && verify_signature(certifier.public(), &E.into(), SCHEME, &Self::signed_data(key.public(), name, self.created), &self.signature).is_ok()
+    }
+
+    /// Certifications, for export
+    pub fn to_bytes(certifications: &[Certification]) -> Result<Vec<u8>, RSAError> {
+        Ok(bincode::serialize(certifications)?)
+    }
+
+    /// Parse exported certifications
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Certification>, RSAError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// For every user whose primary key is valid, a path of users from the first one to them. Our own keys and
+/// verified contacts are valid by themselves. Any other key is valid once it is certified for its user by a valid
+/// key of someone fully trusted, or of `MARGINALS_NEEDED` marginally trusted users, at most `MAX_DEPTH` steps away
+pub fn trust_paths(keyring: &[(String, UserKeys)]) -> HashMap<String, Vec<String>> {
+    let mut paths: HashMap<String, Vec<String>> = keyring.iter()
+        .filter(|(_, keys)| keys.is_verified())
+        .map(|(user, _)| (user.clone(), vec![user.clone()]))
+        .collect();
+    for _ in 0..MAX_DEPTH {
+        let mut found = Vec::new();
+        for (user, keys) in keyring.iter().filter(|(user, _)| !paths.contains_key(user)) {
+            let key = match keys.primary() {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            let (mut full, mut marginal) = (None, Vec::new());
+            for certification in keys.certifications() {
+                let certifier = keyring.iter()
+                    .filter(|(certifier, _)| paths.contains_key(certifier))
+                    .find_map(|(certifier, keys)| Some((certifier, keys, keys.iter().find(|k| k.fingerprint_hex() == certification.certifier)?)));
+                let (certifier, certifier_keys, certifier_key) = match certifier {
+                    Some(certifier) => certifier,
+                    None => continue,
+                };
+                if !certification.is_valid(key, user, certifier_key) {
+                    continue;
+                }
+                if certifier_keys.ownership() == Ownership::Own || certifier_keys.trust() == Trust::Full {
+                    full = Some(certifier);
+                    break;
+                } else if certifier_keys.trust() == Trust::Marginal && !marginal.contains(&certifier) {
+                    marginal.push(certifier);
+                }
+            }
+            let introducer = full.or(if marginal.len() >= MARGINALS_NEEDED { marginal.first().copied() } else { None });
+            if let Some(introducer) = introducer {
+                let mut path = paths[introducer].clone();
+                path.push(user.clone());
+                found.push((user.clone(), path));
+            }
+        }
+        if found.is_empty() {
+            break;
+        }
+        paths.extend(found);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rsa::AlgoRSA;
+    use crate::keystore::MemoryStore;
+    use crate::primes::KeySize;
+
+    #[test]
+    fn should_find_trust_paths_through_certifications() {
+        let world = AlgoRSA::new(MemoryStore::new());
+        let users = ["me", "alice", "bob", "carol", "m1", "m2", "m3"];
+        for user in &users {
+            world.create(user, &KeySize::FiveTwelve).unwrap();
+        }
+        for (certifier, user) in &[("me", "alice"), ("alice", "bob"), ("bob", "carol"), ("me", "m1"), ("me", "m2"), ("me", "m3"),
+                                   ("m1", "carol"), ("m2", "carol"), ("m3", "carol")] {
+            world.certify(certifier, user).unwrap();
+        }
+
+        let mine = AlgoRSA::new(MemoryStore::new());
+        mine.import("me", world.keys("me").unwrap().primary().unwrap().clone()).unwrap();
+        for user in &users[1..] {
+            mine.import(user, world.keys(user).unwrap().primary().unwrap().to_public()).unwrap();
+            mine.import_certifications(user, &world.export_certifications(user).unwrap()).unwrap();
+        }
+        // certified by us, but not yet trusted to certify others
+        assert!(mine.is_valid("alice").unwrap());
+        assert!(!mine.is_valid("bob").unwrap());
+        mine.set_trust("alice", Trust::Full).unwrap();
+        mine.set_trust("m1", Trust::Marginal).unwrap();
+        mine.set_trust("m2", Trust::Marginal).unwrap();
+
+        assert_eq!(mine.trust_path("alice").unwrap().unwrap(), ["me", "alice"]);
+        assert_eq!(mine.trust_path("bob").unwrap().unwrap(), ["me", "alice", "bob"]);
+        assert!(mine.is_valid("m3").unwrap());
+        // bob is valid but not trusted to certify, and two marginally trusted certifiers are not enough
+        assert_eq!(mine.trust_path("carol").unwrap(), None);
+        mine.set_trust("m3", Trust::Marginal).unwrap();
+        assert_eq!(mine.trust_path("carol").unwrap().unwrap(), ["me", "m1", "carol"]);
+
+        // a certification is for a name; under another name the key is not valid
+        mine.rename("bob", "robert").unwrap();
+        assert!(!mine.is_valid("robert").unwrap());
+        assert_eq!(mine.import_certifications("alice", &world.export_certifications("bob").unwrap()).unwrap(), 0);
+    }
+
+    #[test]
+    fn should_not_let_verified_contacts_certify_without_trust() {
+        let world = AlgoRSA::new(MemoryStore::new());
+        world.create("vera", &KeySize::FiveTwelve).unwrap();
+        world.create("dan", &KeySize::FiveTwelve).unwrap();
+        world.certify("vera", "dan").unwrap();
+
+        let mine = AlgoRSA::new(MemoryStore::new());
+        for user in &["vera", "dan"] {
+            mine.import(user, world.keys(user).unwrap().primary().unwrap().to_public()).unwrap();
+            mine.import_certifications(user, &world.export_certifications(user).unwrap()).unwrap();
+        }
+        mine.verify_contact("vera", &world.keys("vera").unwrap().primary().unwrap().fingerprint_hex()).unwrap();
+        assert!(mine.is_valid("vera").unwrap());
+        // a valid key says who vera is, not how carefully they certify others
+        assert!(!mine.is_valid("dan").unwrap());
+        mine.set_trust("vera", Trust::Marginal).unwrap();
+        assert!(!mine.is_valid("dan").unwrap());
+        mine.set_trust("vera", Trust::Full).unwrap();
+        assert_eq!(mine.trust_path("dan").unwrap().unwrap(), ["vera", "dan"]);
+    }
+}
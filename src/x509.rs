@@ -0,0 +1,174 @@
+//! X.509 Certificates for keys in the database
+//! Certificates are only used to identify keys to other software (CMS, S/MIME); trust in this program
+//! always comes from the key being present in the database, never from the certificate chain
+use crate::rsa::{AlgoRSA, HashAlgorithm, SignatureScheme, Usage, E};
+use crate::keystore::KeyStore;
+use crate::der::{self, Der};
+use crate::err::{ErrorKind, RSAError};
+use num_bigint::BigUint;
+use chrono::{Duration, Utc};
+use rand::Rng;
+use failure::ResultExt;
+
+/// Object Identifiers used by X.509 and CMS
+pub mod oid {
+    pub const RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+    pub const RSAES_OAEP: &[u64] = &[1, 2, 840, 113549, 1, 1, 7];
+    pub const MGF1: &[u64] = &[1, 2, 840, 113549, 1, 1, 8];
+    pub const SHA256_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+    pub const SHA384_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 12];
+    pub const SHA512_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 13];
+    pub const SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+    pub const SHA384: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 2];
+    pub const SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+    pub const COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+    pub const SUBJECT_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 14];
+    pub const KEY_USAGE: &[u64] = &[2, 5, 29, 15];
+    pub const BASIC_CONSTRAINTS: &[u64] = &[2, 5, 29, 19];
+    pub const EXTENDED_KEY_USAGE: &[u64] = &[2, 5, 29, 37];
+    pub const EMAIL_PROTECTION: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 4];
+}
+
+/// AlgorithmIdentifier ::= SEQUENCE { algorithm OBJECT IDENTIFIER, parameters ANY OPTIONAL }
+pub fn algorithm(arcs: &[u64], parameters: Option<Vec<u8>>) -> Vec<u8> {
+    let mut items = vec![der::oid(arcs)];
+    items.extend(parameters);
+    der::sequence(&items)
+}
+
+/// The digest algorithm named by a hash or hash-with-RSA OID
+pub fn hash_from_oid(arcs: &[u64]) -> Result<HashAlgorithm, RSAError> {
+    Ok(match arcs {
+        a if a == oid::SHA256 || a == oid::SHA256_WITH_RSA => HashAlgorithm::Sha256,
+        a if a == oid::SHA384 || a == oid::SHA384_WITH_RSA => HashAlgorithm::Sha384,
+        a if a == oid::SHA512 || a == oid::SHA512_WITH_RSA => HashAlgorithm::Sha512,
+        _ => Err(ErrorKind::UnsupportedAlgorithm)?
+    })
+}
+
+/// RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER } (RFC 8017 Appendix A.1.1)
+pub fn rsa_public_key(n: &BigUint, e: &BigUint) -> Vec<u8> {
+    der::sequence(&[der::integer(n), der::integer(e)])
+}
+
+/// SubjectPublicKeyInfo for an RSA key (RFC 5280 Section 4.1.2.7)
+pub fn subject_public_key_info(n: &BigUint, e: &BigUint) -> Vec<u8> {
+    der::sequence(&[
+        algorithm(oid::RSA_ENCRYPTION, Some(der::null())),
+        der::bit_string(&rsa_public_key(n, e)),
+    ])
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    der::sequence(&[der::set(&[der::sequence(&[der::oid(oid::COMMON_NAME), der::utf8_string(common_name)])])])
+}
+
+fn extension(arcs: &[u64], critical: bool, value: Vec<u8>) -> Vec<u8> {
+    let mut items = vec![der::oid(arcs)];
+    if critical {
+        items.push(der::tlv(0x01, &[0xff]));
+    }
+    items.push(der::octet_string(&value));
+    der::sequence(&items)
+}
+
+/// The fields of a certificate that this program cares about
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    pub serial: BigUint,
+    /// DER encoding of the issuer Name
+    pub issuer: Vec<u8>,
+    pub n: BigUint,
+    pub e: BigUint,
+}
+
+impl Certificate {
+    pub fn parse(input: &[u8]) -> Result<Self, RSAError> {
+        let cert = Der::parse(input)?.expect(der::SEQUENCE)?.children()?;
+        let tbs = cert.first().ok_or(ErrorKind::MalformedDer)?.expect(der::SEQUENCE)?.children()?;
+        // skip the optional explicit version
+        let tbs = if tbs.first().map(|v| v.tag) == Some(0xa0) { &tbs[1..] } else { &tbs[..] };
+        if tbs.len() < 6 {
+            Err(ErrorKind::MalformedDer)?
+        }
+        let spki = tbs[5].expect(der::SEQUENCE)?.children()?;
+        if spki.len() != 2 || spki[0].children()?.first().map(Der::as_oid).transpose()?.as_deref() != Some(oid::RSA_ENCRYPTION) {
+            Err(ErrorKind::UnsupportedKey)?
+        }
+        let public_key = Der::parse(spki[1].as_bit_string()?)?.children()?;
+        if public_key.len() != 2 {
+            Err(ErrorKind::MalformedDer)?
+        }
+
+        Ok(Certificate {
+            serial: tbs[0].as_biguint()?,
+            issuer: tbs[2].expect(der::SEQUENCE)?.raw.to_vec(),
+            n: public_key[0].as_biguint()?,
+            e: public_key[1].as_biguint()?,
+        })
+    }
+
+    /// IssuerAndSerialNumber, the way CMS refers to a certificate
+    pub fn issuer_and_serial(&self) -> Vec<u8> {
+        der::sequence(&[self.issuer.clone(), der::integer(&self.serial)])
+    }
+
+    /// Whether a DER-encoded IssuerAndSerialNumber refers to this certificate
+    pub fn is_identified_by(&self, issuer_and_serial: &Der) -> bool {
+        issuer_and_serial.children()
+            .map(|parts| parts.len() == 2 && parts[0].raw == self.issuer.as_slice() && parts[1].as_biguint().ok().as_ref() == Some(&self.serial))
+            .unwrap_or(false)
+    }
+
+    /// Create a self-signed certificate for the key of `user`, valid for `days` days
+    pub fn self_signed(rsa: &AlgoRSA<impl KeyStore>, user: &str, days: i64) -> Result<Vec<u8>, RSAError> {
+        let n = rsa.public_key(user)?;
+        let e = BigUint::from(E);
+        let mut serial = [0u8; 16];
+        rand::thread_rng().fill(&mut serial);
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+
+        let now = Utc::now();
+        let spki = rsa_public_key(&n, &e);
+        let key_identifier = &HashAlgorithm::Sha256.digest(&spki)[..20];
+        let extensions = der::sequence(&[
+            extension(oid::BASIC_CONSTRAINTS, true, der::sequence(&[])),
+            // digitalSignature and keyEncipherment
+            extension(oid::KEY_USAGE, true, der::tlv(der::BIT_STRING, &[0x05, 0xa0])),
+            extension(oid::EXTENDED_KEY_USAGE, false, der::sequence(&[der::oid(oid::EMAIL_PROTECTION)])),
+            extension(oid::SUBJECT_KEY_IDENTIFIER, false, der::octet_string(key_identifier)),
+        ]);
+        let signature_algorithm = algorithm(oid::SHA256_WITH_RSA, Some(der::null()));
+        let tbs = der::sequence(&[
+            der::context(0, true, &der::small_integer(2)),
+            der::integer(&BigUint::from_bytes_be(&serial)),
+            signature_algorithm.clone(),
+            name(user),
+            der::sequence(&[der::time(&now), der::time(&(now + Duration::days(days)))]),
+            name(user),
+            subject_public_key_info(&n, &e),
+            der::context(3, true, &extensions),
+        ]);
+        let signature = rsa.sign_as(user, Usage::Certify, SignatureScheme::Pkcs1v15(HashAlgorithm::Sha256), &tbs)
+            .context(ErrorKind::CertificateNotFound)?;
+        Ok(der::sequence(&[tbs, signature_algorithm, der::bit_string(&signature)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::MemoryStore;
+    use crate::primes::KeySize;
+
+    #[test]
+    fn should_create_and_parse_self_signed_certificates() {
+        let rsa = AlgoRSA::new(MemoryStore::new());
+        rsa.create("carol", &KeySize::TenTwentyFour).unwrap();
+        let der = Certificate::self_signed(&rsa, "carol", 30).unwrap();
+        let cert = Certificate::parse(&der).unwrap();
+        assert_eq!(cert.n, rsa.public_key("carol").unwrap());
+        assert_eq!(cert.e, BigUint::from(E));
+        assert!(cert.is_identified_by(&Der::parse(&cert.issuer_and_serial()).unwrap()));
+    }
+}
@@ -0,0 +1,105 @@
+//! Runs the binary on input that is malformed or hostile, checking that it fails with the exit code of the
+//! error rather than a panic (101) or an abort (134)
+use rsa::Message;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const BEGIN: &str = "--------------------- BEGIN RSA MESSAGE  ---------------------";
+const END: &str = "--------------------- END RSA MESSAGE  ---------------------";
+
+// a fresh directory with a database holding a key for alice
+fn keyring(name: &str) -> PathBuf {
+    let dir = PathBuf::from(format!("/tmp/RSA_MALFORMED_INPUT_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let output = run(rsa(&dir).args(["-u", "alice", "keygen", "--bits", "512"]), b"");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    dir
+}
+
+fn rsa(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rsa"));
+    command.arg("--db").arg(dir.join("keys.db"))
+        .env_remove("RSA_PASSPHRASE")
+        .env("RUST_BACKTRACE", "0");
+    command
+}
+
+fn run(command: &mut Command, stdin: &[u8]) -> Output {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    // the binary may fail before reading all of it
+    let _ = child.stdin.take().unwrap().write_all(stdin);
+    child.wait_with_output().unwrap()
+}
+
+fn assert_exit(output: &Output, code: i32) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!matches!(output.status.code(), Some(101) | Some(134) | None), "crashed: {}", stderr);
+    assert_eq!(output.status.code(), Some(code), "{}", stderr);
+}
+
+fn armor(message: &[u8]) -> Vec<u8> {
+    format!("{}\n{}\n{}", BEGIN, base64::encode(message), END).into_bytes()
+}
+
+fn unarmor(armored: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8(armored.to_vec()).unwrap();
+    let body: String = text.lines().filter(|line| !line.starts_with('-')).collect();
+    base64::decode(&body).unwrap()
+}
+
+#[test]
+fn should_refuse_passphrase_descriptors_that_are_not_open() {
+    let dir = keyring("fd");
+    let output = run(rsa(&dir).arg("passphrase").env("RSA_NEW_PASSPHRASE", "hunter2"), b"");
+    assert!(output.status.success());
+
+    assert_exit(&run(rsa(&dir).args(["--passphrase-fd", "99", "list"]), b""), 2);
+    assert_exit(&run(rsa(&dir).args(["--passphrase-fd", "-1", "list"]), b""), 2);
+    // stdin cannot carry both the passphrase and the message
+    assert_exit(&run(rsa(&dir).args(["--passphrase-fd", "0", "-u", "alice", "encrypt"]), b"hunter2\nmessage"), 2);
+    assert_exit(&run(rsa(&dir).args(["--passphrase-fd", "0", "list"]), b"hunter2\n"), 0);
+}
+
+#[test]
+fn should_refuse_messages_whose_sizes_do_not_add_up() {
+    let dir = keyring("message");
+    let encrypted = run(rsa(&dir).args(["-u", "alice", "encrypt", "-m", "hello"]), b"");
+    assert!(encrypted.status.success());
+    let message = Message::from_bytes(&unarmor(&encrypted.stdout)).unwrap();
+
+    // a length far beyond the blocks used to size an allocation
+    let forged = Message { length: Some(u64::MAX), ..message.clone() };
+    assert_exit(&run(rsa(&dir).args(["-u", "alice", "decrypt"]), &armor(&forged.to_bytes().unwrap())), 5);
+
+    // a block count far beyond the bytes that follow
+    let mut huge = b"RSM2".to_vec();
+    bincode::serialize_into(&mut huge, &(message.key_id.unwrap(), 5u64, u64::MAX >> 2)).unwrap();
+    assert_exit(&run(rsa(&dir).args(["-u", "alice", "decrypt"]), &armor(&huge)), 6);
+
+    assert_exit(&run(rsa(&dir).args(["-u", "alice", "decrypt"]), b"not a message"), 6);
+    let truncated = &encrypted.stdout[..encrypted.stdout.len() / 2];
+    assert!(!run(rsa(&dir).args(["-u", "alice", "decrypt"]), truncated).status.success());
+}
+
+#[test]
+fn should_stop_quietly_when_the_reader_goes_away() {
+    let dir = keyring("pipe");
+    for user in ["bob", "carol", "dave"] {
+        assert!(run(rsa(&dir).args(["-u", user, "keygen", "--bits", "512"]), b"").status.success());
+    }
+    for command in [&["list"][..], &["-u", "alice", "ssh", "public-key"], &["-u", "alice", "trust-path"]] {
+        let mut child = rsa(&dir).args(command).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        drop(child.stdout.take());
+        assert_exit(&child.wait_with_output().unwrap(), 0);
+    }
+}
+
+#[test]
+fn should_refuse_a_corrupt_database() {
+    let dir = keyring("database");
+    fs::write(dir.join("keys.db"), b"\x00garbage that does not inflate").unwrap();
+    assert_exit(&run(rsa(&dir).arg("list"), b""), 7);
+}
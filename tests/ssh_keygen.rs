@@ -0,0 +1,42 @@
+//! Runs the `ssh-keygen -Y` subset the way git does, against an allowed_signers file
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn rsa(args: &[&str], stdin: Option<&Path>) -> Output {
+    let stdin = stdin.map_or_else(Stdio::null, |path| fs::File::open(path).unwrap().into());
+    Command::new(env!("CARGO_BIN_EXE_rsa")).args(args).env("RUST_BACKTRACE", "0").stdin(stdin).output().unwrap()
+}
+
+#[test]
+fn should_tell_principals_that_are_not_allowed_from_bad_signatures() {
+    let dir = PathBuf::from("/tmp/RSA_SSH_KEYGEN_TEST");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let db = path("keys.db");
+    assert!(rsa(&["--db", &db, "-u", "alice", "keygen", "--bits", "1024"], None).status.success());
+    let key = rsa(&["--db", &db, "-u", "alice", "ssh", "public-key"], None).stdout;
+    fs::write(path("alice.pub"), &key).unwrap();
+    fs::write(path("data"), b"commit").unwrap();
+    assert!(rsa(&["-Y", "sign", "--db", &db, "-n", "git", "-f", &path("alice.pub"), &path("data")], None).status.success());
+
+    let key = String::from_utf8(key).unwrap();
+    fs::write(path("allowed_signers"), format!("alice@example.com {}", key)).unwrap();
+    let verify = |principal: &str| rsa(&["-Y", "verify", "-n", "git", "-f", &path("allowed_signers"), "-I", principal, "-s", &path("data.sig")], Some(&dir.join("data")));
+    assert_eq!(verify("alice@example.com").status.code(), Some(0));
+    let refused = verify("mallory@example.com");
+    assert_eq!(refused.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("allowed_signers does not allow"));
+
+    fs::write(path("allowed_signers"), format!("alice@example.com namespaces=\"file\" {}", key)).unwrap();
+    let found = rsa(&["-Y", "find-principals", "-f", &path("allowed_signers"), "-s", &path("data.sig")], None);
+    assert_eq!(found.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&found.stderr).contains("allowed_signers does not allow"));
+
+    // a new primary key signs from now on; the old public key no longer names the key that would sign
+    assert!(rsa(&["--db", &db, "-u", "alice", "keygen", "--bits", "1024"], None).status.success());
+    let old = rsa(&["-Y", "sign", "--db", &db, "-n", "git", "-f", &path("alice.pub"), &path("data")], None);
+    assert_eq!(old.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&old.stderr).contains("Only the primary key"));
+}